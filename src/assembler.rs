@@ -1,4 +1,5 @@
 use crate::mem::*;
+use std::sync::Arc;
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct AssemblerLabel {
@@ -7,9 +8,7 @@ pub struct AssemblerLabel {
 
 impl Default for AssemblerLabel {
     fn default() -> Self {
-        Self {
-            offset: u32::max_value(),
-        }
+        Self { offset: u32::MAX }
     }
 }
 
@@ -19,7 +18,7 @@ impl AssemblerLabel {
     }
 
     pub const fn is_set(&self) -> bool {
-        self.offset != u32::max_value()
+        self.offset != u32::MAX
     }
 
     pub const fn label_at_offset(&self, offset: u32) -> Self {
@@ -27,13 +26,454 @@ impl AssemblerLabel {
     }
 }
 
+/// Points just past the 8-byte immediate of a `movabs`, so that the pointer
+/// can be linked when the code is placed or repatched later.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct DataLabelPtr {
+    pub label: AssemblerLabel,
+}
+
+/// Points just past a 32-bit immediate or displacement.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct DataLabel32 {
+    pub label: AssemblerLabel,
+}
+
+/// Points just past an 8-bit displacement.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct DataLabelCompact {
+    pub label: AssemblerLabel,
+}
+
+impl DataLabelPtr {
+    pub const fn new(label: AssemblerLabel) -> Self {
+        Self { label }
+    }
+}
+
+impl DataLabel32 {
+    pub const fn new(label: AssemblerLabel) -> Self {
+        Self { label }
+    }
+}
+
+impl DataLabelCompact {
+    pub const fn new(label: AssemblerLabel) -> Self {
+        Self { label }
+    }
+}
+
+/// A data label together with the value it is linked to. Values that depend
+/// on where the code is placed are relocations instead.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DataLink {
+    /// End of the field.
+    pub at: AssemblerLabel,
+    /// Field size in bytes: 1, 4 or 8.
+    pub width: u8,
+    pub value: i64,
+}
+
+/// Handle to a constant in an `AssemblerBuffer`'s constant pool.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Constant(usize);
+
+#[derive(Clone, Debug)]
+pub(crate) struct PoolConstant {
+    bytes: Vec<u8>,
+    alignment: usize,
+    /// Set once the pool is placed.
+    offset: Option<u32>,
+}
+
+/// What a PC-relative displacement refers to.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PcRelTarget {
+    Constant(Constant),
+    /// A label in the same buffer.
+    Label(AssemblerLabel),
+    /// An address outside the buffer, resolved when the code is placed.
+    Absolute(usize),
+}
+
+/// A 32-bit displacement relative to the end of its instruction, e.g. a
+/// RIP-relative memory operand.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PcRelFixup {
+    /// End of the disp32 field.
+    pub disp: AssemblerLabel,
+    /// End of the instruction, which the displacement is relative to.
+    pub insn_end: AssemblerLabel,
+    pub target: PcRelTarget,
+    /// Added to the target, e.g. to address an element of a constant.
+    pub addend: i32,
+}
+
+/// Translates offsets taken before a pass that resized instructions in place
+/// (such as branch relaxation) into offsets in the rewritten buffer.
+#[must_use = "labels taken before the pass are stale until translated"]
+#[derive(Clone, Debug, Default)]
+pub struct OffsetMap {
+    /// Old end offset of every resized region, in ascending order.
+    ends: Vec<u32>,
+    /// Accumulated size change up to and including the matching region.
+    deltas: Vec<i64>,
+}
+
+impl OffsetMap {
+    pub(crate) fn push(&mut self, old_end: u32, delta: i64) {
+        let total = self.deltas.last().copied().unwrap_or(0) + delta;
+        self.ends.push(old_end);
+        self.deltas.push(total);
+    }
+
+    pub fn map_offset(&self, offset: u32) -> u32 {
+        let idx = self.ends.partition_point(|&end| end <= offset);
+        if idx == 0 {
+            return offset;
+        }
+        (offset as i64 + self.deltas[idx - 1]) as u32
+    }
+
+    /// Unset labels map to themselves.
+    pub fn map_label(&self, label: AssemblerLabel) -> AssemblerLabel {
+        if !label.is_set() {
+            return label;
+        }
+        AssemblerLabel::new(self.map_offset(label.offset))
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.deltas.iter().all(|&d| d == 0)
+    }
+}
+
+/// How a relocated field is computed from the final address `S` of its
+/// target, the addend `A` and the final address `P` of `Relocation::pc`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum RelocationKind {
+    /// rel32 of a call or jump, `S + A - P` with `P` the end of the field.
+    /// Goes through `Relocation::veneer` if the target is out of range.
+    Rel32Abs,
+    /// 64-bit absolute address, `S + A`.
+    Abs64,
+    /// disp32 of a RIP-relative operand, `S + A - P` with `P` the end of the
+    /// instruction.
+    RipRel32,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RelocationTarget {
+    /// An address outside the code.
+    Absolute(usize),
+    /// A label in the code, which moves along with it.
+    Label(AssemblerLabel),
+}
+
+/// A field whose value depends on the address the code is placed at. Fields
+/// are written when the code is copied to its final location and can be
+/// rewritten to move it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Relocation {
+    pub kind: RelocationKind,
+    /// End of the field.
+    pub at: AssemblerLabel,
+    /// End of the instruction for `RipRel32`, otherwise `at`.
+    pub pc: AssemblerLabel,
+    pub target: RelocationTarget,
+    pub addend: i64,
+    /// Trampoline for a `Rel32Abs` whose target is out of range; appended by
+    /// the assembler when the code is finalized.
+    pub veneer: AssemblerLabel,
+}
+
+impl Relocation {
+    pub fn rel32_abs(at: AssemblerLabel, target: usize) -> Self {
+        Self {
+            kind: RelocationKind::Rel32Abs,
+            at,
+            pc: at,
+            target: RelocationTarget::Absolute(target),
+            addend: 0,
+            veneer: AssemblerLabel::default(),
+        }
+    }
+
+    pub fn abs64(at: AssemblerLabel, target: RelocationTarget) -> Self {
+        Self {
+            kind: RelocationKind::Abs64,
+            at,
+            pc: at,
+            target,
+            addend: 0,
+            veneer: AssemblerLabel::default(),
+        }
+    }
+
+    pub fn rip_rel32(
+        at: AssemblerLabel,
+        insn_end: AssemblerLabel,
+        target: usize,
+        addend: i32,
+    ) -> Self {
+        Self {
+            kind: RelocationKind::RipRel32,
+            at,
+            pc: insn_end,
+            target: RelocationTarget::Absolute(target),
+            addend: addend as i64,
+            veneer: AssemblerLabel::default(),
+        }
+    }
+
+    /// Size of the field in bytes.
+    pub fn width(&self) -> usize {
+        match self.kind {
+            RelocationKind::Abs64 => 8,
+            RelocationKind::Rel32Abs | RelocationKind::RipRel32 => 4,
+        }
+    }
+
+    fn map(&mut self, map: &OffsetMap) {
+        self.at = map.map_label(self.at);
+        self.pc = map.map_label(self.pc);
+        self.veneer = map.map_label(self.veneer);
+        if let RelocationTarget::Label(ref mut label) = self.target {
+            *label = map.map_label(*label);
+        }
+    }
+
+    /// Writes the field into `code` that will execute at `address`. Fails with
+    /// `ERANGE` if a 32-bit field cannot reach its target.
+    pub fn apply(&self, code: &mut [u8], address: usize) -> Result<(), Error> {
+        let target = match self.target {
+            RelocationTarget::Absolute(target) => target,
+            RelocationTarget::Label(label) => address + label.offset as usize,
+        };
+        let value = (target as i64).wrapping_add(self.addend);
+        let end = self.at.offset as usize;
+        if self.kind == RelocationKind::Abs64 {
+            code[end - 8..end].copy_from_slice(&value.to_ne_bytes());
+            return Ok(());
+        }
+        let pc = address + self.pc.offset as usize;
+        let mut disp = value.wrapping_sub(pc as i64);
+        if disp != disp as i32 as i64 {
+            if self.kind != RelocationKind::Rel32Abs || !self.veneer.is_set() {
+                return Err(Error::new("relocate", libc::ERANGE));
+            }
+            disp = self.veneer.offset as i64 - self.pc.offset as i64;
+        }
+        code[end - 4..end].copy_from_slice(&(disp as i32).to_ne_bytes());
+        Ok(())
+    }
+}
+
+/// Finalized code that has not been placed yet, together with its
+/// relocations. It can be placed any number of times, e.g. after being
+/// cached; relocations against absolute addresses must still be valid.
+#[derive(Clone, Debug)]
+pub struct RelocatableCode {
+    pub code: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+    pub protection: JitProtection,
+}
+
+impl RelocatableCode {
+    /// Returns the code with every relocation applied for `address`.
+    pub fn link(&self, address: usize) -> Result<Vec<u8>, Error> {
+        let mut code = self.code.clone();
+        for relocation in self.relocations.iter() {
+            relocation.apply(&mut code, address)?;
+        }
+        Ok(code)
+    }
+
+    /// Relinks a placed copy of the code in place for execution at `to`,
+    /// e.g. after it was moved there.
+    pub fn relocate(&self, code: &mut [u8], to: usize) -> Result<(), Error> {
+        assert_eq!(code.len(), self.code.len());
+        for relocation in self.relocations.iter() {
+            relocation.apply(code, to)?;
+        }
+        Ok(())
+    }
+
+    /// Returns read-only executable code. Under W^X the pages are written
+    /// while RW and only then flipped to RX.
+    pub fn executable_memory(&self) -> Result<ExecutableCode, Error> {
+        let code = self.executable_writable_memory()?;
+        try_protect(code.ptr, code.mapping_size, Access::ReadExecutable)?;
+        Ok(code)
+    }
+
+    /// Returns code that is still writable. Under W^X it is not executable
+    /// until flipped with `protect`.
+    pub fn executable_writable_memory(&self) -> Result<ExecutableCode, Error> {
+        let mapping_size = align_usize(self.code.len(), page_size());
+        let result = try_commit_access(mapping_size, self.protection.initial_access())?;
+        // Owned from here on, so an early return unmaps it.
+        let code = ExecutableCode {
+            ptr: result,
+            len: self.code.len(),
+            mapping_size,
+            protection: self.protection,
+            pool: None,
+        };
+        let linked = self.link(result as usize)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(linked.as_ptr(), result, linked.len());
+        }
+        Ok(code)
+    }
+
+    /// Places the code in a chunk of `pool` aligned to `alignment`. The
+    /// pool's protection mode applies instead of `protection`.
+    pub fn executable_memory_in(
+        &self,
+        pool: &Arc<ExecutableAllocator>,
+        alignment: usize,
+    ) -> Result<ExecutableCode, Error> {
+        let (result, mapping_size) = pool.allocate(self.code.len(), alignment)?;
+        let code = ExecutableCode {
+            ptr: result,
+            len: self.code.len(),
+            mapping_size,
+            protection: pool.protection(),
+            pool: Some(pool.clone()),
+        };
+        pool.write(
+            result as usize - pool.base() as usize,
+            &self.link(result as usize)?,
+        )?;
+        Ok(code)
+    }
+}
+
+/// Finalized machine code owning its memory. A standalone mapping is
+/// unmapped on drop, a chunk of an `ExecutableAllocator` is returned to it.
+pub struct ExecutableCode {
+    ptr: *mut u8,
+    len: usize,
+    mapping_size: usize,
+    protection: JitProtection,
+    pool: Option<Arc<ExecutableAllocator>>,
+}
+
+unsafe impl Send for ExecutableCode {}
+unsafe impl Sync for ExecutableCode {}
+
+impl ExecutableCode {
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// Only meaningful for code obtained from `executable_writable_memory`.
+    /// Live code is modified with `write`.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn protection(&self) -> JitProtection {
+        self.protection
+    }
+
+    /// Overwrites the code at `offset`, respecting the W^X mode it was
+    /// finalized with. Unless the code is dual-mapped or RWX this flips its
+    /// pages to RW and back (see `try_write_code`), so no thread may be
+    /// executing code on those pages, including neighbouring pooled chunks.
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> Result<(), Error> {
+        assert!(offset + bytes.len() <= self.len);
+        let dst = unsafe { self.ptr.add(offset) };
+        match self.pool {
+            Some(ref pool) => pool.write(dst as usize - pool.base() as usize, bytes),
+            None => unsafe { try_write_code(dst, bytes, self.protection) },
+        }
+    }
+
+    /// Size of the code, excluding the page padding of the mapping.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Size of the memory backing the code: whole pages for a standalone
+    /// mapping, the rounded chunk size for pooled code.
+    pub fn mapping_size(&self) -> usize {
+        self.mapping_size
+    }
+
+    pub fn allocator(&self) -> Option<&Arc<ExecutableAllocator>> {
+        self.pool.as_ref()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// Reinterprets the start of the code as a function pointer of type `F`.
+    ///
+    /// # Safety
+    /// `F` must be a function pointer type matching the ABI and signature of
+    /// the generated code, and it must not be called after `self` is dropped.
+    pub unsafe fn as_fn<F: Copy>(&self) -> F {
+        assert_eq!(std::mem::size_of::<F>(), std::mem::size_of::<*const u8>());
+        std::mem::transmute_copy(&self.ptr)
+    }
+}
+
+impl Drop for ExecutableCode {
+    fn drop(&mut self) {
+        match self.pool {
+            Some(ref pool) => pool.free(self.ptr),
+            None => {
+                let res = try_uncommit(self.ptr, self.mapping_size);
+                debug_assert!(res.is_ok(), "{:?}", res);
+            }
+        }
+    }
+}
+
 pub struct AssemblerBuffer {
     pub(crate) storage: Vec<u8>,
     pub(crate) index: usize,
+    pub(crate) relocations: Vec<Relocation>,
+    pub(crate) data_links: Vec<DataLink>,
+    pub(crate) constants: Vec<PoolConstant>,
+    pub(crate) pc_rel_fixups: Vec<PcRelFixup>,
+    /// Number of `pc_rel_fixups` written by the last `place_constant_pool`.
+    resolved_fixups: usize,
+    pub(crate) symbols: Vec<(String, AssemblerLabel)>,
+    pub(crate) comments: Vec<(AssemblerLabel, String)>,
+    pub(crate) protection: JitProtection,
 }
 
 impl AssemblerBuffer {
     pub const INLINE_CAPACITY: usize = 128;
+
+    pub fn new() -> Self {
+        Self {
+            storage: Vec::with_capacity(Self::INLINE_CAPACITY),
+            index: 0,
+            relocations: vec![],
+            data_links: vec![],
+            constants: vec![],
+            pc_rel_fixups: vec![],
+            resolved_fixups: 0,
+            symbols: vec![],
+            comments: vec![],
+            protection: jit_protection(),
+        }
+    }
+}
+
+impl Default for AssemblerBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AssemblerBuffer {
@@ -58,49 +498,449 @@ impl AssemblerBuffer {
         &mut self.storage
     }
 
-    pub fn executable_memory(&self) -> Option<*mut u8> {
-        if self.index == 0 {
-            return None;
+    pub fn protection(&self) -> JitProtection {
+        self.protection
+    }
+
+    /// Selects how standalone mappings from `executable_memory` are
+    /// protected; defaults to `jit_protection()`.
+    pub fn set_protection(&mut self, protection: JitProtection) {
+        self.protection = protection;
+    }
+
+    /// Swaps in rewritten contents, e.g. after relaxation.
+    pub(crate) fn replace_storage(&mut self, storage: Vec<u8>) {
+        self.index = storage.len();
+        self.storage = storage;
+    }
+
+    /// Translates every recorded label after instructions were resized.
+    pub(crate) fn remap(&mut self, map: &OffsetMap) {
+        for relocation in self.relocations.iter_mut() {
+            relocation.map(map);
         }
-        let result = commit(align_usize(self.index, page_size()), true);
-        if result.is_null() {
-            return None;
+        if !map.is_identity() {
+            // Written displacements are stale until the pool is placed again.
+            self.resolved_fixups = 0;
         }
-        unsafe {
-            std::ptr::copy_nonoverlapping(self.storage.as_ptr(), result, self.index);
+        for fixup in self.pc_rel_fixups.iter_mut() {
+            fixup.disp = map.map_label(fixup.disp);
+            fixup.insn_end = map.map_label(fixup.insn_end);
+            if let PcRelTarget::Label(ref mut label) = fixup.target {
+                *label = map.map_label(*label);
+            }
+        }
+        for constant in self.constants.iter_mut() {
+            if let Some(ref mut offset) = constant.offset {
+                *offset = map.map_offset(*offset);
+            }
+        }
+        for link in self.data_links.iter_mut() {
+            link.at = map.map_label(link.at);
+        }
+        for (_, label) in self.symbols.iter_mut() {
+            *label = map.map_label(*label);
+        }
+        for (label, _) in self.comments.iter_mut() {
+            *label = map.map_label(*label);
+        }
+    }
+
+    /// Names `label` in listings and dumps of the code.
+    pub fn name_label(&mut self, label: AssemblerLabel, name: &str) {
+        assert!(label.is_set() && label.offset as usize <= self.index);
+        self.symbols.push((name.to_string(), label));
+    }
+
+    /// Named labels in the order they were named.
+    pub fn symbols(&self) -> &[(String, AssemblerLabel)] {
+        &self.symbols
+    }
+
+    /// Attaches `text` to the code emitted next, for listings.
+    pub fn comment(&mut self, text: &str) {
+        let at = self.label();
+        self.comments.push((at, text.to_string()));
+    }
+
+    pub fn comments(&self) -> &[(AssemblerLabel, String)] {
+        &self.comments
+    }
+
+    pub fn add_relocation(&mut self, relocation: Relocation) {
+        let end = relocation.at.offset as usize;
+        assert!(relocation.at.is_set() && end >= relocation.width() && end <= self.index);
+        self.relocations.push(relocation);
+    }
+
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+
+    /// `from` is the end of the rel32 slot of the call instruction.
+    pub fn add_external_call(&mut self, from: AssemblerLabel, target: usize) {
+        self.add_relocation(Relocation::rel32_abs(from, target));
+    }
+
+    /// Sets the pointer at `at` to `value` when the code is placed.
+    pub fn link_data_ptr(&mut self, at: DataLabelPtr, value: usize) {
+        self.push_data_link(at.label, 8, value as i64);
+    }
+
+    /// Sets the pointer at `at` to the final address of `target`.
+    pub fn link_data_ptr_to_label(&mut self, at: DataLabelPtr, target: AssemblerLabel) {
+        assert!(target.is_set() && target.offset as usize <= self.index);
+        self.add_relocation(Relocation::abs64(at.label, RelocationTarget::Label(target)));
+    }
+
+    pub fn link_data32(&mut self, at: DataLabel32, value: i32) {
+        self.push_data_link(at.label, 4, value as i64);
+    }
+
+    pub fn link_data_compact(&mut self, at: DataLabelCompact, value: i8) {
+        self.push_data_link(at.label, 1, value as i64);
+    }
+
+    fn push_data_link(&mut self, at: AssemblerLabel, width: u8, value: i64) {
+        assert!(at.is_set() && at.offset as usize >= width as usize);
+        assert!(at.offset as usize <= self.index);
+        self.data_links.push(DataLink { at, width, value });
+    }
+
+    /// Adds `bytes` to the constant pool, reusing an identical constant. The
+    /// pool is placed after the code by `place_constant_pool`, each constant
+    /// aligned to `alignment`.
+    pub fn add_constant(&mut self, bytes: &[u8], alignment: usize) -> Constant {
+        assert!(alignment.is_power_of_two());
+        // A placed constant can only be shared if its slot is aligned enough.
+        let reusable = self.constants.iter().position(|c| {
+            c.bytes == bytes
+                && c.offset
+                    .map_or(true, |offset| is_aligned(offset as usize, alignment))
+        });
+        if let Some(idx) = reusable {
+            let constant = &mut self.constants[idx];
+            constant.alignment = constant.alignment.max(alignment);
+            return Constant(idx);
         }
-        protect(result, self.index, Access::ReadExecutable);
-        Some(result)
+        self.constants.push(PoolConstant {
+            bytes: bytes.to_vec(),
+            alignment,
+            offset: None,
+        });
+        Constant(self.constants.len() - 1)
     }
-    pub fn executable_writable_memory(&self) -> Option<*mut u8> {
-        if self.index == 0 {
-            return None;
+
+    /// Offset of a constant once the pool is placed.
+    pub fn constant_offset(&self, constant: Constant) -> Option<u32> {
+        self.constants[constant.0].offset
+    }
+
+    /// Offset of the first placed constant.
+    pub fn constant_pool_offset(&self) -> Option<u32> {
+        self.constants.iter().filter_map(|c| c.offset).min()
+    }
+
+    /// Alignment the code must be placed at for pooled constants to be
+    /// aligned.
+    pub fn constant_alignment(&self) -> usize {
+        self.constants
+            .iter()
+            .map(|c| c.alignment)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Records the disp32 ending at `disp` of an instruction ending at
+    /// `insn_end`; resolved by `place_constant_pool`, or recorded as a
+    /// `RipRel32` relocation for an absolute target.
+    pub fn add_pc_rel_fixup(
+        &mut self,
+        disp: AssemblerLabel,
+        insn_end: AssemblerLabel,
+        target: PcRelTarget,
+        addend: i32,
+    ) {
+        assert!(disp.is_set() && disp.offset >= 4 && disp.offset <= insn_end.offset);
+        if let PcRelTarget::Label(label) = target {
+            assert!(label.is_set(), "PC-relative reference to an unset label");
         }
-        let result = commit(align_usize(self.index, page_size()), true);
-        if result.is_null() {
-            return None;
+        if let PcRelTarget::Absolute(target) = target {
+            self.add_relocation(Relocation::rip_rel32(disp, insn_end, target, addend));
+            return;
         }
-        unsafe {
-            std::ptr::copy_nonoverlapping(self.storage.as_ptr(), result, self.index);
+        self.pc_rel_fixups.push(PcRelFixup {
+            disp,
+            insn_end,
+            target,
+            addend,
+        });
+    }
+
+    pub fn pc_rel_fixups(&self) -> &[PcRelFixup] {
+        &self.pc_rel_fixups
+    }
+
+    /// Whether constants are waiting to be placed or PC-relative
+    /// displacements to be written by `place_constant_pool`.
+    pub fn has_pending_pc_rel(&self) -> bool {
+        self.resolved_fixups < self.pc_rel_fixups.len()
+            || self.constants.iter().any(|c| c.offset.is_none())
+    }
+
+    /// Appends the constants not placed yet after the code, most aligned
+    /// first and separated by `fill`, then resolves every PC-relative fixup.
+    /// Called when the code is finalized; code appended afterwards is placed
+    /// after the pool.
+    pub fn place_constant_pool(&mut self, fill: u8) {
+        let mut pending: Vec<usize> = (0..self.constants.len())
+            .filter(|&i| self.constants[i].offset.is_none())
+            .collect();
+        pending.sort_by_key(|&i| std::cmp::Reverse(self.constants[i].alignment));
+        for i in pending {
+            while !is_aligned(self.index, self.constants[i].alignment) {
+                self.put_byte(fill);
+            }
+            self.constants[i].offset = Some(self.index as u32);
+            let bytes = std::mem::take(&mut self.constants[i].bytes);
+            self.append(&bytes);
+            self.constants[i].bytes = bytes;
         }
-        Some(result)
+
+        for fixup in self.pc_rel_fixups.iter() {
+            let target = match fixup.target {
+                PcRelTarget::Constant(c) => self.constants[c.0].offset.unwrap(),
+                PcRelTarget::Label(label) => {
+                    assert!(label.is_set() && label.offset as usize <= self.index);
+                    label.offset
+                }
+                PcRelTarget::Absolute(_) => unreachable!(),
+            };
+            let disp = target as i64 + fixup.addend as i64 - fixup.insn_end.offset as i64;
+            assert!(
+                disp == disp as i32 as i64,
+                "PC-relative displacement out of i32 range"
+            );
+            let end = fixup.disp.offset as usize;
+            self.storage[end - 4..end].copy_from_slice(&(disp as i32).to_ne_bytes());
+        }
+        self.resolved_fixups = self.pc_rel_fixups.len();
+    }
+
+    pub fn data_links(&self) -> &[DataLink] {
+        &self.data_links
     }
+
+    /// Returns the finalized code with data links applied, to be placed by
+    /// applying its relocations.
+    ///
+    /// Panics if `has_pending_pc_rel`: the constant pool has to be placed
+    /// first, which finalizing through `X86Assembler` does.
+    pub fn relocatable(&self) -> RelocatableCode {
+        assert!(
+            !self.has_pending_pc_rel(),
+            "constant pool not placed; call place_constant_pool before placing the code"
+        );
+        let mut code = self.storage[..self.index].to_vec();
+        for link in self.data_links.iter() {
+            let end = link.at.offset as usize;
+            let width = link.width as usize;
+            code[end - width..end].copy_from_slice(&link.value.to_ne_bytes()[..width]);
+        }
+        RelocatableCode {
+            code,
+            relocations: self.relocations.clone(),
+            protection: self.protection,
+        }
+    }
+
+    /// Returns read-only executable code. Under W^X the pages are written
+    /// while RW and only then flipped to RX.
+    pub fn executable_memory(&self) -> Result<ExecutableCode, Error> {
+        self.relocatable().executable_memory()
+    }
+
+    /// Returns code that is still writable. Under W^X it is not executable
+    /// until flipped with `protect`.
+    pub fn executable_writable_memory(&self) -> Result<ExecutableCode, Error> {
+        self.relocatable().executable_writable_memory()
+    }
+
+    /// Places the code in a chunk of `pool` aligned to `alignment`. The
+    /// pool's protection mode applies instead of the buffer's.
+    pub fn executable_memory_in(
+        &self,
+        pool: &Arc<ExecutableAllocator>,
+        alignment: usize,
+    ) -> Result<ExecutableCode, Error> {
+        self.relocatable().executable_memory_in(pool, alignment)
+    }
+
     // https://github.com/rust-lang/rust/issues/69228
     /*pub fn put_integral<T: 'static + Sized + Copy + Clone>(&mut self, x: T) {
         let bytes: [u8; std::mem::size_of::<T>()] = unsafe { std::mem::transmute(x) };
     }*/
     pub fn put_byte(&mut self, value: u8) {
-        self.append(&[value as u8]);
+        self.append(&[value]);
     }
     pub fn put_short(&mut self, value: u16) {
-        self.append(&unsafe { std::mem::transmute::<u16, [u8; 2]>(value as u16) });
+        self.append(&value.to_ne_bytes());
     }
 
     pub fn put_int(&mut self, value: i32) {
-        self.append(&unsafe { std::mem::transmute::<u32, [u8; 4]>(value as u32) });
+        self.append(&value.to_ne_bytes());
     }
 
     pub fn put_long(&mut self, value: u64) {
-        self.append(&unsafe { std::mem::transmute::<u64, [u8; 8]>(value as u64) });
+        self.append(&value.to_ne_bytes());
     }
 }
+
+#[test]
+fn test_executable_code() {
+    let mut buffer = AssemblerBuffer::new();
+    buffer.put_byte(0xc3);
+    assert!(AssemblerBuffer::new().executable_memory().is_err());
+    let code = buffer.executable_memory().unwrap();
+    assert_eq!(code.len(), 1);
+    assert_eq!(code.mapping_size(), page_size());
+    assert_eq!(code.as_slice(), &[0xc3][..]);
+    let f: extern "C" fn() = unsafe { code.as_fn() };
+    f();
+}
+
+#[test]
+fn test_executable_code_in_allocator() {
+    let pool = Arc::new(ExecutableAllocator::new(page_size()).unwrap());
+    let mut buffer = AssemblerBuffer::new();
+    buffer.put_byte(0xc3);
+
+    let a = buffer.executable_memory_in(&pool, 16).unwrap();
+    let b = buffer.executable_memory_in(&pool, 16).unwrap();
+    assert_eq!(b.as_ptr() as usize - a.as_ptr() as usize, 16);
+    assert_eq!(pool.stats().live_allocations, 2);
+    let f: extern "C" fn() = unsafe { b.as_fn() };
+    f();
+
+    drop(a);
+    drop(b);
+    assert_eq!(pool.stats().allocated, 0);
+    assert_eq!(Arc::strong_count(&pool), 1);
+}
+
+#[cfg(target_os = "linux")]
+#[cfg(test)]
+pub(crate) fn mapping_permissions(addr: *const u8) -> String {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let range = fields.next().unwrap();
+        let perms = fields.next().unwrap();
+        let (lo, hi) = range.split_once('-').unwrap();
+        let lo = usize::from_str_radix(lo, 16).unwrap();
+        let hi = usize::from_str_radix(hi, 16).unwrap();
+        if (lo..hi).contains(&(addr as usize)) {
+            return perms[..3].to_string();
+        }
+    }
+    panic!("address not mapped");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_write_xor_execute() {
+    // mov eax, 1; ret
+    let mut buffer = AssemblerBuffer::new();
+    buffer.append(&[0xb8, 1, 0, 0, 0, 0xc3]);
+    buffer.set_protection(JitProtection::WriteXorExecute);
+
+    let code = buffer.executable_writable_memory().unwrap();
+    assert_eq!(mapping_permissions(code.as_ptr()), "rw-");
+    drop(code);
+
+    let code = buffer.executable_memory().unwrap();
+    assert_eq!(mapping_permissions(code.as_ptr()), "r-x");
+    let f: extern "C" fn() -> i32 = unsafe { code.as_fn() };
+    assert_eq!(f(), 1);
+    code.write(1, &7i32.to_ne_bytes()).unwrap();
+    assert_eq!(mapping_permissions(code.as_ptr()), "r-x");
+    assert_eq!(f(), 7);
+
+    let pool =
+        Arc::new(ExecutableAllocator::with_protection(1, JitProtection::WriteXorExecute).unwrap());
+    let code = buffer.executable_memory_in(&pool, 16).unwrap();
+    assert_eq!(mapping_permissions(code.as_ptr()), "r-x");
+    let f: extern "C" fn() -> i32 = unsafe { code.as_fn() };
+    assert_eq!(f(), 1);
+}
+
+#[test]
+fn test_relocate_on_copy() {
+    // movabs $label, %rax; ret; call rel32 far away, with a veneer slot.
+    let mut buffer = AssemblerBuffer::new();
+    buffer.append(&[0x48, 0xb8]);
+    buffer.put_long(0);
+    buffer.add_relocation(Relocation::abs64(
+        AssemblerLabel::new(10),
+        RelocationTarget::Label(AssemblerLabel::new(11)),
+    ));
+    buffer.put_byte(0xc3);
+    buffer.put_byte(0xe8);
+    buffer.put_int(0);
+    let mut far = Relocation::rel32_abs(AssemblerLabel::new(16), 0x10);
+    far.veneer = AssemblerLabel::new(16);
+    buffer.add_relocation(far);
+
+    let code = buffer.relocatable();
+    let first = code.link(0x7000_0000_0000).unwrap();
+    assert_eq!(&first[2..10], &0x7000_0000_000bu64.to_ne_bytes());
+    assert_eq!(&first[12..16], &0i32.to_ne_bytes());
+
+    // Moving the code only rewrites the relocated fields.
+    let mut moved = first.clone();
+    code.relocate(&mut moved, 0x8000).unwrap();
+    assert_eq!(moved, code.link(0x8000).unwrap());
+    assert_eq!(&moved[2..10], &0x800bu64.to_ne_bytes());
+    assert_eq!(&moved[12..16], &(0x10 - 0x8010i32).to_ne_bytes());
+
+    let placed = code.executable_memory().unwrap();
+    let f: extern "C" fn() -> usize = unsafe { placed.as_fn() };
+    assert_eq!(f(), placed.as_ptr() as usize + 11);
+
+    // Without a veneer an unreachable rel32 is an error.
+    let mut buffer = AssemblerBuffer::new();
+    buffer.put_byte(0xe8);
+    buffer.put_int(0);
+    buffer.add_external_call(AssemblerLabel::new(5), 0x10);
+    let err = buffer.relocatable().link(0x7000_0000_0000).unwrap_err();
+    assert_eq!(err.errno(), libc::ERANGE);
+}
+
+#[test]
+fn test_constant_pool() {
+    let mut buffer = AssemblerBuffer::new();
+    buffer.put_byte(0xc3);
+    let a = buffer.add_constant(&1.5f64.to_ne_bytes(), 8);
+    let b = buffer.add_constant(&[0xff; 16], 16);
+    assert_eq!(buffer.add_constant(&1.5f64.to_ne_bytes(), 8), a);
+    // A stricter alignment request for the same bytes upgrades the slot.
+    assert_eq!(buffer.add_constant(&[0xff; 16], 32), b);
+    assert_eq!(buffer.constant_alignment(), 32);
+
+    // Pretend a 5 byte instruction with a trailing disp32 referenced `a`.
+    buffer.append(&[0; 5]);
+    buffer.add_pc_rel_fixup(
+        AssemblerLabel::new(6),
+        AssemblerLabel::new(6),
+        PcRelTarget::Constant(a),
+        4,
+    );
+    assert!(buffer.has_pending_pc_rel());
+    buffer.place_constant_pool(0xf4);
+    assert!(!buffer.has_pending_pc_rel());
+    assert_eq!(buffer.constant_offset(b), Some(32));
+    assert_eq!(buffer.constant_offset(a), Some(48));
+    assert_eq!(buffer.code_size(), 56);
+    assert_eq!(&buffer.data()[2..6], &(48 + 4 - 6i32).to_ne_bytes());
+    assert_eq!(&buffer.data()[32..48], &[0xff; 16]);
+}
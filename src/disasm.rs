@@ -0,0 +1,153 @@
+//! Disassembly listings of generated code, through capstone.
+//!
+//! A listing shows every instruction in AT&T syntax with its offset and raw
+//! bytes, preceded by the names given with `name_label` and the comments
+//! attached with `comment` while emitting. The constant pool is shown as data.
+use crate::assembler::AssemblerBuffer;
+use crate::x86assembler::X86Assembler;
+use capstone::prelude::*;
+use std::io::{self, Write};
+
+/// Raw bytes shown per line; longer instructions push the mnemonic right.
+const BYTES_COLUMN: usize = 8;
+
+fn capstone() -> io::Result<Capstone> {
+    #[cfg(target_arch = "x86_64")]
+    let mode = arch::x86::ArchMode::Mode64;
+    #[cfg(target_arch = "x86")]
+    let mode = arch::x86::ArchMode::Mode32;
+    Capstone::new()
+        .x86()
+        .mode(mode)
+        .syntax(arch::x86::ArchSyntax::Att)
+        .build()
+        .map_err(|err| io::Error::other(err.to_string()))
+}
+
+fn write_line(w: &mut impl Write, offset: usize, bytes: &[u8], text: &str) -> io::Result<()> {
+    write!(w, "{:6x}: ", offset)?;
+    for byte in bytes {
+        write!(w, " {:02x}", byte)?;
+    }
+    for _ in bytes.len()..BYTES_COLUMN {
+        write!(w, "   ")?;
+    }
+    writeln!(w, "  {}", text)
+}
+
+impl AssemblerBuffer {
+    /// Writes a disassembly listing of the code. Bytes that do not decode are
+    /// shown as `.byte`.
+    pub fn dump_listing(&self, w: &mut impl Write) -> io::Result<()> {
+        let cs = capstone()?;
+        let code = self.relocatable().code;
+        let pool = self
+            .constant_pool_offset()
+            .map_or(code.len(), |offset| offset as usize);
+
+        // Names and comments sorted by offset; the sort is stable, so several
+        // at one offset keep the order they were added in.
+        let mut notes: Vec<(usize, String)> = self
+            .symbols()
+            .iter()
+            .map(|(name, label)| (label.offset as usize, format!("{}:", name)))
+            .chain(
+                self.comments()
+                    .iter()
+                    .map(|(label, text)| (label.offset as usize, format!("{:8}; {}", "", text))),
+            )
+            .collect();
+        notes.sort_by_key(|&(offset, _)| offset);
+        let mut notes = notes.into_iter().peekable();
+
+        let mut offset = 0;
+        while offset < code.len() {
+            while let Some((_, note)) = notes.next_if(|&(at, _)| at <= offset) {
+                writeln!(w, "{}", note)?;
+            }
+            let next_note = notes.peek().map_or(code.len(), |&(at, _)| at);
+            if offset >= pool {
+                let end = code
+                    .len()
+                    .min(offset + BYTES_COLUMN)
+                    .min(next_note.max(offset + 1));
+                let data = &code[offset..end];
+                let text = data
+                    .iter()
+                    .map(|byte| format!("{:#04x}", byte))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write_line(w, offset, data, &format!(".byte {}", text))?;
+                offset = end;
+                continue;
+            }
+            let insns = cs
+                .disasm_count(&code[offset..pool], offset as u64, 1)
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            match insns.iter().next() {
+                Some(insn) => {
+                    let text = format!(
+                        "{} {}",
+                        insn.mnemonic().unwrap_or(""),
+                        insn.op_str().unwrap_or("")
+                    );
+                    write_line(w, offset, insn.bytes(), text.trim_end())?;
+                    offset += insn.bytes().len();
+                }
+                None => {
+                    let byte = code[offset];
+                    write_line(w, offset, &[byte], &format!(".byte {:#04x}", byte))?;
+                    offset += 1;
+                }
+            }
+        }
+        for (_, note) in notes {
+            writeln!(w, "{}", note)?;
+        }
+        Ok(())
+    }
+}
+
+impl X86Assembler {
+    /// Writes a disassembly listing of the code emitted so far.
+    pub fn dump_listing(&self, w: &mut impl Write) -> io::Result<()> {
+        self.buffer().dump_listing(w)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_dump_listing() {
+    use crate::assembler::PcRelTarget;
+    use crate::x86assembler::*;
+    use crate::x86operand::*;
+
+    let mut asm = X86Assembler::new();
+    let entry = asm.label();
+    asm.name_label(entry, "add");
+    asm.comment("rax = rsi + rdi");
+    asm.movq(X86Gpr::Esi, X86Gpr::Eax);
+    asm.addq(X86Gpr::Edi, X86Gpr::Eax);
+    asm.ret();
+    let half = asm.constant_f64(0.5);
+    let scale = asm.label();
+    asm.name_label(scale, "scale");
+    asm.mulsd(Mem::rip(PcRelTarget::Constant(half), 0), X86Fpr::XMM0);
+    asm.ret();
+    asm.finish_for_output();
+
+    let mut listing = vec![];
+    asm.dump_listing(&mut listing).unwrap();
+    assert_eq!(
+        String::from_utf8(listing).unwrap(),
+        "add:\n\
+         \x20       ; rax = rsi + rdi\n\
+         \x20    0:  48 89 f0                 movq %rsi, %rax\n\
+         \x20    3:  48 01 f8                 addq %rdi, %rax\n\
+         \x20    6:  c3                       retq\n\
+         scale:\n\
+         \x20    7:  f2 0f 59 05 01 00 00 00  mulsd 1(%rip), %xmm0\n\
+         \x20    f:  c3                       retq\n\
+         \x20   10:  00 00 00 00 00 00 e0 3f  .byte 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe0, 0x3f\n"
+    );
+}
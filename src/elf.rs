@@ -0,0 +1,583 @@
+//! ELF64 relocatable object files for x86_64, for using the assembler ahead
+//! of time.
+//!
+//! The code goes into `.text` and the constant pool into `.rodata`. Functions
+//! are named by the label of their entry; labels named with
+//! `X86Assembler::name_label` become local symbols. External calls and absolute
+//! RIP-relative targets are emitted as references to undefined symbols, so
+//! every such address has to be given a name with `ElfWriter::import`.
+use crate::assembler::*;
+use crate::mem::is_aligned;
+use crate::x86assembler::X86Assembler;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    /// A relocation refers to an address that was not imported.
+    UnknownAddress(usize),
+    /// Code was appended after the constant pool was placed.
+    CodeAfterConstantPool,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::UnknownAddress(address) => {
+                write!(f, "no symbol imported for address {:#x}", address)
+            }
+            ElfError::CodeAfterConstantPool => write!(f, "code follows the constant pool"),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+const EM_X86_64: u16 = 62;
+const ET_REL: u16 = 1;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+const TEXT: u16 = 1;
+const RODATA: u16 = 2;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const SHSTRTAB: u16 = 7;
+
+const SYM_TEXT: u32 = 1;
+const SYM_RODATA: u32 = 2;
+
+struct Rela {
+    offset: u64,
+    symbol: u32,
+    kind: u32,
+    addend: i64,
+}
+
+/// Turns a finished `X86Assembler` into a relocatable object file.
+pub struct ElfWriter {
+    text: Vec<u8>,
+    rodata: Vec<u8>,
+    rodata_alignment: usize,
+    /// `(disp, insn_end, offset into .rodata)` of every pooled constant use.
+    constant_uses: Vec<(AssemblerLabel, AssemblerLabel, i64)>,
+    relocations: Vec<Relocation>,
+    functions: Vec<(String, AssemblerLabel, bool)>,
+    /// Labels named in the assembler, exported as local symbols.
+    labels: Vec<(String, AssemblerLabel)>,
+    imports: Vec<(String, usize)>,
+}
+
+impl ElfWriter {
+    /// Finishes `asm` and takes a copy of its code. External calls do not
+    /// get veneers, the linker reaches them.
+    pub fn new(asm: &mut X86Assembler) -> Self {
+        asm.formatter.finish_object();
+        let buffer = &asm.formatter.buffer;
+        let code = buffer.relocatable();
+        let pool_start = buffer
+            .constant_pool_offset()
+            .map_or(code.code.len(), |offset| offset as usize);
+        let constant_uses = buffer
+            .pc_rel_fixups()
+            .iter()
+            .filter_map(|fixup| match fixup.target {
+                PcRelTarget::Constant(c) => {
+                    let offset = buffer.constant_offset(c).unwrap() as usize - pool_start;
+                    Some((
+                        fixup.disp,
+                        fixup.insn_end,
+                        offset as i64 + fixup.addend as i64,
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+        Self {
+            text: code.code[..pool_start].to_vec(),
+            rodata: code.code[pool_start..].to_vec(),
+            rodata_alignment: buffer.constant_alignment(),
+            constant_uses,
+            relocations: code.relocations,
+            functions: vec![],
+            labels: buffer.symbols().to_vec(),
+            imports: vec![],
+        }
+    }
+
+    /// Exports a function starting at `entry`.
+    pub fn function(&mut self, name: &str, entry: AssemblerLabel) {
+        self.functions.push((name.to_string(), entry, true));
+    }
+
+    /// Names a function starting at `entry` that is only visible inside the
+    /// object.
+    pub fn local_function(&mut self, name: &str, entry: AssemblerLabel) {
+        self.functions.push((name.to_string(), entry, false));
+    }
+
+    /// References to `address` become references to the undefined symbol
+    /// `name`, e.g. for `call_external(libc::abs as *const u8)`.
+    pub fn import(&mut self, name: &str, address: usize) {
+        self.imports.push((name.to_string(), address));
+    }
+
+    /// Returns the contents of the `.o` file.
+    pub fn write(&self) -> Result<Vec<u8>, ElfError> {
+        let text_len = self.text.len() as u32;
+        let in_text = |label: AssemblerLabel| label.offset <= text_len;
+
+        let mut strtab = vec![0u8];
+        let mut name = |strtab: &mut Vec<u8>, name: &str| {
+            let index = strtab.len() as u32;
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+            index
+        };
+
+        // Locals first: null, the two section symbols, named labels and local
+        // functions.
+        let mut symtab = vec![0u8; 24];
+        push_symbol(&mut symtab, 0, STB_LOCAL, STT_SECTION, TEXT, 0, 0);
+        push_symbol(&mut symtab, 0, STB_LOCAL, STT_SECTION, RODATA, 0, 0);
+        for (lname, label) in self.labels.iter() {
+            // A function symbol already names its entry.
+            if self
+                .functions
+                .iter()
+                .any(|f| f.0 == *lname && f.1 == *label)
+            {
+                continue;
+            }
+            let index = name(&mut strtab, lname);
+            let (section, value) = if in_text(*label) {
+                (TEXT, label.offset)
+            } else {
+                (RODATA, label.offset - text_len)
+            };
+            push_symbol(
+                &mut symtab,
+                index,
+                STB_LOCAL,
+                STT_NOTYPE,
+                section,
+                value as u64,
+                0,
+            );
+        }
+        let mut entries: Vec<u32> = self.functions.iter().map(|f| f.1.offset).collect();
+        entries.push(text_len);
+        entries.sort_unstable();
+        let size_of = |entry: u32| {
+            let next = entries.iter().find(|&&e| e > entry).unwrap_or(&text_len);
+            (next - entry) as u64
+        };
+        for global in [false, true].iter() {
+            for (fname, entry, _) in self.functions.iter().filter(|f| f.2 == *global) {
+                if !in_text(*entry) {
+                    return Err(ElfError::CodeAfterConstantPool);
+                }
+                let binding = if *global { STB_GLOBAL } else { STB_LOCAL };
+                let index = name(&mut strtab, fname);
+                push_symbol(
+                    &mut symtab,
+                    index,
+                    binding,
+                    STT_FUNC,
+                    TEXT,
+                    entry.offset as u64,
+                    size_of(entry.offset),
+                );
+            }
+        }
+        let first_global =
+            (symtab.len() / 24) as u32 - self.functions.iter().filter(|f| f.2).count() as u32;
+        let mut imported = HashMap::new();
+        for (iname, address) in self.imports.iter() {
+            let index = name(&mut strtab, iname);
+            imported.insert(*address, (symtab.len() / 24) as u32);
+            push_symbol(&mut symtab, index, STB_GLOBAL, STT_NOTYPE, 0, 0, 0);
+        }
+
+        let mut relas = vec![];
+        for &(disp, insn_end, offset) in self.constant_uses.iter() {
+            if !in_text(insn_end) {
+                return Err(ElfError::CodeAfterConstantPool);
+            }
+            relas.push(Rela {
+                offset: disp.offset as u64 - 4,
+                symbol: SYM_RODATA,
+                kind: R_X86_64_PC32,
+                addend: offset - (insn_end.offset - disp.offset + 4) as i64,
+            });
+        }
+        for relocation in self.relocations.iter() {
+            if !in_text(relocation.pc) {
+                return Err(ElfError::CodeAfterConstantPool);
+            }
+            let (symbol, offset) = match relocation.target {
+                RelocationTarget::Absolute(address) => match imported.get(&address) {
+                    Some(&symbol) => (symbol, 0),
+                    None => return Err(ElfError::UnknownAddress(address)),
+                },
+                RelocationTarget::Label(label) if in_text(label) => (SYM_TEXT, label.offset as i64),
+                RelocationTarget::Label(label) => (SYM_RODATA, (label.offset - text_len) as i64),
+            };
+            let width = relocation.width() as u32;
+            let (kind, pc_bias) = match relocation.kind {
+                RelocationKind::Abs64 => (R_X86_64_64, 0),
+                // ELF displacements are relative to the start of the field.
+                RelocationKind::Rel32Abs => (R_X86_64_PLT32, 4),
+                RelocationKind::RipRel32 => (
+                    R_X86_64_PC32,
+                    relocation.pc.offset - relocation.at.offset + 4,
+                ),
+            };
+            relas.push(Rela {
+                offset: (relocation.at.offset - width) as u64,
+                symbol,
+                kind,
+                addend: offset + relocation.addend - pc_bias as i64,
+            });
+        }
+        let mut rela_text = vec![];
+        for rela in relas.iter() {
+            rela_text.extend_from_slice(&rela.offset.to_le_bytes());
+            rela_text.extend_from_slice(
+                &(((rela.symbol as u64) << 32) | rela.kind as u64).to_le_bytes(),
+            );
+            rela_text.extend_from_slice(&rela.addend.to_le_bytes());
+        }
+
+        let mut shstrtab = vec![0u8];
+        let names: Vec<u32> = [
+            ".text",
+            ".rodata",
+            ".symtab",
+            ".strtab",
+            ".rela.text",
+            ".note.GNU-stack",
+            ".shstrtab",
+        ]
+        .iter()
+        .map(|n| name(&mut shstrtab, n))
+        .collect();
+
+        // Section contents follow the header, each aligned as it requires.
+        let mut file = vec![0u8; 64];
+        let mut place = |file: &mut Vec<u8>, data: &[u8], alignment: usize| {
+            while !is_aligned(file.len(), alignment) {
+                file.push(0);
+            }
+            let offset = file.len() as u64;
+            file.extend_from_slice(data);
+            offset
+        };
+        let text_offset = place(&mut file, &self.text, 16);
+        let rodata_offset = place(&mut file, &self.rodata, self.rodata_alignment);
+        let symtab_offset = place(&mut file, &symtab, 8);
+        let strtab_offset = place(&mut file, &strtab, 1);
+        let rela_offset = place(&mut file, &rela_text, 8);
+        let note_offset = place(&mut file, &[], 1);
+        let shstrtab_offset = place(&mut file, &shstrtab, 1);
+        let shoff = place(&mut file, &[], 8);
+
+        let mut headers = vec![0u8; 64];
+        let mut section =
+            |name, kind, flags, offset, size: usize, link, info, alignment: usize, entsize| {
+                push_section(
+                    &mut headers,
+                    name,
+                    kind,
+                    flags,
+                    offset,
+                    size as u64,
+                    link,
+                    info,
+                    alignment as u64,
+                    entsize,
+                );
+            };
+        section(
+            names[0],
+            SHT_PROGBITS,
+            SHF_ALLOC | SHF_EXECINSTR,
+            text_offset,
+            self.text.len(),
+            0,
+            0,
+            16,
+            0,
+        );
+        section(
+            names[1],
+            SHT_PROGBITS,
+            SHF_ALLOC,
+            rodata_offset,
+            self.rodata.len(),
+            0,
+            0,
+            self.rodata_alignment,
+            0,
+        );
+        section(
+            names[2],
+            SHT_SYMTAB,
+            0,
+            symtab_offset,
+            symtab.len(),
+            STRTAB,
+            first_global,
+            8,
+            24,
+        );
+        section(
+            names[3],
+            SHT_STRTAB,
+            0,
+            strtab_offset,
+            strtab.len(),
+            0,
+            0,
+            1,
+            0,
+        );
+        section(
+            names[4],
+            SHT_RELA,
+            SHF_INFO_LINK,
+            rela_offset,
+            rela_text.len(),
+            SYMTAB,
+            TEXT as u32,
+            8,
+            24,
+        );
+        section(names[5], SHT_PROGBITS, 0, note_offset, 0, 0, 0, 1, 0);
+        section(
+            names[6],
+            SHT_STRTAB,
+            0,
+            shstrtab_offset,
+            shstrtab.len(),
+            0,
+            0,
+            1,
+            0,
+        );
+        file.extend_from_slice(&headers);
+
+        let header = &mut file[..64];
+        header[..4].copy_from_slice(b"\x7fELF");
+        // 64-bit, little endian, version 1, System V ABI.
+        header[4..8].copy_from_slice(&[2, 1, 1, 0]);
+        header[16..18].copy_from_slice(&ET_REL.to_le_bytes());
+        header[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        header[20..24].copy_from_slice(&1u32.to_le_bytes());
+        header[40..48].copy_from_slice(&shoff.to_le_bytes());
+        header[52..54].copy_from_slice(&64u16.to_le_bytes());
+        header[58..60].copy_from_slice(&64u16.to_le_bytes());
+        header[60..62].copy_from_slice(&(headers.len() as u16 / 64).to_le_bytes());
+        header[62..64].copy_from_slice(&SHSTRTAB.to_le_bytes());
+        Ok(file)
+    }
+}
+
+fn push_symbol(
+    symtab: &mut Vec<u8>,
+    name: u32,
+    binding: u8,
+    kind: u8,
+    section: u16,
+    value: u64,
+    size: u64,
+) {
+    symtab.extend_from_slice(&name.to_le_bytes());
+    symtab.push((binding << 4) | kind);
+    symtab.push(0);
+    symtab.extend_from_slice(&section.to_le_bytes());
+    symtab.extend_from_slice(&value.to_le_bytes());
+    symtab.extend_from_slice(&size.to_le_bytes());
+}
+
+#[allow(clippy::too_many_arguments)]
+fn push_section(
+    headers: &mut Vec<u8>,
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    alignment: u64,
+    entsize: u64,
+) {
+    headers.extend_from_slice(&name.to_le_bytes());
+    headers.extend_from_slice(&kind.to_le_bytes());
+    headers.extend_from_slice(&flags.to_le_bytes());
+    headers.extend_from_slice(&0u64.to_le_bytes());
+    headers.extend_from_slice(&offset.to_le_bytes());
+    headers.extend_from_slice(&size.to_le_bytes());
+    headers.extend_from_slice(&link.to_le_bytes());
+    headers.extend_from_slice(&info.to_le_bytes());
+    headers.extend_from_slice(&alignment.to_le_bytes());
+    headers.extend_from_slice(&entsize.to_le_bytes());
+}
+
+/// `(name, binding, section, value)` of every symbol in `object`, and the
+/// index of the first global one.
+#[cfg(test)]
+fn read_symbols(object: &[u8]) -> (Vec<(String, u8, u16, u64)>, usize) {
+    let u16_at = |at: usize| u16::from_le_bytes([object[at], object[at + 1]]);
+    let u32_at = |at: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&object[at..at + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    let u64_at = |at: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&object[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let shoff = u64_at(40) as usize;
+    let header = |index: usize| shoff + index * 64;
+    let symtab = (0..u16_at(60) as usize)
+        .map(header)
+        .find(|&h| u32_at(h + 4) == SHT_SYMTAB)
+        .unwrap();
+    let strtab = u64_at(header(u32_at(symtab + 40) as usize) + 24) as usize;
+    let (start, size) = (u64_at(symtab + 24) as usize, u64_at(symtab + 32) as usize);
+    let symbols = (start..start + size)
+        .step_by(24)
+        .map(|sym| {
+            let name = &object[strtab + u32_at(sym) as usize..];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap()];
+            (
+                String::from_utf8(name.to_vec()).unwrap(),
+                object[sym + 4] >> 4,
+                u16_at(sym + 6),
+                u64_at(sym + 8),
+            )
+        })
+        .collect();
+    (symbols, u32_at(symtab + 44) as usize)
+}
+
+#[test]
+fn test_named_labels_become_local_symbols() {
+    use crate::x86assembler::X86Gpr;
+
+    let mut asm = X86Assembler::new();
+    let entry = asm.label();
+    asm.name_label(entry, "proy_count");
+    asm.movl_i32r(0, X86Gpr::Eax as _);
+    let top = asm.label();
+    asm.name_label(top, "count_loop");
+    asm.addl_ir(1, X86Gpr::Eax as _);
+    asm.ret();
+    let first = asm.constant_f64(2.5);
+    let factor = asm.constant_f64(4.0);
+    asm.formatter.finish_object();
+    let buffer = asm.buffer();
+    let offset = buffer.constant_offset(factor).unwrap();
+    let rodata_offset = offset - buffer.constant_offset(first).unwrap();
+    asm.name_label(AssemblerLabel::new(offset), "count_factor");
+
+    let mut writer = ElfWriter::new(&mut asm);
+    writer.function("proy_count", entry);
+    let (symbols, first_global) = read_symbols(&writer.write().unwrap());
+    let find = |name: &str| symbols.iter().position(|s| s.0 == name).unwrap();
+
+    let count_loop = &symbols[find("count_loop")];
+    assert_eq!(count_loop.1, STB_LOCAL);
+    assert_eq!((count_loop.2, count_loop.3), (TEXT, top.offset as u64));
+    let count_factor = &symbols[find("count_factor")];
+    assert_eq!(count_factor.1, STB_LOCAL);
+    assert_eq!(
+        (count_factor.2, count_factor.3),
+        (RODATA, rodata_offset as u64)
+    );
+    assert!(find("count_loop") < first_global);
+    // The function symbol is the only one naming the entry.
+    assert_eq!(symbols.iter().filter(|s| s.0 == "proy_count").count(), 1);
+    assert_eq!(symbols[find("proy_count")].1, STB_GLOBAL);
+    assert!(find("proy_count") >= first_global);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_link_and_run_object() {
+    use crate::x86assembler::{X86Fpr, X86Gpr};
+    use crate::x86operand::*;
+    use std::process::Command;
+
+    extern "C" {
+        fn abs(x: i32) -> i32;
+    }
+
+    let abs_address = abs as *const u8 as usize;
+    let mut asm = X86Assembler::new();
+    // double proy_scale(double x) { return x * 2.5; }
+    let scale = asm.label();
+    let factor = asm.constant_f64(2.5);
+    asm.mulsd(Mem::rip(PcRelTarget::Constant(factor), 0), X86Fpr::XMM0);
+    asm.ret();
+    // int proy_abs_plus_one(int x) { return abs(x) + 1; }
+    let abs_plus_one = asm.label();
+    asm.push(X86Gpr::Eax);
+    asm.call_external(abs as *const u8);
+    asm.pop(X86Gpr::Ecx);
+    asm.addl(Imm(1), X86Gpr::Eax);
+    asm.ret();
+
+    let mut writer = ElfWriter::new(&mut asm);
+    writer.function("proy_scale", scale);
+    writer.function("proy_abs_plus_one", abs_plus_one);
+    writer.import("abs", abs_address);
+
+    let dir = std::env::temp_dir().join(format!("proy-elf-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("code.o"), writer.write().unwrap()).unwrap();
+    std::fs::write(
+        dir.join("main.c"),
+        "double proy_scale(double);\n\
+         int proy_abs_plus_one(int);\n\
+         int main(void) { return proy_abs_plus_one(-40) + (int)proy_scale(4.0); }\n",
+    )
+    .unwrap();
+    let status = Command::new("cc")
+        .current_dir(&dir)
+        .args(["main.c", "code.o", "-o", "main"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let status = Command::new(dir.join("main")).status().unwrap();
+    assert_eq!(status.code(), Some(41 + 10));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // An unnamed external address cannot be written out.
+    let mut asm = X86Assembler::new();
+    asm.call_external(abs as *const u8);
+    assert_eq!(
+        ElfWriter::new(&mut asm).write(),
+        Err(ElfError::UnknownAddress(abs_address))
+    );
+}
@@ -1,5 +1,12 @@
 #![allow(unused)]
 #![allow(non_upper_case_globals)]
 pub mod assembler;
+#[cfg(feature = "disasm")]
+pub mod disasm;
+#[cfg(target_arch = "x86_64")]
+pub mod elf;
 pub mod mem;
+pub mod output;
 pub mod x86assembler;
+pub mod x86operand;
+pub mod x86patcher;
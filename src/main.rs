@@ -1,24 +1,14 @@
 extern crate proy;
-use capstone::prelude::*;
 use proy::x86assembler::*;
+use proy::x86operand::*;
 fn main() {
     let mut asm = X86Assembler::new();
-    asm.movq_rr(X86Gpr::Esi as _, X86Gpr::Eax as _);
-    asm.addq_rr(X86Gpr::Edi as u8, X86Gpr::Eax as u8);
+    asm.movq(X86Gpr::Esi, X86Gpr::Eax);
+    asm.addq(X86Gpr::Edi, X86Gpr::Eax);
     asm.ret();
-    let f: fn(i64, i64) -> i64 =
-        unsafe { std::mem::transmute(asm.formatter.executable_readable()) };
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(i64, i64) -> i64 = unsafe { code.as_fn() };
     println!("{}", f(2, 3));
-    let cs = Capstone::new()
-        .x86()
-        .mode(arch::x86::ArchMode::Mode64)
-        .syntax(arch::x86::ArchSyntax::Att)
-        .detail(true)
-        .build()
-        .expect("Failed to create Capstone object");
-
-    let insns = cs.disasm_all(asm.code(), 0x0).unwrap();
-    for i in insns.iter() {
-        println!("{}", i);
-    }
+    #[cfg(feature = "disasm")]
+    asm.dump_listing(&mut std::io::stdout()).unwrap();
 }
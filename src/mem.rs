@@ -1,16 +1,112 @@
-#[cfg(target_family = "unix")]
-pub fn commit(size: usize, executable: bool) -> *mut u8 {
-    let mut prot = libc::PROT_READ | libc::PROT_WRITE;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Mutex, RwLock};
+
+/// A failed memory-management syscall together with the OS error code.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Error {
+    operation: &'static str,
+    errno: i32,
+}
+
+impl Error {
+    pub fn new(operation: &'static str, errno: i32) -> Self {
+        Self { operation, errno }
+    }
+
+    /// Captures `errno` (`GetLastError()` on Windows) for `operation`.
+    pub fn last(operation: &'static str) -> Self {
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+        Self::new(operation, errno)
+    }
 
-    if executable {
-        prot |= libc::PROT_EXEC;
+    /// Name of the syscall that failed, e.g. `"mmap"`.
+    pub fn operation(&self) -> &'static str {
+        self.operation
     }
 
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}() failed: {}",
+            self.operation,
+            std::io::Error::from_raw_os_error(self.errno)
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        std::io::Error::from_raw_os_error(err.errno)
+    }
+}
+
+/// How pages holding generated code are protected.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum JitProtection {
+    /// Code is written while its pages are RW and published by flipping them
+    /// to RX; no page is ever writable and executable at once.
+    #[default]
+    WriteXorExecute,
+    /// Code pages are mapped RWX and written in place.
+    ReadWriteExecute,
+}
+
+impl JitProtection {
+    /// Access for freshly committed pages that code is about to be copied to.
+    pub fn initial_access(self) -> Access {
+        match self {
+            JitProtection::WriteXorExecute => Access::ReadWrite,
+            JitProtection::ReadWriteExecute => Access::ReadWriteExecutable,
+        }
+    }
+}
+
+static JIT_PROTECTION: AtomicU8 = AtomicU8::new(0);
+
+/// The protection mode new buffers and allocators start with.
+pub fn jit_protection() -> JitProtection {
+    match JIT_PROTECTION.load(Ordering::Relaxed) {
+        0 => JitProtection::WriteXorExecute,
+        _ => JitProtection::ReadWriteExecute,
+    }
+}
+
+pub fn set_jit_protection(protection: JitProtection) {
+    let value = match protection {
+        JitProtection::WriteXorExecute => 0,
+        JitProtection::ReadWriteExecute => 1,
+    };
+    JIT_PROTECTION.store(value, Ordering::Relaxed);
+}
+
+/// Commits `size` bytes. Executable memory starts out RW under
+/// `JitProtection::WriteXorExecute` and has to be flipped with `protect`.
+pub fn try_commit(size: usize, executable: bool) -> Result<*mut u8, Error> {
+    let access = if executable {
+        jit_protection().initial_access()
+    } else {
+        Access::ReadWrite
+    };
+    try_commit_access(size, access)
+}
+
+#[cfg(target_family = "unix")]
+pub fn try_commit_access(size: usize, access: Access) -> Result<*mut u8, Error> {
     let ptr = unsafe {
         libc::mmap(
             std::ptr::null_mut(),
             size,
-            prot,
+            access.unix_protection(),
             libc::MAP_PRIVATE | libc::MAP_ANON,
             -1,
             0,
@@ -18,88 +114,111 @@ pub fn commit(size: usize, executable: bool) -> *mut u8 {
     };
 
     if ptr == libc::MAP_FAILED {
-        panic!("committing memory with mmap() failed");
+        return Err(Error::last("mmap"));
     }
 
-    ptr as *mut u8
+    Ok(ptr as *mut u8)
 }
 
 #[cfg(target_family = "windows")]
-pub fn commit(size: usize, executable: bool) -> *mut u8 {
+pub fn try_commit_access(size: usize, access: Access) -> Result<*mut u8, Error> {
     use kernel32::VirtualAlloc;
-    use winapi::um::winnt::{MEM_COMMIT, MEM_RESERVE, PAGE_EXECUTE_READWRITE, PAGE_READWRITE};
-
-    let prot = if executable {
-        PAGE_EXECUTE_READWRITE
-    } else {
-        PAGE_READWRITE
-    };
+    use winapi::um::winnt::{MEM_COMMIT, MEM_RESERVE};
 
     let ptr = unsafe {
         VirtualAlloc(
             std::ptr::null_mut(),
             size as u64,
             MEM_COMMIT | MEM_RESERVE,
-            prot,
+            access.windows_protection(),
         )
     };
 
     if ptr.is_null() {
-        panic!("VirtualAlloc failed");
+        return Err(Error::last("VirtualAlloc"));
     }
 
-    ptr as *mut u8
+    Ok(ptr as *mut u8)
+}
+
+pub fn commit(size: usize, executable: bool) -> *mut u8 {
+    try_commit(size, executable).unwrap_or_else(|err| panic!("committing memory: {}", err))
 }
 
 #[cfg(target_family = "unix")]
-pub fn protect(start: *mut u8, size: usize, access: Access) {
-    if access.is_none() {
-        discard(start, size);
-        return;
+pub fn try_uncommit(ptr: *mut u8, size: usize) -> Result<(), Error> {
+    let res = unsafe { libc::munmap(ptr.cast(), size) };
+
+    if res != 0 {
+        return Err(Error::last("munmap"));
     }
 
-    let protection = match access {
-        Access::None => unreachable!(),
-        Access::Read => libc::PROT_READ,
-        Access::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
-        Access::ReadExecutable => libc::PROT_READ | libc::PROT_EXEC,
-        Access::ReadWriteExecutable => libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
-    };
+    Ok(())
+}
 
-    let res = unsafe { libc::mprotect(start.cast(), size, protection) };
+#[cfg(target_family = "windows")]
+pub fn try_uncommit(ptr: *mut u8, _size: usize) -> Result<(), Error> {
+    use kernel32::VirtualFree;
+    use winapi::um::winnt::MEM_RELEASE;
+
+    let res = unsafe { VirtualFree(ptr.cast(), 0, MEM_RELEASE) };
+
+    if res == 0 {
+        return Err(Error::last("VirtualFree"));
+    }
+
+    Ok(())
+}
+
+pub fn uncommit(ptr: *mut u8, size: usize) {
+    try_uncommit(ptr, size).unwrap_or_else(|err| panic!("uncommitting memory: {}", err))
+}
+
+#[cfg(target_family = "unix")]
+pub fn try_protect(start: *mut u8, size: usize, access: Access) -> Result<(), Error> {
+    if access.is_none() {
+        return try_discard(start, size);
+    }
+
+    let res = unsafe { libc::mprotect(start.cast(), size, access.unix_protection()) };
 
     if res != 0 {
-        panic!("mprotect() failed");
+        return Err(Error::last("mprotect"));
     }
+
+    Ok(())
 }
 
 #[cfg(target_family = "windows")]
-pub fn protect(start: *mut u8, size: usize, access: Access) {
+pub fn try_protect(start: *mut u8, size: usize, access: Access) -> Result<(), Error> {
     use kernel32::VirtualAlloc;
-    use winapi::um::winnt::{
-        MEM_COMMIT, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_READONLY, PAGE_READWRITE,
-    };
+    use winapi::um::winnt::MEM_COMMIT;
 
     if access.is_none() {
-        discard(start, size);
-        return;
+        return try_discard(start, size);
     }
 
-    let protection = match access {
-        Access::None => unreachable!(),
-        Access::Read => PAGE_READONLY,
-        Access::ReadWrite => PAGE_READWRITE,
-        Access::ReadExecutable => PAGE_EXECUTE_READ,
-        Access::ReadWriteExecutable => PAGE_EXECUTE_READWRITE,
+    let ptr = unsafe {
+        VirtualAlloc(
+            start.cast(),
+            size as u64,
+            MEM_COMMIT,
+            access.windows_protection(),
+        )
     };
 
-    let ptr = unsafe { VirtualAlloc(start.cast(), size as u64, MEM_COMMIT, protection) };
-
     if ptr.is_null() {
-        panic!("VirtualAlloc failed");
+        return Err(Error::last("VirtualAlloc"));
     }
+
+    Ok(())
+}
+
+pub fn protect(start: *mut u8, size: usize, access: Access) {
+    try_protect(start, size, access).unwrap_or_else(|err| panic!("protecting memory: {}", err))
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Access {
     None,
     Read,
@@ -110,34 +229,535 @@ pub enum Access {
 
 impl Access {
     fn is_none(&self) -> bool {
+        matches!(self, Access::None)
+    }
+
+    #[cfg(target_family = "unix")]
+    fn unix_protection(self) -> libc::c_int {
         match self {
-            Access::None => true,
-            _ => false,
+            Access::None => libc::PROT_NONE,
+            Access::Read => libc::PROT_READ,
+            Access::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+            Access::ReadExecutable => libc::PROT_READ | libc::PROT_EXEC,
+            Access::ReadWriteExecutable => libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+        }
+    }
+
+    #[cfg(target_family = "windows")]
+    fn windows_protection(self) -> u32 {
+        use winapi::um::winnt::{
+            PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_NOACCESS, PAGE_READONLY, PAGE_READWRITE,
+        };
+
+        match self {
+            Access::None => PAGE_NOACCESS,
+            Access::Read => PAGE_READONLY,
+            Access::ReadWrite => PAGE_READWRITE,
+            Access::ReadExecutable => PAGE_EXECUTE_READ,
+            Access::ReadWriteExecutable => PAGE_EXECUTE_READWRITE,
+        }
+    }
+}
+
+/// Copies `bytes` over code at `dst`. Code in a `DualMappedRegion` is
+/// written through its writable alias and RWX code in place; only then may
+/// other threads be executing it. A write that fits in one aligned 8-byte
+/// word is done with a single store, so such a thread sees either the old or
+/// the new bytes.
+///
+/// Otherwise, under `JitProtection::WriteXorExecute`, the covering pages are
+/// flipped to RW for the duration of the copy and back to RX afterwards. Any
+/// thread executing code on those pages meanwhile faults, so this path is only
+/// for memory that is not published yet.
+///
+/// # Safety
+/// `dst..dst + bytes.len()` must lie in committed code memory. If it is
+/// neither dual-mapped nor RWX, no thread may execute code on the pages it
+/// touches until the call returns.
+pub unsafe fn try_write_code(
+    dst: *mut u8,
+    bytes: &[u8],
+    protection: JitProtection,
+) -> Result<(), Error> {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    if let Some(alias) = writable_alias_range(dst, bytes.len()) {
+        store_code(alias, bytes);
+        return Ok(());
+    }
+    if protection == JitProtection::ReadWriteExecute {
+        store_code(dst, bytes);
+        return Ok(());
+    }
+
+    let start = dst as usize & !(page_size() - 1);
+    let size = page_align(dst as usize + bytes.len()) - start;
+    try_protect(start as *mut u8, size, Access::ReadWrite)?;
+    store_code(dst, bytes);
+    try_protect(start as *mut u8, size, Access::ReadExecutable)
+}
+
+unsafe fn store_code(dst: *mut u8, bytes: &[u8]) {
+    let word = dst as usize & !7;
+    if dst as usize + bytes.len() > word + 8 {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len());
+        return;
+    }
+    let word = &*(word as *const AtomicU64);
+    let at = dst as usize & 7;
+    let mut value = word.load(Ordering::Relaxed).to_ne_bytes();
+    value[at..at + bytes.len()].copy_from_slice(bytes);
+    word.store(u64::from_ne_bytes(value), Ordering::Release);
+}
+
+/// Makes code modified with `try_write_code` visible to every thread.
+///
+/// x86 instruction caches are coherent, but a thread that already fetched the
+/// old bytes may still execute them until it serializes. On Linux this uses
+/// `membarrier(MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE)` to serialize all
+/// threads of the process; elsewhere it is only a full fence. Either way it
+/// applies to all code, so it takes no address range.
+pub fn flush_code() {
+    std::sync::atomic::fence(Ordering::SeqCst);
+    #[cfg(target_os = "linux")]
+    {
+        const MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE: libc::c_int = 1 << 5;
+        const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE: libc::c_int = 1 << 6;
+        static REGISTERED: std::sync::Once = std::sync::Once::new();
+        static SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+        REGISTERED.call_once(|| {
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_membarrier,
+                    MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE,
+                    0,
+                )
+            };
+            SUPPORTED.store(res == 0, Ordering::Relaxed);
+        });
+        if SUPPORTED.load(Ordering::Relaxed) {
+            unsafe {
+                libc::syscall(
+                    libc::SYS_membarrier,
+                    MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE,
+                    0,
+                )
+            };
         }
     }
 }
 
+/// # Safety
+/// See `try_write_code`.
+pub unsafe fn write_code(dst: *mut u8, bytes: &[u8], protection: JitProtection) {
+    try_write_code(dst, bytes, protection).unwrap_or_else(|err| panic!("writing code: {}", err))
+}
+
 #[cfg(target_family = "unix")]
-pub fn discard(ptr: *mut u8, size: usize) {
+pub fn try_discard(ptr: *mut u8, size: usize) -> Result<(), Error> {
     let res = unsafe { libc::madvise(ptr.cast(), size, libc::MADV_DONTNEED) };
 
     if res != 0 {
-        panic!("discarding memory with madvise() failed");
+        return Err(Error::last("madvise"));
     }
 
     let res = unsafe { libc::mprotect(ptr.cast(), size, libc::PROT_NONE) };
 
     if res != 0 {
-        panic!("discarding memory with mprotect() failed");
+        return Err(Error::last("mprotect"));
     }
+
+    Ok(())
 }
 
 #[cfg(target_family = "windows")]
-pub fn discard(ptr: *mut u8, size: usize) {
+pub fn try_discard(ptr: *mut u8, size: usize) -> Result<(), Error> {
     use kernel32::VirtualFree;
     use winapi::um::winnt::MEM_DECOMMIT;
 
-    let _ = unsafe { VirtualFree(ptr.cast(), size as u64, MEM_DECOMMIT) };
+    let res = unsafe { VirtualFree(ptr.cast(), size as u64, MEM_DECOMMIT) };
+
+    if res == 0 {
+        return Err(Error::last("VirtualFree"));
+    }
+
+    Ok(())
+}
+
+pub fn discard(ptr: *mut u8, size: usize) {
+    try_discard(ptr, size).unwrap_or_else(|err| panic!("discarding memory: {}", err))
+}
+
+/// Memory backed by a `memfd` and mapped twice: an RX view that code runs
+/// from and an RW view at a different address that it is written through.
+/// Neither view is ever both writable and executable.
+///
+/// Live regions are registered globally so that `writable_alias` and
+/// `try_write_code` can translate executable addresses.
+pub struct DualMappedRegion {
+    executable: *mut u8,
+    writable: *mut u8,
+    size: usize,
+}
+
+unsafe impl Send for DualMappedRegion {}
+unsafe impl Sync for DualMappedRegion {}
+
+/// `(executable, writable, size)` of every live `DualMappedRegion`.
+static DUAL_MAPPINGS: RwLock<Vec<(usize, usize, usize)>> = RwLock::new(Vec::new());
+
+impl DualMappedRegion {
+    /// Maps `size` bytes, rounded up to whole pages.
+    #[cfg(target_os = "linux")]
+    pub fn new(size: usize) -> Result<Self, Error> {
+        let size = align_usize(size, page_size());
+        // The libc crate version in use has no `memfd_create` wrapper.
+        let name = b"proy-jit\0";
+        let fd = unsafe {
+            libc::syscall(libc::SYS_memfd_create, name.as_ptr(), libc::MFD_CLOEXEC) as libc::c_int
+        };
+        if fd < 0 {
+            return Err(Error::last("memfd_create"));
+        }
+
+        let map =
+            |prot| unsafe { libc::mmap(std::ptr::null_mut(), size, prot, libc::MAP_SHARED, fd, 0) };
+        let result = if unsafe { libc::ftruncate(fd, size as libc::off_t) } != 0 {
+            Err(Error::last("ftruncate"))
+        } else {
+            match map(libc::PROT_READ | libc::PROT_EXEC) {
+                libc::MAP_FAILED => Err(Error::last("mmap")),
+                executable => match map(libc::PROT_READ | libc::PROT_WRITE) {
+                    libc::MAP_FAILED => {
+                        let err = Error::last("mmap");
+                        unsafe { libc::munmap(executable, size) };
+                        Err(err)
+                    }
+                    writable => Ok(Self {
+                        executable: executable as *mut u8,
+                        writable: writable as *mut u8,
+                        size,
+                    }),
+                },
+            }
+        };
+        // The mappings keep the file alive.
+        unsafe { libc::close(fd) };
+
+        let region = result?;
+        DUAL_MAPPINGS.write().unwrap().push((
+            region.executable as usize,
+            region.writable as usize,
+            size,
+        ));
+        Ok(region)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_size: usize) -> Result<Self, Error> {
+        Err(Error::new("memfd_create", libc::ENOSYS))
+    }
+
+    pub fn executable_base(&self) -> *mut u8 {
+        self.executable
+    }
+
+    pub fn writable_base(&self) -> *mut u8 {
+        self.writable
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Translates an address in the executable view to the writable one.
+    pub fn writable_alias(&self, executable: *const u8) -> Option<*mut u8> {
+        let offset = (executable as usize).wrapping_sub(self.executable as usize);
+        if offset < self.size {
+            Some(unsafe { self.writable.add(offset) })
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for DualMappedRegion {
+    fn drop(&mut self) {
+        let mut mappings = DUAL_MAPPINGS.write().unwrap();
+        mappings.retain(|&(executable, _, _)| executable != self.executable as usize);
+        drop(mappings);
+
+        let res = try_uncommit(self.executable, self.size)
+            .and_then(|_| try_uncommit(self.writable, self.size));
+        debug_assert!(res.is_ok(), "{:?}", res);
+    }
+}
+
+/// Translates an address inside any live `DualMappedRegion` to its writable
+/// alias.
+pub fn writable_alias(executable: *const u8) -> Option<*mut u8> {
+    writable_alias_range(executable, 1)
+}
+
+pub(crate) fn writable_alias_range(executable: *const u8, len: usize) -> Option<*mut u8> {
+    let addr = executable as usize;
+    let mappings = DUAL_MAPPINGS.read().unwrap();
+    mappings
+        .iter()
+        .find(|&&(base, _, size)| addr >= base && addr + len <= base + size)
+        .map(|&(base, writable, _)| (writable + (addr - base)) as *mut u8)
+}
+
+/// Suballocates finalized code out of one large executable region, so that
+/// many small functions share pages instead of each getting its own mapping.
+///
+/// Chunks are handed out first-fit and adjacent free chunks are coalesced
+/// when released.
+pub struct ExecutableAllocator {
+    base: *mut u8,
+    size: usize,
+    protection: JitProtection,
+    /// Backing of allocators created with `dual_mapped`.
+    dual: Option<DualMappedRegion>,
+    state: Mutex<AllocatorState>,
+}
+
+unsafe impl Send for ExecutableAllocator {}
+unsafe impl Sync for ExecutableAllocator {}
+
+struct AllocatorState {
+    /// Free blocks keyed by offset, mapping to their size.
+    free: BTreeMap<usize, usize>,
+    /// Live chunks keyed by offset, mapping to their size.
+    live: BTreeMap<usize, usize>,
+    allocated: usize,
+    peak_allocated: usize,
+}
+
+/// Snapshot of an `ExecutableAllocator`'s usage.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct AllocatorStats {
+    /// Size of the reserved region.
+    pub reserved: usize,
+    /// Bytes handed out to live chunks, including granule rounding.
+    pub allocated: usize,
+    pub peak_allocated: usize,
+    pub free: usize,
+    /// Size of the largest free block, i.e. the biggest request that can
+    /// succeed with the minimal alignment.
+    pub largest_free_block: usize,
+    pub free_blocks: usize,
+    pub live_allocations: usize,
+}
+
+impl ExecutableAllocator {
+    /// Chunk sizes and addresses are multiples of this, except in a W^X pool
+    /// without a dual mapping; see `with_protection`.
+    pub const GRANULE: usize = 16;
+
+    /// Reserves `size` bytes, rounded up to whole pages, protected according
+    /// to `jit_protection()`. Under `JitProtection::WriteXorExecute` the pool
+    /// is `dual_mapped` where `memfd_create` is available, so that filling a
+    /// chunk never touches the protection of code that may be running.
+    pub fn new(size: usize) -> Result<Self, Error> {
+        let protection = jit_protection();
+        if protection == JitProtection::WriteXorExecute {
+            match Self::dual_mapped(size) {
+                Err(err) if err.operation() == "memfd_create" => {}
+                result => return result,
+            }
+        }
+        Self::with_protection(size, protection)
+    }
+
+    /// Under `JitProtection::WriteXorExecute` the region is mapped RX and
+    /// chunks are filled through `write` by flipping their pages to RW. To
+    /// keep those flips away from published code, chunks are then whole pages
+    /// that share none with a neighbour.
+    pub fn with_protection(size: usize, protection: JitProtection) -> Result<Self, Error> {
+        let size = align_usize(size, page_size());
+        let access = match protection {
+            JitProtection::WriteXorExecute => Access::ReadExecutable,
+            JitProtection::ReadWriteExecute => Access::ReadWriteExecutable,
+        };
+        let base = try_commit_access(size, access)?;
+        Ok(Self::with_region(base, size, protection, None))
+    }
+
+    /// Backs the allocator with a `DualMappedRegion`, so chunks are written
+    /// through the RW view while other code keeps running from the RX one.
+    pub fn dual_mapped(size: usize) -> Result<Self, Error> {
+        let region = DualMappedRegion::new(size)?;
+        Ok(Self::with_region(
+            region.executable_base(),
+            region.size(),
+            JitProtection::WriteXorExecute,
+            Some(region),
+        ))
+    }
+
+    fn with_region(
+        base: *mut u8,
+        size: usize,
+        protection: JitProtection,
+        dual: Option<DualMappedRegion>,
+    ) -> Self {
+        let mut free = BTreeMap::new();
+        free.insert(0, size);
+
+        Self {
+            base,
+            size,
+            protection,
+            dual,
+            state: Mutex::new(AllocatorState {
+                free,
+                live: BTreeMap::new(),
+                allocated: 0,
+                peak_allocated: 0,
+            }),
+        }
+    }
+
+    pub fn base(&self) -> *mut u8 {
+        self.base
+    }
+
+    pub fn reserved_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn protection(&self) -> JitProtection {
+        self.protection
+    }
+
+    pub fn dual_mapping(&self) -> Option<&DualMappedRegion> {
+        self.dual.as_ref()
+    }
+
+    /// Size and alignment unit of chunks.
+    fn granule(&self) -> usize {
+        if self.dual.is_none() && self.protection == JitProtection::WriteXorExecute {
+            page_size()
+        } else {
+            Self::GRANULE
+        }
+    }
+
+    /// Copies `bytes` into the region at `offset` from `base()`, see
+    /// `try_write_code`. In a W^X pool without a dual mapping this flips the
+    /// pages of the chunk, so it must not be executing yet.
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> Result<(), Error> {
+        assert!(offset + bytes.len() <= self.size);
+        if let Some(ref dual) = self.dual {
+            let dst = unsafe { dual.writable_base().add(offset) };
+            unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len()) };
+            return Ok(());
+        }
+        unsafe { try_write_code(self.base.add(offset), bytes, self.protection) }
+    }
+
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let addr = ptr as usize;
+        addr >= self.base as usize && addr < self.base as usize + self.size
+    }
+
+    /// Returns a chunk of at least `size` bytes aligned to `alignment`, which
+    /// must be a power of two. The chunk's rounded size is returned with it.
+    pub fn allocate(&self, size: usize, alignment: usize) -> Result<(*mut u8, usize), Error> {
+        assert!(alignment.is_power_of_two());
+        if size == 0 {
+            return Err(Error::new("ExecutableAllocator::allocate", libc::EINVAL));
+        }
+        let granule = self.granule();
+        let size = align_usize(size, granule);
+        let alignment = alignment.max(granule);
+        let base = self.base as usize;
+
+        let mut state = self.state.lock().unwrap();
+        let found = state.free.iter().find_map(|(&offset, &len)| {
+            let start = align_usize(base + offset, alignment) - base;
+            if start + size <= offset + len {
+                Some((offset, len, start))
+            } else {
+                None
+            }
+        });
+        let (offset, len, start) = match found {
+            Some(found) => found,
+            None => return Err(Error::new("ExecutableAllocator::allocate", libc::ENOMEM)),
+        };
+
+        state.free.remove(&offset);
+        if start > offset {
+            state.free.insert(offset, start - offset);
+        }
+        if start + size < offset + len {
+            state.free.insert(start + size, offset + len - start - size);
+        }
+        state.live.insert(start, size);
+        state.allocated += size;
+        state.peak_allocated = state.peak_allocated.max(state.allocated);
+
+        Ok((unsafe { self.base.add(start) }, size))
+    }
+
+    /// Returns a chunk obtained from `allocate` to the pool.
+    ///
+    /// Panics if `ptr` is not the start of a live chunk.
+    pub fn free(&self, ptr: *mut u8) {
+        assert!(self.contains(ptr), "pointer outside of the allocator");
+        let mut offset = ptr as usize - self.base as usize;
+
+        let mut state = self.state.lock().unwrap();
+        let mut size = state
+            .live
+            .remove(&offset)
+            .expect("freeing a chunk that is not allocated");
+        state.allocated -= size;
+
+        // Coalesce with the following block, then with the preceding one.
+        if let Some(next) = state.free.remove(&(offset + size)) {
+            size += next;
+        }
+        let prev = state
+            .free
+            .range(..offset)
+            .next_back()
+            .map(|(&prev, &len)| (prev, len));
+        if let Some((prev, len)) = prev {
+            if prev + len == offset {
+                offset = prev;
+                size += len;
+            }
+        }
+        state.free.insert(offset, size);
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let state = self.state.lock().unwrap();
+        AllocatorStats {
+            reserved: self.size,
+            allocated: state.allocated,
+            peak_allocated: state.peak_allocated,
+            free: self.size - state.allocated,
+            largest_free_block: state.free.values().copied().max().unwrap_or(0),
+            free_blocks: state.free.len(),
+            live_allocations: state.live.len(),
+        }
+    }
+}
+
+impl Drop for ExecutableAllocator {
+    fn drop(&mut self) {
+        if self.dual.is_some() {
+            return;
+        }
+        let res = try_uncommit(self.base, self.size);
+        debug_assert!(res.is_ok(), "{:?}", res);
+    }
 }
 
 static mut PAGE_SIZE: usize = 0;
@@ -202,7 +822,7 @@ fn determine_page_size() -> usize {
 /// determine log_2 of given value
 fn log2(mut val: usize) -> usize {
     let mut log = 0;
-    assert!(val <= u32::max_value() as usize);
+    assert!(val <= u32::MAX as usize);
 
     if (val & 0xFFFF0000) != 0 {
         val >>= 16;
@@ -230,7 +850,6 @@ fn test_log2() {
         assert_eq!(i, log2(1 << i));
     }
 }
-use std::i32;
 use std::mem::size_of;
 /// return pointer width: either 4 or 8
 /// (although only 64bit architectures are supported right now)
@@ -257,12 +876,12 @@ pub fn is_page_aligned(val: usize) -> bool {
 fn test_is_page_aligned() {
     let p = page_size();
 
-    assert_eq!(false, is_page_aligned(1));
-    assert_eq!(false, is_page_aligned(2));
-    assert_eq!(false, is_page_aligned(64));
-    assert_eq!(true, is_page_aligned(p));
-    assert_eq!(true, is_page_aligned(2 * p));
-    assert_eq!(true, is_page_aligned(3 * p));
+    assert!(!is_page_aligned(1));
+    assert!(!is_page_aligned(2));
+    assert!(!is_page_aligned(64));
+    assert!(is_page_aligned(p));
+    assert!(is_page_aligned(2 * p));
+    assert!(is_page_aligned(3 * p));
 }
 
 /// round the given value up to the nearest multiple of a page
@@ -291,7 +910,7 @@ pub fn align(value: u32, align: u32) -> u32 {
         return value;
     }
 
-    ((value + align - 1) / align) * align
+    value.div_ceil(align) * align
 }
 
 /// rounds the given value `val` up to the nearest multiple
@@ -311,7 +930,7 @@ pub fn align_usize(value: usize, align: usize) -> usize {
         return value;
     }
 
-    ((value + align - 1) / align) * align
+    value.div_ceil(align) * align
 }
 
 /// returns 'true' if th given `value` is already aligned
@@ -322,10 +941,105 @@ pub fn is_aligned(value: usize, align: usize) -> bool {
 
 /// returns true if value fits into u8 (unsigned 8bits).
 pub fn fits_u8(value: i64) -> bool {
-    0 <= value && value <= 255
+    (0..=255).contains(&value)
 }
 
 /// returns true if value fits into i32 (signed 32bits).
 pub fn fits_i32(value: i64) -> bool {
     i32::MIN as i64 <= value && value <= i32::MAX as i64
 }
+
+#[cfg(target_family = "unix")]
+#[test]
+fn test_errors_are_reported() {
+    let err = try_commit(0, false).unwrap_err();
+    assert_eq!(err.operation(), "mmap");
+    assert_eq!(err.errno(), libc::EINVAL);
+
+    // Not page aligned.
+    let err = try_protect(std::ptr::NonNull::dangling().as_ptr(), 1, Access::Read).unwrap_err();
+    assert_eq!(err.errno(), libc::EINVAL);
+}
+
+#[test]
+fn test_executable_allocator() {
+    // RWX pools allocate in `GRANULE`s whether or not memfd is available.
+    let alloc = ExecutableAllocator::with_protection(1, JitProtection::ReadWriteExecute).unwrap();
+    let p = page_size();
+    assert_eq!(alloc.reserved_size(), p);
+
+    let (a, a_size) = alloc.allocate(1, 1).unwrap();
+    let (b, b_size) = alloc.allocate(40, 64).unwrap();
+    let (c, _) = alloc.allocate(16, 16).unwrap();
+    assert_eq!(a, alloc.base());
+    assert_eq!(a_size, ExecutableAllocator::GRANULE);
+    assert_eq!(b_size, 48);
+    assert!(is_aligned(b as usize, 64));
+
+    let stats = alloc.stats();
+    assert_eq!(stats.allocated, 16 + 48 + 16);
+    assert_eq!(stats.live_allocations, 3);
+    // The alignment gap between `a` and `b` stays free.
+    assert_eq!(stats.free_blocks, 2);
+
+    alloc.free(b);
+    alloc.free(a);
+    assert_eq!(alloc.stats().free_blocks, 2);
+    alloc.free(c);
+
+    let stats = alloc.stats();
+    assert_eq!(stats.allocated, 0);
+    assert_eq!(stats.peak_allocated, 80);
+    assert_eq!(stats.free_blocks, 1);
+    assert_eq!(stats.largest_free_block, p);
+
+    assert_eq!(alloc.allocate(p + 1, 16).unwrap_err().errno(), libc::ENOMEM);
+    assert_eq!(alloc.allocate(0, 16).unwrap_err().errno(), libc::EINVAL);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_write_xor_execute_allocator() {
+    // The default W^X pool writes through an alias, so chunks share pages.
+    let alloc = ExecutableAllocator::new(1).unwrap();
+    assert!(alloc.dual_mapping().is_some());
+    assert_eq!(
+        alloc.allocate(1, 1).unwrap().1,
+        ExecutableAllocator::GRANULE
+    );
+
+    // Without one, every chunk gets pages of its own.
+    let p = page_size();
+    let alloc =
+        ExecutableAllocator::with_protection(3 * p, JitProtection::WriteXorExecute).unwrap();
+    let (a, a_size) = alloc.allocate(1, 1).unwrap();
+    let (b, b_size) = alloc.allocate(p + 1, 16).unwrap();
+    assert_eq!((a_size, b_size), (p, 2 * p));
+    assert_eq!(b as usize - a as usize, p);
+    alloc.write(p, &[0xc3]).unwrap();
+    assert_eq!(unsafe { *b }, 0xc3);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_dual_mapped_region() {
+    let region = DualMappedRegion::new(1).unwrap();
+    let exec = region.executable_base();
+    assert_ne!(exec, region.writable_base());
+    assert_eq!(region.writable_alias(exec), Some(region.writable_base()));
+    assert_eq!(
+        region.writable_alias(unsafe { exec.add(region.size()) }),
+        None
+    );
+
+    let at = unsafe { exec.add(8) };
+    let alias = writable_alias(at).unwrap();
+    assert_eq!(alias, unsafe { region.writable_base().add(8) });
+    unsafe {
+        try_write_code(at, &[1, 2, 3], JitProtection::WriteXorExecute).unwrap();
+        assert_eq!(std::slice::from_raw_parts(at, 3), &[1, 2, 3]);
+    }
+
+    drop(region);
+    assert_eq!(writable_alias(at), None);
+}
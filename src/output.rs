@@ -0,0 +1,190 @@
+//! Writing the raw bytes of assembled code: flat binaries, Intel HEX, `xxd`
+//! style hex dumps and Rust array literals.
+//!
+//! Data links are applied. Fields that depend on where the code is placed,
+//! such as external calls, are written as emitted; see
+//! `X86Assembler::finish_for_output`. The text formats start a new row at
+//! every label named with `AssemblerBuffer::name_label` and print its name.
+use crate::assembler::{AssemblerBuffer, AssemblerLabel};
+use std::io::{self, Write};
+
+/// Bytes per row of the text formats.
+const ROW: usize = 16;
+
+impl AssemblerBuffer {
+    /// Splits the code into rows of at most `ROW` bytes, breaking at named
+    /// labels, and calls `f` with each row's offset, bytes and the names of
+    /// the labels at its start.
+    fn for_each_row(
+        &self,
+        mut f: impl FnMut(usize, &[u8], &[&str]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let code = self.relocatable().code;
+        let mut symbols: Vec<(usize, &str)> = self
+            .symbols
+            .iter()
+            .map(|(name, label)| (label.offset as usize, name.as_str()))
+            .collect();
+        symbols.sort_by_key(|&(offset, _)| offset);
+
+        let mut offset = 0;
+        let mut next = 0;
+        while offset < code.len() || next < symbols.len() {
+            let mut names = vec![];
+            while next < symbols.len() && symbols[next].0 == offset {
+                names.push(symbols[next].1);
+                next += 1;
+            }
+            let end = symbols
+                .get(next)
+                .map_or(code.len(), |&(at, _)| at)
+                .min(offset + ROW)
+                .min(code.len());
+            f(offset, &code[offset..end], &names)?;
+            offset = end;
+        }
+        Ok(())
+    }
+
+    /// Writes the code as a flat binary.
+    pub fn write_bin(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&self.relocatable().code)
+    }
+
+    /// Writes the code as Intel HEX records loaded at `base`. The format has
+    /// no room for label names.
+    pub fn write_intel_hex(&self, w: &mut impl Write, base: u32) -> io::Result<()> {
+        let record = |w: &mut dyn Write, address: u16, kind: u8, data: &[u8]| {
+            let mut sum = data.len() as u8;
+            sum = sum
+                .wrapping_add((address >> 8) as u8)
+                .wrapping_add(address as u8)
+                .wrapping_add(kind);
+            write!(w, ":{:02X}{:04X}{:02X}", data.len(), address, kind)?;
+            for &byte in data {
+                sum = sum.wrapping_add(byte);
+                write!(w, "{:02X}", byte)?;
+            }
+            writeln!(w, "{:02X}", sum.wrapping_neg())
+        };
+
+        let code = self.relocatable().code;
+        let mut upper = None;
+        let mut offset = 0;
+        while offset < code.len() {
+            let address = base.wrapping_add(offset as u32);
+            // Rows never cross a 64K boundary of the extended linear address.
+            let len = ROW
+                .min(code.len() - offset)
+                .min(0x10000 - (address & 0xffff) as usize);
+            if upper != Some(address >> 16) {
+                record(w, 0, 4, &((address >> 16) as u16).to_be_bytes())?;
+                upper = Some(address >> 16);
+            }
+            record(w, address as u16, 0, &code[offset..offset + len])?;
+            offset += len;
+        }
+        record(w, 0, 1, &[])
+    }
+
+    /// Writes an `xxd` style hex dump, each named label on a line of its own.
+    pub fn write_hex_dump(&self, w: &mut impl Write) -> io::Result<()> {
+        self.for_each_row(|offset, bytes, names| {
+            for name in names {
+                writeln!(w, "{}:", name)?;
+            }
+            if bytes.is_empty() {
+                return Ok(());
+            }
+            write!(w, "{:08x}:", offset)?;
+            for i in 0..ROW {
+                if i % 2 == 0 {
+                    write!(w, " ")?;
+                }
+                match bytes.get(i) {
+                    Some(byte) => write!(w, "{:02x}", byte)?,
+                    None => write!(w, "  ")?,
+                }
+            }
+            write!(w, "  ")?;
+            for &byte in bytes {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(w, "{}", c)?;
+            }
+            writeln!(w)
+        })
+    }
+
+    /// Writes `pub const <name>: [u8; N] = [...];` with a comment at each
+    /// named label.
+    pub fn write_rust_const(&self, w: &mut impl Write, name: &str) -> io::Result<()> {
+        writeln!(w, "pub const {}: [u8; {}] = [", name, self.code_size())?;
+        self.for_each_row(|offset, bytes, names| {
+            for name in names {
+                writeln!(w, "    // {} (offset {:#x})", name, offset)?;
+            }
+            if bytes.is_empty() {
+                return Ok(());
+            }
+            write!(w, "   ")?;
+            for byte in bytes {
+                write!(w, " {:#04x},", byte)?;
+            }
+            writeln!(w)
+        })?;
+        writeln!(w, "];")
+    }
+}
+
+#[test]
+fn test_output_formats() {
+    let mut buffer = AssemblerBuffer::new();
+    // push %rbp; mov $1, %eax; pop %rbp; ret; then 20 bytes of "proy".
+    buffer.append(&[0x55, 0xb8, 1, 0, 0, 0, 0x5d, 0xc3]);
+    let table = buffer.label();
+    buffer.append(&b"proy".repeat(5));
+    buffer.name_label(AssemblerLabel::new(0), "entry");
+    buffer.name_label(table, "table");
+    buffer.name_label(buffer.label(), "end");
+
+    let mut bin = vec![];
+    buffer.write_bin(&mut bin).unwrap();
+    assert_eq!(bin, buffer.data());
+
+    let mut hex = vec![];
+    buffer.write_intel_hex(&mut hex, 0xfff8).unwrap();
+    assert_eq!(
+        String::from_utf8(hex).unwrap(),
+        ":020000040000FA\n\
+         :08FFF80055B8010000005DC3D3\n\
+         :020000040001F9\n\
+         :1000000070726F7970726F7970726F7970726F79C8\n\
+         :0400100070726F7922\n\
+         :00000001FF\n"
+    );
+
+    let mut dump = vec![];
+    buffer.write_hex_dump(&mut dump).unwrap();
+    assert_eq!(
+        String::from_utf8(dump).unwrap(),
+        "entry:\n\
+         00000000: 55b8 0100 0000 5dc3                      U.....].\n\
+         table:\n\
+         00000008: 7072 6f79 7072 6f79 7072 6f79 7072 6f79  proyproyproyproy\n\
+         00000018: 7072 6f79                                proy\n\
+         end:\n"
+    );
+
+    let mut literal = vec![];
+    buffer.write_rust_const(&mut literal, "CODE").unwrap();
+    let literal = String::from_utf8(literal).unwrap();
+    assert!(literal.starts_with("pub const CODE: [u8; 28] = [\n    // entry (offset 0x0)\n"));
+    assert!(literal.contains(
+        "    0x55, 0xb8, 0x01, 0x00, 0x00, 0x00, 0x5d, 0xc3,\n    // table (offset 0x8)\n"
+    ));
+    assert!(literal.ends_with("    // end (offset 0x1c)\n];\n"));
+}
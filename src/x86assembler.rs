@@ -1,4 +1,5 @@
 use super::assembler::*;
+use std::collections::HashMap;
 #[derive(Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug, Hash)]
 #[repr(u8)]
 pub enum X86Gpr {
@@ -38,11 +39,92 @@ pub enum X86Fpr {
     XMM5,
     XMM6,
     XMM7,
+    #[cfg(target_arch = "x86_64")]
+    XMM8,
+    #[cfg(target_arch = "x86_64")]
+    XMM9,
+    #[cfg(target_arch = "x86_64")]
+    XMM10,
+    #[cfg(target_arch = "x86_64")]
+    XMM11,
+    #[cfg(target_arch = "x86_64")]
+    XMM12,
+    #[cfg(target_arch = "x86_64")]
+    XMM13,
+    #[cfg(target_arch = "x86_64")]
+    XMM14,
+    #[cfg(target_arch = "x86_64")]
+    XMM15,
+}
+
+/// Condition codes in the order the `Jcc`/`SETcc`/`CMOVcc` opcodes encode them.
+#[derive(Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug, Hash)]
+#[repr(u8)]
+pub enum X86Condition {
+    O,
+    NO,
+    B,
+    AE,
+    E,
+    NE,
+    BE,
+    A,
+    S,
+    NS,
+    P,
+    NP,
+    L,
+    GE,
+    LE,
+    G,
+}
+
+impl X86Condition {
+    /// The condition that holds exactly when `self` does not.
+    pub fn invert(self) -> Self {
+        use X86Condition::*;
+        match self {
+            O => NO,
+            NO => O,
+            B => AE,
+            AE => B,
+            E => NE,
+            NE => E,
+            BE => A,
+            A => BE,
+            S => NS,
+            NS => S,
+            P => NP,
+            NP => P,
+            L => GE,
+            GE => L,
+            LE => G,
+            G => LE,
+        }
+    }
+
+    /// The condition to test after swapping the operands of the comparison,
+    /// e.g. `a < b` is `b > a`.
+    pub fn commute(self) -> Self {
+        use X86Condition::*;
+        match self {
+            B => A,
+            A => B,
+            AE => BE,
+            BE => AE,
+            L => G,
+            G => L,
+            GE => LE,
+            LE => GE,
+            // Equality, overflow, sign and parity do not depend on the order.
+            _ => self,
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug, Hash)]
 #[repr(u8)]
-enum ModRmMode {
+pub(crate) enum ModRmMode {
     NoDisp,
     Disp8,
     Disp32,
@@ -57,14 +139,26 @@ const NO_BASE2: u8 = X86Gpr::R13 as u8;
 #[cfg(target_arch = "x86_64")]
 const HAS_SIB2: u8 = X86Gpr::R12 as u8;
 
+/// Pseudo base register of a `[rip + disp32]` operand, handed out by
+/// `X86Assembler::rip_relative`. It encodes as `rbp` without `REX.B`, which
+/// `mod=00` turns into RIP-relative addressing.
+#[cfg(target_arch = "x86_64")]
+pub const RIP: u8 = 0x10 | NO_BASE;
+
 pub struct X86InsFormatter {
-    buffer: AssemblerBuffer,
+    pub(crate) buffer: AssemblerBuffer,
+    /// End of the last watchpoint's patchable bytes; finalisation pads the
+    /// code with NOPs up to here.
+    watchpoint_tail: usize,
+    /// While `X86Assembler::rip_relative` emits its instruction, the number
+    /// of `RIP` operands and the end of the last one's disp32.
+    rip_operands: Option<(usize, AssemblerLabel)>,
 }
 
 impl X86InsFormatter {
     fn put_modrm(&mut self, mode: ModRmMode, r: u8, rm: i32) {
         self.buffer
-            .put_byte(((mode as u8) << 6) | ((r as u8 & 7) << 3) | (rm as u8 & 7));
+            .put_byte(((mode as u8) << 6) | ((r & 7) << 3) | (rm as u8 & 7));
     }
 
     fn put_modrm_sib(&mut self, mode: ModRmMode, r: u8, base: u8, index: u8, scale: i32) {
@@ -76,16 +170,37 @@ impl X86InsFormatter {
     fn register_modrm(&mut self, reg: u8, rm: u8) {
         self.put_modrm(ModRmMode::Reg, reg, rm as _);
     }
+    #[cfg(target_arch = "x86_64")]
+    fn rip_modrm(&mut self, r: u8, offset: i32) {
+        self.put_modrm(ModRmMode::NoDisp, r, NO_BASE as _);
+        self.buffer.put_int(offset);
+        let label = self.buffer.label();
+        let (count, disp) = self
+            .rip_operands
+            .as_mut()
+            .expect("RIP operand outside X86Assembler::rip_relative");
+        *count += 1;
+        *disp = label;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn assert_not_rip(base: u8, what: &str) {
+        assert!(base != RIP, "RIP-relative operands {}", what);
+    }
+    #[cfg(target_arch = "x86")]
+    fn assert_not_rip(_: u8, _: &str) {}
+
     fn memory_modrm_1(&mut self, r: u8, base: u8, offset: i32) {
-        let cond;
         #[cfg(target_arch = "x86_64")]
         {
-            cond = base == HAS_SIB || base == HAS_SIB2
-        };
+            if base == RIP {
+                return self.rip_modrm(r, offset);
+            }
+        }
+        #[cfg(target_arch = "x86_64")]
+        let cond = base == HAS_SIB || base == HAS_SIB2;
         #[cfg(target_arch = "x86")]
-        {
-            cond = base == HAS_SIB
-        };
+        let cond = base == HAS_SIB;
         if cond {
             if offset == 0 {
                 self.put_modrm_sib(ModRmMode::NoDisp, r, base, NO_INDEX as _, 0);
@@ -97,19 +212,15 @@ impl X86InsFormatter {
                 self.buffer.put_int(offset as _);
             }
         } else {
-            let additional;
             #[cfg(target_arch = "x86_64")]
-            {
-                additional = base != NO_BASE2;
-            }
+            let additional = base != NO_BASE2;
             #[cfg(target_arch = "x86")]
-            {
-                additional = true;
-            }
+            let additional = true;
             if offset == 0 && (base != NO_BASE) && additional {
                 self.put_modrm(ModRmMode::NoDisp, r, base as _);
             } else if can_sign_extend(offset) {
                 self.put_modrm(ModRmMode::Disp8, r, base as _);
+                self.buffer.put_byte(offset as _);
             } else {
                 self.put_modrm(ModRmMode::Disp32, r, base as _);
                 self.buffer.put_int(offset);
@@ -118,16 +229,12 @@ impl X86InsFormatter {
     }
 
     fn memory_modrm_disp8(&mut self, r: u8, base: u8, offset: i32) {
+        Self::assert_not_rip(base, "have a disp32");
         assert!(can_sign_extend(offset));
-        let cond;
         #[cfg(target_arch = "x86_64")]
-        {
-            cond = base == HAS_SIB || base == HAS_SIB2
-        };
+        let cond = base == HAS_SIB || base == HAS_SIB2;
         #[cfg(target_arch = "x86")]
-        {
-            cond = base == HAS_SIB
-        };
+        let cond = base == HAS_SIB;
         if cond {
             self.put_modrm_sib(ModRmMode::Disp8, r, base, NO_INDEX, 0);
             self.buffer.put_byte(offset as _);
@@ -137,16 +244,16 @@ impl X86InsFormatter {
         }
     }
     fn memory_modrm_disp32(&mut self, r: u8, base: u8, offset: i32) {
-        assert!(can_sign_extend(offset));
-        let cond;
         #[cfg(target_arch = "x86_64")]
         {
-            cond = base == HAS_SIB || base == HAS_SIB2
-        };
+            if base == RIP {
+                return self.rip_modrm(r, offset);
+            }
+        }
+        #[cfg(target_arch = "x86_64")]
+        let cond = base == HAS_SIB || base == HAS_SIB2;
         #[cfg(target_arch = "x86")]
-        {
-            cond = base == HAS_SIB
-        };
+        let cond = base == HAS_SIB;
         if cond {
             self.put_modrm_sib(ModRmMode::Disp32, r, base, NO_INDEX, 0);
             self.buffer.put_int(offset as _);
@@ -157,16 +264,12 @@ impl X86InsFormatter {
     }
 
     fn memory_modrm_2(&mut self, r: u8, base: u8, index: u8, scale: i32, offset: i32) {
+        Self::assert_not_rip(base, "cannot be indexed");
         assert!(index != NO_INDEX);
-        let cond;
         #[cfg(target_arch = "x86_64")]
-        {
-            cond = offset == 0 && (base != NO_BASE) && (base != NO_BASE2)
-        };
+        let cond = offset == 0 && (base != NO_BASE) && (base != NO_BASE2);
         #[cfg(target_arch = "x86")]
-        {
-            cond = offset == 0 && (base != NO_BASE)
-        };
+        let cond = offset == 0 && (base != NO_BASE);
         if cond {
             self.put_modrm_sib(ModRmMode::NoDisp, r, base, index, scale);
         } else if can_sign_extend(offset) {
@@ -178,10 +281,17 @@ impl X86InsFormatter {
         }
     }
 
+    /// `[index * scale + disp32]` without a base register.
+    fn memory_modrm_no_base(&mut self, r: u8, index: u8, scale: i32, offset: i32) {
+        assert!(index != NO_INDEX);
+        self.put_modrm_sib(ModRmMode::NoDisp, r, NO_BASE, index, scale);
+        self.buffer.put_int(offset);
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[inline]
     pub fn reg_requires_rex(r: u8) -> bool {
-        r >= X86Gpr::R8 as u8
+        r & 8 != 0
     }
     #[cfg(target_arch = "x86_64")]
     #[inline]
@@ -192,7 +302,7 @@ impl X86InsFormatter {
     #[inline]
     pub fn emit_rex(&mut self, w: u8, r: u8, x: u8, b: u8) {
         self.buffer
-            .put_byte(PRE_REX | (w << 3) | ((r >> 3) << 2) | ((x >> 3) << 1) | (b >> 3));
+            .put_byte(PRE_REX | (w << 3) | (r >> 3 & 1) << 2 | (x >> 3 & 1) << 1 | (b >> 3 & 1));
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -234,12 +344,115 @@ impl X86InsFormatter {
     #[inline]
     pub const fn emit_rex_if_needed(&mut self, _: u8, _: u8, _: u8) {}
 
-    pub fn executable_writable(&mut self) -> *mut u8 {
-        self.buffer.executable_writable_memory().unwrap()
+    /// Completes the code before it is copied out: a watchpoint at the very
+    /// end still needs room for its jump, external calls need veneers and
+    /// pooled constants are appended. `placement` is the address range the
+    /// code will be placed in, if known.
+    fn finish(&mut self, placement: Option<(usize, usize)>) {
+        self.pad_watchpoint_tail();
+        self.emit_call_veneers(placement);
+        self.buffer.place_constant_pool(OP_HLT);
+    }
+
+    /// Like `finish` for code written to an object file, where the linker
+    /// takes care of reaching external calls.
+    pub(crate) fn finish_object(&mut self) {
+        self.pad_watchpoint_tail();
+        self.buffer.place_constant_pool(OP_HLT);
+    }
+
+    fn pad_watchpoint_tail(&mut self) {
+        while self.code_size() < self.watchpoint_tail {
+            self.one_byte_op_1(OP_NOP);
+        }
+    }
+
+    /// Appends a `jmp *0(%rip)` + 8-byte address trampoline for every external
+    /// call target that does not have one yet. Targets a rel32 reaches from
+    /// anywhere in `placement` are called directly and get none.
+    fn emit_call_veneers(&mut self, placement: Option<(usize, usize)>) {
+        let reaches = |target: usize| match placement {
+            Some((start, end)) => [start, end].iter().all(|&pc| {
+                let disp = target as i64 - pc as i64;
+                disp == disp as i32 as i64
+            }),
+            None => false,
+        };
+        let mut veneers = self
+            .buffer
+            .relocations
+            .iter()
+            .filter_map(|r| match r.target {
+                RelocationTarget::Absolute(target) if r.veneer.is_set() => Some((target, r.veneer)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        for i in 0..self.buffer.relocations.len() {
+            let relocation = self.buffer.relocations[i];
+            let target = match relocation.target {
+                RelocationTarget::Absolute(target) => target,
+                RelocationTarget::Label(_) => continue,
+            };
+            if relocation.kind != RelocationKind::Rel32Abs
+                || relocation.veneer.is_set()
+                || reaches(target)
+            {
+                continue;
+            }
+            let veneer = match veneers.get(&target) {
+                Some(&veneer) => veneer,
+                None => {
+                    let veneer = self.label();
+                    self.one_byte_op_1(OP_GROUP5_Ev);
+                    self.put_modrm(ModRmMode::NoDisp, GROUP5_OP_JMPN, NO_BASE as _);
+                    self.imm32(0);
+                    self.imm64(target as i64);
+                    veneers.insert(target, veneer);
+                    veneer
+                }
+            };
+            self.buffer.relocations[i].veneer = veneer;
+        }
+    }
+
+    pub fn try_executable_writable(&mut self) -> Result<ExecutableCode, crate::mem::Error> {
+        self.finish(None);
+        self.buffer.executable_writable_memory()
+    }
+
+    pub fn try_executable_readable(&mut self) -> Result<ExecutableCode, crate::mem::Error> {
+        self.finish(None);
+        self.buffer.executable_memory()
+    }
+
+    /// Places the code in a chunk of `pool` aligned for a function entry and
+    /// for its pooled constants.
+    pub fn try_executable_in(
+        &mut self,
+        pool: &std::sync::Arc<crate::mem::ExecutableAllocator>,
+    ) -> Result<ExecutableCode, crate::mem::Error> {
+        let start = pool.base() as usize;
+        self.finish(Some((start, start + pool.reserved_size())));
+        let alignment = self.buffer.constant_alignment().max(16);
+        self.buffer.executable_memory_in(pool, alignment)
+    }
+
+    pub fn executable_in(
+        &mut self,
+        pool: &std::sync::Arc<crate::mem::ExecutableAllocator>,
+    ) -> ExecutableCode {
+        self.try_executable_in(pool)
+            .unwrap_or_else(|err| panic!("finalizing code: {}", err))
+    }
+
+    pub fn executable_writable(&mut self) -> ExecutableCode {
+        self.try_executable_writable()
+            .unwrap_or_else(|err| panic!("finalizing code: {}", err))
     }
 
-    pub fn executable_readable(&mut self) -> *const u8 {
-        self.buffer.executable_memory().unwrap()
+    pub fn executable_readable(&mut self) -> ExecutableCode {
+        self.try_executable_readable()
+            .unwrap_or_else(|err| panic!("finalizing code: {}", err))
     }
 
     pub fn code_size(&self) -> usize {
@@ -305,8 +518,8 @@ impl X86InsFormatter {
     ) {
         self.emit_rex_if(
             Self::byte_reg_requires_rex(reg)
-                || Self::byte_reg_requires_rex(base)
-                || Self::byte_reg_requires_rex(index),
+                || Self::reg_requires_rex(base)
+                || Self::reg_requires_rex(index),
             reg,
             index,
             base,
@@ -315,6 +528,22 @@ impl X86InsFormatter {
         self.memory_modrm_2(reg, base, index, scale, offset);
     }
 
+    pub fn one_byte_op8_4(&mut self, op: u8, reg: u8, base: u8, offset: i32) {
+        self.emit_rex_if(
+            Self::byte_reg_requires_rex(reg) || Self::reg_requires_rex(base),
+            reg,
+            0,
+            base,
+        );
+        self.buffer.put_byte(op);
+        self.memory_modrm_1(reg, base, offset);
+    }
+
+    pub fn one_byte_op8_5(&mut self, op: u8, reg: u8) {
+        self.emit_rex_if(Self::byte_reg_requires_rex(reg), 0, 0, reg);
+        self.buffer.put_byte(op + (reg & 7));
+    }
+
     pub fn two_byte_op8_1(&mut self, op: u8, reg: u8, rm: u8) {
         self.emit_rex_if(
             Self::byte_reg_requires_rex(reg) || Self::byte_reg_requires_rex(rm),
@@ -332,6 +561,104 @@ impl X86InsFormatter {
         self.register_modrm(g, rm);
     }
 
+    pub fn two_byte_op8_4(&mut self, op: u8, reg: u8, base: u8, offset: i32) {
+        self.emit_rex_if(
+            Self::byte_reg_requires_rex(reg) || Self::reg_requires_rex(base),
+            reg,
+            0,
+            base,
+        );
+        self.buffer.append(&[OP_2BYTE_ESCAPE, op]);
+        self.memory_modrm_1(reg, base, offset);
+    }
+
+    pub fn two_byte_op8_5(
+        &mut self,
+        op: u8,
+        reg: u8,
+        base: u8,
+        index: u8,
+        scale: i32,
+        offset: i32,
+    ) {
+        self.emit_rex_if(
+            Self::byte_reg_requires_rex(reg)
+                || Self::reg_requires_rex(base)
+                || Self::reg_requires_rex(index),
+            reg,
+            index,
+            base,
+        );
+        self.buffer.append(&[OP_2BYTE_ESCAPE, op]);
+        self.memory_modrm_2(reg, base, index, scale, offset);
+    }
+
+    /// `reg` is a full register and `rm` a byte register, as in `movzx`.
+    pub fn two_byte_op8_3(&mut self, op: u8, reg: u8, rm: u8) {
+        self.emit_rex_if(
+            Self::reg_requires_rex(reg) || Self::byte_reg_requires_rex(rm),
+            reg,
+            0,
+            rm,
+        );
+        self.buffer.append(&[OP_2BYTE_ESCAPE, op]);
+        self.register_modrm(reg, rm);
+    }
+
+    /// Emits a three-byte VEX prefix and `op`. `map` selects the opcode map
+    /// (1: 0F, 2: 0F38, 3: 0F3A) and `pp` the implied prefix (0: none,
+    /// 1: 66, 2: F3, 3: F2); `vvvv` is the extra register operand.
+    #[allow(clippy::too_many_arguments)]
+    fn vex(&mut self, map: u8, pp: u8, w: bool, reg: u8, vvvv: u8, index: u8, rm: u8, op: u8) {
+        let rxb = (!reg >> 3 & 1) << 7 | (!index >> 3 & 1) << 6 | (!rm >> 3 & 1) << 5;
+        self.buffer
+            .append(&[0xc4, rxb | map, (w as u8) << 7 | (!vvvv & 15) << 3 | pp, op]);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn vex_op_rr(&mut self, map: u8, pp: u8, w: bool, op: u8, reg: u8, vvvv: u8, rm: u8) {
+        self.vex(map, pp, w, reg, vvvv, 0, rm, op);
+        self.register_modrm(reg, rm);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn vex_op_mr(
+        &mut self,
+        map: u8,
+        pp: u8,
+        w: bool,
+        op: u8,
+        reg: u8,
+        vvvv: u8,
+        base: u8,
+        offset: i32,
+    ) {
+        self.vex(map, pp, w, reg, vvvv, 0, base, op);
+        self.memory_modrm_1(reg, base, offset);
+    }
+
+    pub fn two_byte_op_1(&mut self, op: u8) {
+        self.buffer.append(&[OP_2BYTE_ESCAPE, op]);
+    }
+
+    pub fn two_byte_op_3(&mut self, op: u8, reg: u8, base: u8, offset: i32) {
+        self.emit_rex_if_needed(reg, 0, base);
+        self.buffer.append(&[OP_2BYTE_ESCAPE, op]);
+        self.memory_modrm_1(reg, base, offset);
+    }
+
+    pub fn two_byte_op_4(&mut self, op: u8, reg: u8, base: u8, index: u8, scale: i32, offset: i32) {
+        self.emit_rex_if_needed(reg, index, base);
+        self.buffer.append(&[OP_2BYTE_ESCAPE, op]);
+        self.memory_modrm_2(reg, base, index, scale, offset);
+    }
+
+    pub fn two_byte_op_6(&mut self, op: u8, reg: u8, rm: u8) {
+        self.emit_rex_if_needed(reg, 0, rm);
+        self.buffer.append(&[OP_2BYTE_ESCAPE, op]);
+        self.register_modrm(reg, rm);
+    }
+
     cfg_if::cfg_if! {
         if #[cfg(target_arch="x86_64")]
         {
@@ -380,12 +707,28 @@ impl X86InsFormatter {
                 self.buffer.put_byte(op);
                 self.register_modrm(reg, rm);
             }
+
+            pub fn two_byte_op64_3(&mut self,op: u8,reg: u8,base: u8,offset: i32) {
+                self.emit_rexw(reg,0,base);
+                self.buffer.append(&[OP_2BYTE_ESCAPE, op]);
+                self.memory_modrm_1(reg, base, offset);
+            }
+
+            pub fn two_byte_op64_4(&mut self,op: u8,reg: u8,base: u8,index: u8,scale: i32,offset: i32) {
+                self.emit_rexw(reg,index,base);
+                self.buffer.append(&[OP_2BYTE_ESCAPE, op]);
+                self.memory_modrm_2(reg, base, index, scale, offset);
+            }
         } // x86assembler is included in build only on x86_32 and x86_64 so we do not need to check for other platforms
     }
 
     pub fn prefix(&mut self, x: u8) {
         self.buffer.put_byte(x);
     }
+    /// Makes the next read-modify-write instruction on memory atomic.
+    pub fn lock(&mut self) {
+        self.prefix(PRE_LOCK);
+    }
     pub fn one_byte_op_1(&mut self, op: u8) {
         self.buffer.put_byte(op);
     }
@@ -415,42 +758,77 @@ impl X86InsFormatter {
         self.memory_modrm_2(reg, base, index, scale, offset);
     }
 
+    pub fn one_byte_op_no_base(&mut self, op: u8, reg: u8, index: u8, scale: i32, offset: i32) {
+        self.emit_rex_if_needed(reg, index, 0);
+        self.buffer.put_byte(op);
+        self.memory_modrm_no_base(reg, index, scale, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn one_byte_op64_no_base(&mut self, op: u8, reg: u8, index: u8, scale: i32, offset: i32) {
+        self.emit_rexw(reg, index, 0);
+        self.buffer.put_byte(op);
+        self.memory_modrm_no_base(reg, index, scale, offset);
+    }
+
     pub fn one_byte_op_6(&mut self, op: u8, reg: u8, rm: u8) {
         self.emit_rex_if_needed(reg, 0, rm);
         self.buffer.put_byte(op);
         self.register_modrm(reg, rm);
     }
 
-    #[cfg(target_arch = "x86")]
-    pub fn one_byte_op_5(&mut self, _op: u8, _reg: u8, _address: usize) {
-        unimplemented!()
+    /// Absolute `[disp32]` addressing. On x86_64 `mod=00 rm=101` means
+    /// RIP-relative, so the SIB form without base and index is used and
+    /// `address` must be reachable as a sign-extended disp32.
+    pub fn one_byte_op_5(&mut self, op: u8, reg: u8, address: usize) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert!(
+                address as i64 == address as i32 as i64,
+                "absolute address out of disp32 range"
+            );
+            self.emit_rex_if_needed(reg, 0, 0);
+            self.buffer.put_byte(op);
+            self.put_modrm_sib(ModRmMode::NoDisp, reg, NO_BASE, NO_INDEX, 0);
+        }
+        #[cfg(target_arch = "x86")]
+        {
+            self.buffer.put_byte(op);
+            self.put_modrm(ModRmMode::NoDisp, reg, NO_BASE as _);
+        }
+        self.buffer.put_int(address as i32);
     }
 }
 
 pub const fn can_sign_extend(x: i32) -> bool {
-    return x == (x as i8) as i32;
+    x == (x as i8) as i32
 }
 
 macro_rules! opcodes {
     (1 $($i: ident = $e: expr),*) => {
-       $( const $i: u8 = $e;)*
+       $( pub(crate) const $i: u8 = $e;)*
     };
     (2 $($i: ident = $e: expr),*) => {
-        $( const $i: u16 = $e;)*
+        $( pub(crate) const $i: u16 = $e;)*
      };
 }
 
 opcodes! {1
+        OP_ADD_EbGb                     = 0x00,
         OP_ADD_EvGv                     = 0x01,
         OP_ADD_GvEv                     = 0x03,
+        OP_OR_EbGb                      = 0x08,
         OP_OR_EvGv                      = 0x09,
         OP_OR_GvEv                      = 0x0B,
         OP_2BYTE_ESCAPE                 = 0x0F,
+        OP_AND_EbGb                     = 0x20,
         OP_AND_EvGv                     = 0x21,
         OP_AND_GvEv                     = 0x23,
+        OP_SUB_EbGb                     = 0x28,
         OP_SUB_EvGv                     = 0x29,
         OP_SUB_GvEv                     = 0x2B,
         PRE_PREDICT_BRANCH_NOT_TAKEN    = 0x2E,
+        OP_XOR_EbGb                     = 0x30,
         OP_XOR_EvGv                     = 0x31,
         OP_XOR_GvEv                     = 0x33,
         OP_CMP_EvGv                     = 0x39,
@@ -458,19 +836,23 @@ opcodes! {1
         PRE_REX                         = 0x40,
         OP_PUSH_EAX                     = 0x50,
         OP_POP_EAX                      = 0x58,
+        OP_JCC_rel8                     = 0x70,
         OP_MOVSXD_GvEv                  = 0x63,
         PRE_OPERAND_SIZE                = 0x66,
         PRE_SSE_66                      = 0x66,
         OP_PUSH_Iz                      = 0x68,
         OP_IMUL_GvEvIz                  = 0x69,
+        OP_IMUL_GvEvIb                  = 0x6B,
         OP_GROUP1_EbIb                  = 0x80,
         OP_GROUP1_EvIz                  = 0x81,
         OP_GROUP1_EvIb                  = 0x83,
         OP_TEST_EbGb                    = 0x84,
         OP_TEST_EvGv                    = 0x85,
+        OP_XCHG_EbGb                    = 0x86,
         OP_XCHG_EvGv                    = 0x87,
         OP_MOV_EbGb                     = 0x88,
         OP_MOV_EvGv                     = 0x89,
+        OP_MOV_GbEb                     = 0x8A,
         OP_MOV_GvEv                     = 0x8B,
         OP_LEA                          = 0x8D,
         OP_GROUP1A_Ev                   = 0x8F,
@@ -478,6 +860,7 @@ opcodes! {1
         OP_CDQ                          = 0x99,
         OP_MOV_EAXOv                    = 0xA1,
         OP_MOV_OvEAX                    = 0xA3,
+        OP_MOV_ALIb                     = 0xB0,
         OP_MOV_EAXIv                    = 0xB8,
         OP_GROUP2_EvIb                  = 0xC1,
         OP_RET                          = 0xC3,
@@ -489,6 +872,9 @@ opcodes! {1
         OP_ESCAPE_DD                    = 0xDD,
         OP_CALL_rel32                   = 0xE8,
         OP_JMP_rel32                    = 0xE9,
+        OP_JMP_rel8                     = 0xEB,
+        PRE_LOCK                        = 0xF0,
+        PRE_REP                         = 0xF3,
         PRE_SSE_F2                      = 0xF2,
         PRE_SSE_F3                      = 0xF3,
         OP_HLT                          = 0xF4,
@@ -514,13 +900,22 @@ opcodes! {
         OP2_SUBSD_VsdWsd    = 0x5C,
         OP2_DIVSD_VsdWsd    = 0x5E,
         OP2_SQRTSD_VsdWsd   = 0x51,
+        OP2_CMOVCC          = 0x40,
+        OP2_ANDPD_VpdWpd    = 0x54,
         OP2_ANDNPD_VpdWpd   = 0x55,
         OP2_XORPD_VpdWpd    = 0x57,
         OP2_MOVD_VdEd       = 0x6E,
         OP2_MOVD_EdVd       = 0x7E,
         OP2_JCC_rel32       = 0x80,
         OP_SETCC            = 0x90,
+        OP2_GROUP15         = 0xAE,
         OP2_IMUL_GvEv       = 0xAF,
+        OP2_CMPXCHG_EbGb    = 0xB0,
+        OP2_CMPXCHG_EvGv    = 0xB1,
+        OP2_XADD_EbGb       = 0xC0,
+        OP2_XADD_EvGv       = 0xC1,
+        OP2_GROUP9          = 0xC7,
+        OP2_MULX            = 0xF6,
         OP2_MOVZX_GvEb      = 0xB6,
         OP2_MOVSX_GvEb      = 0xBE,
         OP2_MOVZX_GvEw      = 0xB7,
@@ -554,46 +949,258 @@ opcodes! {1
     GROUP3_OP_TEST = 0,
     GROUP3_OP_NOT  = 2,
     GROUP3_OP_NEG  = 3,
+    GROUP3_OP_MUL  = 4,
+    GROUP3_OP_IMUL = 5,
+    GROUP3_OP_DIV  = 6,
     GROUP3_OP_IDIV = 7,
 
     GROUP5_OP_CALLN = 2,
     GROUP5_OP_JMPN  = 4,
     GROUP5_OP_PUSH  = 6,
 
+    GROUP9_OP_CMPXCHG8B = 1,
+
     GROUP11_MOV = 0,
 
     GROUP14_OP_PSLLQ = 6,
     GROUP14_OP_PSRLQ = 2,
 
+    GROUP15_OP_LFENCE = 5,
+    GROUP15_OP_MFENCE = 6,
+    GROUP15_OP_SFENCE = 7,
+
     ESCAPE_DD_FSTP_doubleReal = 3
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum BranchKind {
+    Jmp,
+    Jcc(X86Condition),
+    Call,
+}
+
+/// A `jmp`/`jcc`/`call` recorded while branch relaxation is enabled. Calls
+/// are never shortened but still need relinking when code moves.
+#[derive(Copy, Clone, Debug)]
+struct JumpRecord {
+    start: u32,
+    from: AssemblerLabel,
+    to: AssemblerLabel,
+    kind: BranchKind,
+    short: bool,
+    relaxable: bool,
+}
+
+impl JumpRecord {
+    fn len(&self) -> u32 {
+        self.from.offset - self.start
+    }
+
+    fn is_pending_shrink(&self) -> bool {
+        self.short && self.len() > 2
+    }
+}
+
+/// Padding emitted by `align` while branch relaxation is enabled; it is
+/// recomputed when the code in front of it shrinks.
+#[derive(Copy, Clone, Debug)]
+struct AlignRecord {
+    start: u32,
+    end: u32,
+    alignment: u32,
+    fill: u8,
+}
+
+#[derive(Copy, Clone)]
+enum RelaxEdit {
+    Jump(usize),
+    Align(usize),
+}
+
+/// Defines `lock`-prefixed group 1 operations on memory: a register source
+/// and an immediate source form in 8, 16, 32 and 64 bit, for `[base + disp]`
+/// and, as `_index`, for `[base + index * scale + disp]` operands.
+macro_rules! lock_group1 {
+    ($($opb: ident, $op: ident, $group: ident =>
+        $rmb: ident, $imb: ident, $rmw: ident, $imw: ident,
+        $rm: ident, $im: ident, $rmq: ident, $imq: ident;
+        $rmb_i: ident, $imb_i: ident, $rmw_i: ident, $imw_i: ident,
+        $rm_i: ident, $im_i: ident, $rmq_i: ident, $imq_i: ident;)*) => {
+        $(
+            pub fn $rmb(&mut self, src: u8, offset: i32, base: u8) {
+                self.formatter.lock();
+                self.formatter.one_byte_op8_4($opb, src, base, offset);
+            }
+            pub fn $rmb_i(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+                self.formatter.lock();
+                self.formatter
+                    .one_byte_op8_3($opb, src, base, index, scale, offset);
+            }
+            pub fn $imb(&mut self, imm: i8, offset: i32, base: u8) {
+                self.formatter.lock();
+                self.formatter
+                    .one_byte_op_3(OP_GROUP1_EbIb, $group, base, offset);
+                self.formatter.imm8(imm);
+            }
+            pub fn $imb_i(&mut self, imm: i8, offset: i32, base: u8, index: u8, scale: i32) {
+                self.formatter.lock();
+                self.formatter
+                    .one_byte_op_4(OP_GROUP1_EbIb, $group, base, index, scale, offset);
+                self.formatter.imm8(imm);
+            }
+            pub fn $rmw(&mut self, src: u8, offset: i32, base: u8) {
+                self.formatter.prefix(PRE_OPERAND_SIZE);
+                self.$rm(src, offset, base);
+            }
+            pub fn $rmw_i(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+                self.formatter.prefix(PRE_OPERAND_SIZE);
+                self.$rm_i(src, offset, base, index, scale);
+            }
+            pub fn $imw(&mut self, imm: i16, offset: i32, base: u8) {
+                self.formatter.prefix(PRE_OPERAND_SIZE);
+                self.formatter.lock();
+                if can_sign_extend(imm as i32) {
+                    self.formatter
+                        .one_byte_op_3(OP_GROUP1_EvIb, $group, base, offset);
+                    self.formatter.imm8(imm as _);
+                } else {
+                    self.formatter
+                        .one_byte_op_3(OP_GROUP1_EvIz, $group, base, offset);
+                    self.formatter.imm16(imm);
+                }
+            }
+            pub fn $imw_i(&mut self, imm: i16, offset: i32, base: u8, index: u8, scale: i32) {
+                self.formatter.prefix(PRE_OPERAND_SIZE);
+                self.formatter.lock();
+                if can_sign_extend(imm as i32) {
+                    self.formatter
+                        .one_byte_op_4(OP_GROUP1_EvIb, $group, base, index, scale, offset);
+                    self.formatter.imm8(imm as _);
+                } else {
+                    self.formatter
+                        .one_byte_op_4(OP_GROUP1_EvIz, $group, base, index, scale, offset);
+                    self.formatter.imm16(imm);
+                }
+            }
+            pub fn $rm(&mut self, src: u8, offset: i32, base: u8) {
+                self.formatter.lock();
+                self.formatter.one_byte_op_3($op, src, base, offset);
+            }
+            pub fn $rm_i(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+                self.formatter.lock();
+                self.formatter
+                    .one_byte_op_4($op, src, base, index, scale, offset);
+            }
+            pub fn $im(&mut self, imm: i32, offset: i32, base: u8) {
+                self.formatter.lock();
+                if can_sign_extend(imm) {
+                    self.formatter
+                        .one_byte_op_3(OP_GROUP1_EvIb, $group, base, offset);
+                    self.formatter.imm8(imm as _);
+                } else {
+                    self.formatter
+                        .one_byte_op_3(OP_GROUP1_EvIz, $group, base, offset);
+                    self.formatter.imm32(imm);
+                }
+            }
+            pub fn $im_i(&mut self, imm: i32, offset: i32, base: u8, index: u8, scale: i32) {
+                self.formatter.lock();
+                if can_sign_extend(imm) {
+                    self.formatter
+                        .one_byte_op_4(OP_GROUP1_EvIb, $group, base, index, scale, offset);
+                    self.formatter.imm8(imm as _);
+                } else {
+                    self.formatter
+                        .one_byte_op_4(OP_GROUP1_EvIz, $group, base, index, scale, offset);
+                    self.formatter.imm32(imm);
+                }
+            }
+            #[cfg(target_arch = "x86_64")]
+            pub fn $rmq(&mut self, src: u8, offset: i32, base: u8) {
+                self.formatter.lock();
+                self.formatter.one_byte_op64_3($op, src, base, offset);
+            }
+            #[cfg(target_arch = "x86_64")]
+            pub fn $rmq_i(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+                self.formatter.lock();
+                self.formatter
+                    .one_byte_op64_4($op, src, base, index, scale, offset);
+            }
+            #[cfg(target_arch = "x86_64")]
+            pub fn $imq(&mut self, imm: i32, offset: i32, base: u8) {
+                self.formatter.lock();
+                if can_sign_extend(imm) {
+                    self.formatter
+                        .one_byte_op64_3(OP_GROUP1_EvIb, $group, base, offset);
+                    self.formatter.imm8(imm as _);
+                } else {
+                    self.formatter
+                        .one_byte_op64_3(OP_GROUP1_EvIz, $group, base, offset);
+                    self.formatter.imm32(imm);
+                }
+            }
+            #[cfg(target_arch = "x86_64")]
+            pub fn $imq_i(&mut self, imm: i32, offset: i32, base: u8, index: u8, scale: i32) {
+                self.formatter.lock();
+                if can_sign_extend(imm) {
+                    self.formatter
+                        .one_byte_op64_4(OP_GROUP1_EvIb, $group, base, index, scale, offset);
+                    self.formatter.imm8(imm as _);
+                } else {
+                    self.formatter
+                        .one_byte_op64_4(OP_GROUP1_EvIz, $group, base, index, scale, offset);
+                    self.formatter.imm32(imm);
+                }
+            }
+        )*
+    };
+}
+
 pub struct X86Assembler {
     pub formatter: X86InsFormatter,
     idx_of_last_watchpoint: i32,
     idx_of_tail_last_watchpoint: i32,
+    relax_branches: bool,
+    jumps: Vec<JumpRecord>,
+    jump_index: HashMap<u32, usize>,
+    alignments: Vec<AlignRecord>,
+    watchpoints: Vec<AssemblerLabel>,
+}
+
+/// Watchpoint sites are aligned so their 5 bytes never straddle an 8-byte
+/// word.
+pub const WATCHPOINT_ALIGNMENT: usize = 8;
+
+impl Default for X86Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl X86Assembler {
     pub fn new() -> Self {
         Self {
             formatter: X86InsFormatter {
-                buffer: AssemblerBuffer {
-                    storage: Vec::with_capacity(128),
-                    index: 0,
-                },
+                buffer: AssemblerBuffer::new(),
+                watchpoint_tail: 0,
+                rip_operands: None,
             },
             idx_of_last_watchpoint: 0,
             idx_of_tail_last_watchpoint: 0,
+            relax_branches: false,
+            jumps: vec![],
+            jump_index: HashMap::new(),
+            alignments: vec![],
+            watchpoints: vec![],
         }
     }
     pub fn code(&self) -> &[u8] {
-        &self.formatter.data()
+        self.formatter.data()
     }
     fn store_possibly_unaligned<T: Sized>(location: *mut u8, idx: i32, value: T) {
         unsafe {
             let ptr = (location.cast::<T>()).offset(idx as _);
-            std::ptr::copy_nonoverlapping(&value, ptr, std::mem::size_of::<T>());
+            ptr.write_unaligned(value);
         }
     }
 
@@ -612,137 +1219,327 @@ impl X86Assembler {
     }
 
     fn set_rel32(from: *mut u8, to: *mut u8) {
-        let offset = to as usize - from as usize;
+        let offset = (to as isize).wrapping_sub(from as isize);
+        assert!(offset == offset as i32 as isize);
         Self::set_i32(from, offset as _);
     }
 
     fn get_relocate_offset(code: *mut u8, lbl: AssemblerLabel) -> *mut u8 {
         assert!(lbl.is_set());
-        return (code as usize + lbl.offset as usize) as *mut u8;
+        (code as usize + lbl.offset as usize) as *mut u8
     }
 
-    fn replace_with_address_computation(mut ptr: *mut u8) {
-        unsafe {
-            #[cfg(target_arch = "x86_64")]
-            {
-                if (*ptr & !15) == PRE_REX {
-                    ptr = ptr.offset(1);
-                }
-            }
-            match *ptr {
-                OP_MOV_GvEv => *ptr = OP_LEA,
-                OP_LEA => (),
-                _ => unreachable!(),
-            }
-        }
+    fn link_ptr(code: *mut u8, w: AssemblerLabel, value: *mut u8) {
+        Self::set_ptr(unsafe { code.offset(w.offset as isize) }, value);
     }
-    fn replace_with_load(mut ptr: *mut u8) {
-        unsafe {
-            #[cfg(target_arch = "x86_64")]
-            {
-                if (*ptr & !15) == PRE_REX {
-                    ptr = ptr.offset(1);
-                }
-            }
-            match *ptr {
-                OP_MOV_GvEv => (),
-                OP_LEA => *ptr = OP_MOV_GvEv,
-                _ => unreachable!(),
-            }
-        }
+    fn slink_call(code: *mut u8, w: AssemblerLabel, value: *mut u8) {
+        Self::set_rel32(unsafe { code.offset(w.offset as isize) }, value);
     }
-    fn revert_jump_to_cmpl_im_force32(mut ptr: *mut u8, imm: i32, offset: i32, dst: u8) {
-        const OPCODE_BYTES: u8 = 1;
-        const MODRM_BYTES: u8 = 1;
-        unsafe {
-            *ptr.offset(0) = OP_GROUP1_EvIz;
-            *ptr.offset(1) = ((ModRmMode::NoDisp as u8) << 6) | (GROUP1_OP_CMP << 3) | dst;
-            let bytes: [u8; 4] = std::mem::transmute(imm);
-            for i in OPCODE_BYTES + MODRM_BYTES..5 {
-                *ptr.offset(i as isize) =
-                    bytes[i as usize - OPCODE_BYTES as usize - MODRM_BYTES as usize];
-            }
-        }
+    fn slink_jump(code: *mut u8, w: AssemblerLabel, value: *mut u8) {
+        Self::set_rel32(unsafe { code.offset(w.offset as isize) }, value);
     }
 
-    fn revert_jump_to_cmpl_ir_force32(mut ptr: *mut u8, imm: i32, dst: u8) {
-        const OPCODE_BYTES: u8 = 1;
-        const MODRM_BYTES: u8 = 1;
-        unsafe {
-            *ptr.offset(0) = OP_GROUP1_EvIz;
-            *ptr.offset(1) = ((ModRmMode::Reg as u8) << 6) | (GROUP1_OP_CMP << 3) | dst;
-            let bytes: [u8; 4] = std::mem::transmute(imm);
-            for i in OPCODE_BYTES + MODRM_BYTES..5 {
-                *ptr.offset(i as isize) =
-                    bytes[i as usize - OPCODE_BYTES as usize - MODRM_BYTES as usize];
+    /// Points the jump whose rel32 slot ends at `from` to `to`. Both labels
+    /// must lie inside this assembler's buffer; `to` may precede `from`.
+    pub fn link_jump(&mut self, from: AssemblerLabel, to: AssemblerLabel) {
+        assert!(from.is_set() && to.is_set());
+        assert!(from.offset as usize <= self.formatter.code_size());
+        assert!(to.offset as usize <= self.formatter.code_size());
+        debug_assert!(
+            self.watchpoints
+                .iter()
+                .all(|w| to.offset <= w.offset || to.offset >= w.offset + 5),
+            "jump into the patchable bytes of a watchpoint"
+        );
+        if let Some(&idx) = self.jump_index.get(&from.offset) {
+            self.jumps[idx].to = to;
+            if self.jumps[idx].short {
+                let disp = to.offset as i64 - from.offset as i64;
+                assert!(disp == disp as i8 as i64, "relaxed jump out of rel8 range");
+                self.formatter.data_mut()[from.offset as usize - 1] = disp as u8;
+                return;
             }
         }
+        let code = self.formatter.data_mut().as_mut_ptr();
+        Self::set_rel32(unsafe { code.offset(from.offset as _) }, unsafe {
+            code.offset(to.offset as _)
+        });
     }
 
-    fn revert_jump_to_movq_i64r(mut ptr: *mut u8, imm: i64, dst: u8) {
-        const REX_BYTES: u8 = 1;
-        const OPCODE_BYTES: u8 = 1;
-        unsafe {
-            *ptr.offset(0) = PRE_REX | (1 << 3) | (dst >> 3);
-            *ptr.offset(1) = OP_MOV_EAXIv | (dst & 7);
-            let bytes: [u8; 8] = std::mem::transmute(imm);
-            for i in REX_BYTES + OPCODE_BYTES..5 {
-                *ptr.offset(i as isize) =
-                    bytes[i as usize - REX_BYTES as usize - OPCODE_BYTES as usize];
-            }
-        }
+    /// Points a `call` emitted by this assembler at a label in the same buffer.
+    pub fn link_call(&mut self, from: AssemblerLabel, to: AssemblerLabel) {
+        self.link_jump(from, to);
     }
-    fn read_ptr(loc: *mut u8) -> *mut u8 {
-        unsafe { *loc.cast::<*mut u8>().offset(-1) }
+
+    /// Sets the immediate of a `movq_i64r_with_patch` when the code is placed.
+    pub fn link_data_ptr(&mut self, at: DataLabelPtr, value: *const u8) {
+        self.formatter.buffer.link_data_ptr(at, value as usize);
     }
-    fn replace_with_jump(mut ptr: *mut u8, to: *mut u8) {
-        unsafe {
-            let dist = (to as usize - (ptr as usize + 5));
-            *ptr.offset(0) = OP_JMP_rel32;
-            *ptr.offset(1).cast::<i32>() = dist as i32;
-        }
+
+    /// Sets the immediate to the final address of `target` in this buffer.
+    pub fn link_data_ptr_to_label(&mut self, at: DataLabelPtr, target: AssemblerLabel) {
+        self.formatter.buffer.link_data_ptr_to_label(at, target);
     }
 
-    fn repatch_ptr(location: *mut u8, value: *mut u8) {
-        Self::set_ptr(location, value)
+    pub fn link_data32(&mut self, at: DataLabel32, value: i32) {
+        self.formatter.buffer.link_data32(at, value);
     }
 
-    fn repatch_i32(location: *mut u8, value: i32) {
-        Self::set_i32(location, value)
+    pub fn link_data_compact(&mut self, at: DataLabelCompact, value: i8) {
+        self.formatter.buffer.link_data_compact(at, value);
     }
 
-    fn relink_jump(from: *mut u8, to: *mut u8) {
-        Self::set_rel32(from, to);
+    /// Names `label` in listings and dumps of the code.
+    pub fn name_label(&mut self, label: AssemblerLabel, name: &str) {
+        self.formatter.buffer.name_label(label, name);
     }
-    fn relink_call(from: *mut u8, to: *mut u8) {
-        Self::set_rel32(from, to);
+
+    /// Attaches `text` to the next instruction in listings.
+    pub fn comment(&mut self, text: &str) {
+        self.formatter.buffer.comment(text);
     }
 
-    fn link_ptr(code: *mut u8, w: AssemblerLabel, value: *mut u8) {
-        Self::set_ptr(unsafe { code.offset(w.offset as isize) }, value);
+    pub fn buffer(&self) -> &AssemblerBuffer {
+        &self.formatter.buffer
     }
-    fn link_call(code: *mut u8, w: AssemblerLabel, value: *mut u8) {
-        Self::set_ptr(unsafe { code.offset(w.offset as isize) }, value);
+
+    /// Finishes the code for writing it out with the `AssemblerBuffer`
+    /// serializers: constants are placed, external calls stay unresolved.
+    pub fn finish_for_output(&mut self) -> &AssemblerBuffer {
+        self.formatter.finish_object();
+        &self.formatter.buffer
     }
-    fn slink_jump(code: *mut u8, w: AssemblerLabel, value: *mut u8) {
-        Self::set_ptr(unsafe { code.offset(w.offset as isize) }, value);
+
+    /// When enabled, `jmp` and `jcc` are recorded so that `relax_branches` can
+    /// later shrink the ones whose displacement fits in an i8. Labels held by
+    /// the caller are not updated by that pass; see `relax_branches`.
+    pub fn set_branch_relaxation(&mut self, enabled: bool) {
+        self.relax_branches = enabled;
     }
 
-    fn link_jump(&mut self, from: AssemblerLabel, to: AssemblerLabel) {
-        let code = self.formatter.data_mut().as_mut_ptr();
-        Self::set_rel32(unsafe { code.offset(from.offset as _) }, unsafe {
-            code.offset(to.offset as _)
+    fn record_jump(&mut self, start: u32, from: AssemblerLabel, kind: BranchKind) {
+        if !self.relax_branches {
+            return;
+        }
+        // Code inside a watchpoint's 5 bytes must keep its size.
+        let relaxable =
+            kind != BranchKind::Call && start as i32 >= self.idx_of_tail_last_watchpoint;
+        self.jump_index.insert(from.offset, self.jumps.len());
+        self.jumps.push(JumpRecord {
+            start,
+            from,
+            to: AssemblerLabel::default(),
+            kind,
+            short: false,
+            relaxable,
         });
     }
 
-    pub fn align(&mut self, alignment: usize) -> AssemblerLabel {
-        while self.formatter.code_size() & (alignment - 1) == 0 {
-            self.formatter.one_byte_op_1(OP_HLT);
+    fn relax_edits(&self) -> Vec<(u32, RelaxEdit)> {
+        let mut edits = self
+            .jumps
+            .iter()
+            .enumerate()
+            .filter(|(_, j)| j.is_pending_shrink())
+            .map(|(i, j)| (j.start, RelaxEdit::Jump(i)))
+            .chain(
+                self.alignments
+                    .iter()
+                    .enumerate()
+                    .map(|(i, a)| (a.start, RelaxEdit::Align(i))),
+            )
+            .collect::<Vec<_>>();
+        // An alignment that adds no padding can start where a jump does; its
+        // padding goes before the jump.
+        edits.sort_by_key(|&(start, edit)| (start, matches!(edit, RelaxEdit::Jump(_))));
+        edits
+    }
+
+    fn relaxed_layout(&self) -> OffsetMap {
+        let mut map = OffsetMap::default();
+        let mut delta = 0i64;
+        for (_, edit) in self.relax_edits() {
+            match edit {
+                RelaxEdit::Jump(i) => {
+                    let j = &self.jumps[i];
+                    let d = 2 - j.len() as i64;
+                    delta += d;
+                    map.push(j.from.offset, d);
+                }
+                RelaxEdit::Align(i) => {
+                    let a = &self.alignments[i];
+                    let new_start = a.start as i64 + delta;
+                    let pad =
+                        (a.alignment as i64 - new_start % a.alignment as i64) % a.alignment as i64;
+                    let d = pad - (a.end - a.start) as i64;
+                    delta += d;
+                    map.push(a.end, d);
+                }
+            }
+        }
+        map
+    }
+
+    /// Upper bound on how much alignment padding between two offsets can grow.
+    fn alignment_slack(&self, a: u32, b: u32) -> i64 {
+        let (lo, hi) = if a < b { (a, b) } else { (b, a) };
+        self.alignments
+            .iter()
+            .filter(|r| r.start >= lo && r.start < hi)
+            .map(|r| r.alignment as i64 - 1)
+            .sum()
+    }
+
+    fn short_displacement(&self, map: &OffsetMap, j: &JumpRecord) -> i64 {
+        let start = map.map_offset(j.start) as i64;
+        let to = map.map_offset(j.to.offset) as i64;
+        if !j.short && j.to.offset >= j.from.offset {
+            // Shrinking this jump pulls a forward target in by the same amount.
+            to - start - j.len() as i64
+        } else {
+            to - (start + 2)
+        }
+    }
+
+    /// Rewrites every recorded jump whose displacement fits in an i8 into its
+    /// two-byte `EB`/`7x` form. Call once the jumps are linked.
+    ///
+    /// Labels recorded inside the assembler (jumps, relocations, constants,
+    /// watchpoints, names and comments) are remapped. Every other label taken
+    /// earlier from `label`, `jmp`, `jcc` or a data label emitter must be
+    /// translated with the returned map: an untranslated one may point into
+    /// the middle of an instruction and is not detected.
+    #[must_use = "labels taken before relaxation must be translated with the returned map"]
+    pub fn relax_branches(&mut self) -> OffsetMap {
+        let fits = |disp: i64, slack: i64| {
+            disp - slack >= i8::MIN as i64 && disp + slack <= i8::MAX as i64
+        };
+        loop {
+            let map = self.relaxed_layout();
+            let mut changed = false;
+            for i in 0..self.jumps.len() {
+                let j = self.jumps[i];
+                if j.short || !j.relaxable || !j.to.is_set() {
+                    continue;
+                }
+                let slack = self.alignment_slack(j.start, j.to.offset);
+                if fits(self.short_displacement(&map, &j), slack) {
+                    self.jumps[i].short = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        let map = loop {
+            let map = self.relaxed_layout();
+            let mut changed = false;
+            for i in 0..self.jumps.len() {
+                let j = self.jumps[i];
+                if j.is_pending_shrink() && !fits(self.short_displacement(&map, &j), 0) {
+                    self.jumps[i].short = false;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break map;
+            }
+        };
+        self.apply_relaxation(&map);
+        map
+    }
+
+    fn apply_relaxation(&mut self, map: &OffsetMap) {
+        let edits = self.relax_edits();
+        let old = self.formatter.data().to_vec();
+        let mut out = Vec::with_capacity(old.len());
+        let mut cursor = 0usize;
+        for (start, edit) in edits {
+            out.extend_from_slice(&old[cursor..start as usize]);
+            match edit {
+                RelaxEdit::Jump(i) => {
+                    let j = &self.jumps[i];
+                    out.push(match j.kind {
+                        BranchKind::Jcc(cond) => OP_JCC_rel8 + cond as u8,
+                        BranchKind::Jmp => OP_JMP_rel8,
+                        BranchKind::Call => unreachable!(),
+                    });
+                    out.push(0);
+                    cursor = j.from.offset as usize;
+                }
+                RelaxEdit::Align(i) => {
+                    let a = &self.alignments[i];
+                    while out.len() % a.alignment as usize != 0 {
+                        out.push(a.fill);
+                    }
+                    cursor = a.end as usize;
+                }
+            }
         }
+        out.extend_from_slice(&old[cursor..]);
+        self.formatter.buffer.replace_storage(out);
+        self.formatter.buffer.remap(map);
 
-        return self.label();
+        for j in self.jumps.iter_mut() {
+            if j.is_pending_shrink() {
+                j.start = map.map_offset(j.start);
+                j.from = AssemblerLabel::new(j.start + 2);
+            } else {
+                j.start = map.map_offset(j.start);
+                j.from = map.map_label(j.from);
+            }
+            j.to = map.map_label(j.to);
+        }
+        for a in self.alignments.iter_mut() {
+            a.start = map.map_offset(a.start);
+            a.end = map.map_offset(a.end);
+        }
+        self.jump_index = self
+            .jumps
+            .iter()
+            .enumerate()
+            .map(|(i, j)| (j.from.offset, i))
+            .collect();
+        self.idx_of_last_watchpoint = map.map_offset(self.idx_of_last_watchpoint as u32) as i32;
+        self.idx_of_tail_last_watchpoint =
+            map.map_offset(self.idx_of_tail_last_watchpoint as u32) as i32;
+        self.formatter.watchpoint_tail =
+            map.map_offset(self.formatter.watchpoint_tail as u32) as usize;
+        for w in self.watchpoints.iter_mut() {
+            *w = map.map_label(*w);
+        }
+
+        for i in 0..self.jumps.len() {
+            let j = self.jumps[i];
+            if j.to.is_set() {
+                self.link_jump(j.from, j.to);
+            }
+        }
+    }
+
+    pub fn align(&mut self, alignment: usize) -> AssemblerLabel {
+        self.pad_to(alignment, OP_HLT);
+        self.label()
+    }
+
+    fn pad_to(&mut self, alignment: usize, fill: u8) {
+        let start = self.formatter.code_size() as u32;
+        while self.formatter.code_size() & (alignment - 1) != 0 {
+            self.formatter.one_byte_op_1(fill);
+        }
+        if self.relax_branches {
+            self.alignments.push(AlignRecord {
+                start,
+                end: self.formatter.code_size() as u32,
+                alignment: alignment as u32,
+                fill,
+            });
+        }
     }
 
+    /// Label of the current offset. With branch relaxation enabled it has to
+    /// be translated with the map returned by `relax_branches`.
     pub fn label(&mut self) -> AssemblerLabel {
         let mut r = self.formatter.label();
         while (r.offset as i32) < self.idx_of_tail_last_watchpoint {
@@ -756,14 +1553,92 @@ impl X86Assembler {
         self.formatter.label()
     }
 
+    /// Marks a site that can later be overwritten with a 5-byte `jmp` by
+    /// `CodePatcher::replace_with_jump`, e.g. to invalidate the code and
+    /// divert into an OSR exit. Until then execution falls through it.
+    ///
+    /// The site is 8-byte aligned (with NOPs) so that the jump is written with
+    /// a single store, and `label()` pads so that no label lands inside the
+    /// 5 bytes. A site at the very end of the code is padded on finalisation.
     pub fn label_for_watchpoit(&mut self) -> AssemblerLabel {
         let mut result = self.formatter.label();
-        if result.offset as i32 != self.idx_of_last_watchpoint {
-            result = self.label();
+        if result.offset as i32 != self.idx_of_last_watchpoint || self.watchpoints.is_empty() {
+            self.label();
+            self.pad_to(WATCHPOINT_ALIGNMENT, OP_NOP);
+            result = self.formatter.label();
+            self.watchpoints.push(result);
         }
         self.idx_of_last_watchpoint = result.offset as _;
         self.idx_of_tail_last_watchpoint = result.offset as i32 + 5;
-        return result;
+        self.formatter.watchpoint_tail = self.idx_of_tail_last_watchpoint as usize;
+        result
+    }
+
+    /// Pools `bytes` aligned to `alignment`, see `AssemblerBuffer::add_constant`.
+    pub fn constant(&mut self, bytes: &[u8], alignment: usize) -> Constant {
+        self.formatter.buffer.add_constant(bytes, alignment)
+    }
+
+    pub fn constant_f64(&mut self, value: f64) -> Constant {
+        self.constant(&value.to_ne_bytes(), 8)
+    }
+
+    /// A 16-byte aligned constant, suitable for packed SSE operands.
+    pub fn constant_u128(&mut self, value: u128) -> Constant {
+        self.constant(&value.to_ne_bytes(), 16)
+    }
+
+    /// Emits one instruction whose memory operand refers to `constant`
+    /// RIP-relative, see `rip_relative`. For example a sign flip is
+    /// `asm.with_constant(mask, |asm, offset, base| asm.xorpd_mr(offset, base, dst))`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_constant(&mut self, constant: Constant, emit: impl FnOnce(&mut Self, i32, u8)) {
+        self.rip_relative(PcRelTarget::Constant(constant), emit);
+    }
+
+    /// Emits one instruction whose memory operand is `[rip + disp32]`
+    /// addressing `target`. `emit` receives the `(offset, base)` pair to pass
+    /// to any base-addressed memory form, `base` being the `RIP` pseudo
+    /// register; an offset other than 0 is added to the target address.
+    /// Immediates emitted by `emit` after the operand are accounted for.
+    /// `emit` must use exactly one such operand.
+    ///
+    /// Constants and labels are resolved when the code is finalized, absolute
+    /// addresses when it is placed, which fails with `ERANGE` if the address
+    /// is more than 2GB away from the code.
+    #[cfg(target_arch = "x86_64")]
+    pub fn rip_relative(&mut self, target: PcRelTarget, emit: impl FnOnce(&mut Self, i32, u8)) {
+        /// Closes the operand count even if `emit` panics.
+        struct Scope<'a>(&'a mut X86Assembler);
+        impl Drop for Scope<'_> {
+            fn drop(&mut self) {
+                self.0.formatter.rip_operands = None;
+            }
+        }
+
+        assert!(
+            self.formatter.rip_operands.is_none(),
+            "nested RIP-relative operands"
+        );
+        self.formatter.rip_operands = Some((0, AssemblerLabel::default()));
+        let scope = Scope(self);
+        emit(scope.0, 0, RIP);
+        let (count, disp) = scope.0.formatter.rip_operands.unwrap();
+        drop(scope);
+        assert_eq!(count, 1, "expected exactly one RIP-relative operand");
+
+        let end = disp.offset as usize;
+        let mut addend = [0; 4];
+        addend.copy_from_slice(&self.formatter.data()[end - 4..end]);
+        let insn_end = self.formatter.label();
+        self.formatter
+            .buffer
+            .add_pc_rel_fixup(disp, insn_end, target, i32::from_ne_bytes(addend));
+    }
+
+    /// Every site returned by `label_for_watchpoit`, in emission order.
+    pub fn watchpoints(&self) -> &[AssemblerLabel] {
+        &self.watchpoints
     }
 
     pub fn ret(&mut self) {
@@ -777,6 +1652,180 @@ impl X86Assembler {
         self.formatter.prefix(PRE_PREDICT_BRANCH_NOT_TAKEN);
     }
 
+    /// Emits `jmp rel32` and returns the label of the end of its displacement,
+    /// to be passed to `link_jump`. With branch relaxation enabled the label
+    /// is only valid until `relax_branches`, which returns the map to
+    /// translate it.
+    pub fn jmp(&mut self) -> AssemblerLabel {
+        let start = self.formatter.label();
+        self.formatter.one_byte_op_1(OP_JMP_rel32);
+        let from = self.formatter.imm_rel();
+        self.record_jump(start.offset, from, BranchKind::Jmp);
+        from
+    }
+
+    pub fn jmp_r(&mut self, dst: u8) {
+        self.formatter
+            .one_byte_op_6(OP_GROUP5_Ev, GROUP5_OP_JMPN, dst);
+    }
+
+    pub fn jmp_m(&mut self, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op_3(OP_GROUP5_Ev, GROUP5_OP_JMPN, base, offset);
+    }
+
+    pub fn jmp_m_index(&mut self, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter
+            .one_byte_op_4(OP_GROUP5_Ev, GROUP5_OP_JMPN, base, index, scale, offset);
+    }
+
+    /// Emits `jcc rel32`; see `jmp` for the returned label.
+    pub fn jcc(&mut self, cond: X86Condition) -> AssemblerLabel {
+        let start = self.formatter.label();
+        self.formatter.two_byte_op_1(OP2_JCC_rel32 + cond as u8);
+        let from = self.formatter.imm_rel();
+        self.record_jump(start.offset, from, BranchKind::Jcc(cond));
+        from
+    }
+
+    /// Sets the low byte of `dst` to 1 if `cond` holds, else to 0.
+    pub fn setcc_r(&mut self, cond: X86Condition, dst: u8) {
+        self.formatter.two_byte_op8_2(OP_SETCC + cond as u8, 0, dst);
+    }
+
+    pub fn setcc_m(&mut self, cond: X86Condition, offset: i32, base: u8) {
+        self.formatter
+            .two_byte_op_3(OP_SETCC + cond as u8, 0, base, offset);
+    }
+
+    pub fn setcc_m_index(
+        &mut self,
+        cond: X86Condition,
+        offset: i32,
+        base: u8,
+        index: u8,
+        scale: i32,
+    ) {
+        self.formatter
+            .two_byte_op_4(OP_SETCC + cond as u8, 0, base, index, scale, offset);
+    }
+
+    pub fn cmovw_rr(&mut self, cond: X86Condition, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.cmovl_rr(cond, src, dst);
+    }
+
+    pub fn cmovw_mr(&mut self, cond: X86Condition, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.cmovl_mr(cond, offset, base, dst);
+    }
+
+    pub fn cmovw_mr_index(
+        &mut self,
+        cond: X86Condition,
+        offset: i32,
+        base: u8,
+        index: u8,
+        scale: i32,
+        dst: u8,
+    ) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.cmovl_mr_index(cond, offset, base, index, scale, dst);
+    }
+
+    pub fn cmovl_rr(&mut self, cond: X86Condition, src: u8, dst: u8) {
+        self.formatter
+            .two_byte_op_6(OP2_CMOVCC + cond as u8, dst, src);
+    }
+
+    pub fn cmovl_mr(&mut self, cond: X86Condition, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op_3(OP2_CMOVCC + cond as u8, dst, base, offset);
+    }
+
+    pub fn cmovl_mr_index(
+        &mut self,
+        cond: X86Condition,
+        offset: i32,
+        base: u8,
+        index: u8,
+        scale: i32,
+        dst: u8,
+    ) {
+        self.formatter
+            .two_byte_op_4(OP2_CMOVCC + cond as u8, dst, base, index, scale, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn cmovq_rr(&mut self, cond: X86Condition, src: u8, dst: u8) {
+        self.formatter
+            .two_byte_op64(OP2_CMOVCC + cond as u8, dst, src);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn cmovq_mr(&mut self, cond: X86Condition, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op64_3(OP2_CMOVCC + cond as u8, dst, base, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn cmovq_mr_index(
+        &mut self,
+        cond: X86Condition,
+        offset: i32,
+        base: u8,
+        index: u8,
+        scale: i32,
+        dst: u8,
+    ) {
+        self.formatter
+            .two_byte_op64_4(OP2_CMOVCC + cond as u8, dst, base, index, scale, offset);
+    }
+
+    /// Emits `call rel32` to a label in this buffer; link it with `link_call`.
+    pub fn call(&mut self) -> AssemblerLabel {
+        let start = self.formatter.label();
+        self.formatter.one_byte_op_1(OP_CALL_rel32);
+        let from = self.formatter.imm_rel();
+        self.record_jump(start.offset, from, BranchKind::Call);
+        from
+    }
+
+    pub fn call_r(&mut self, dst: u8) {
+        self.formatter
+            .one_byte_op_6(OP_GROUP5_Ev, GROUP5_OP_CALLN, dst);
+    }
+
+    pub fn call_m(&mut self, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op_3(OP_GROUP5_Ev, GROUP5_OP_CALLN, base, offset);
+    }
+
+    /// Emits `call rel32` to an absolute address. The displacement is filled in
+    /// once the code is placed; targets out of rel32 range go through a
+    /// trampoline appended to the code.
+    ///
+    /// Each trampoline costs 14 bytes per distinct target. Code placed in an
+    /// `ExecutableAllocator` only gets them for targets out of range of the
+    /// whole pool; code placed in its own mapping, whose address is not known
+    /// when the code is finalized, gets one for every target.
+    pub fn call_external(&mut self, target: *const u8) -> AssemblerLabel {
+        self.formatter.one_byte_op_1(OP_CALL_rel32);
+        let from = self.formatter.imm_rel();
+        self.formatter
+            .buffer
+            .add_external_call(from, target as usize);
+        from
+    }
+
+    /// Calls an absolute address through `r11`, which is clobbered. Always
+    /// reaches, at the cost of 13 bytes.
+    #[cfg(target_arch = "x86_64")]
+    pub fn call_far(&mut self, target: *const u8) {
+        self.movq_i64r(target as i64, X86Gpr::R11 as _);
+        self.call_r(X86Gpr::R11 as _);
+    }
+
     pub fn push_r(&mut self, r: u8) {
         self.formatter.one_byte_op_2(OP_PUSH_EAX, r);
     }
@@ -803,11 +1852,11 @@ impl X86Assembler {
         if can_sign_extend(imm) {
             self.formatter
                 .one_byte_op_5(OP_GROUP1_EvIb, GROUP1_OP_ADC, addr as _);
-            self.formatter.imm8(imm as u8);
+            self.formatter.imm8(imm as i8);
         } else {
             self.formatter
                 .one_byte_op_5(OP_GROUP1_EvIz, GROUP1_OP_ADC, addr as _);
-            self.formatter.imm32(imm as u8);
+            self.formatter.imm32(imm);
         }
     }
 
@@ -848,7 +1897,7 @@ impl X86Assembler {
                 self.formatter.imm8(imm as _);
             } else {
                 self.formatter.one_byte_op64_2(OP_GROUP1_EvIz,GROUP1_OP_ADD,dst);
-                self.formatter.imm8(imm as _);
+                self.formatter.imm32(imm);
             }
         }
 
@@ -894,6 +1943,7 @@ impl X86Assembler {
         } else {
             self.formatter
                 .one_byte_op_3(OP_GROUP1_EvIz, GROUP1_OP_AND, base, offset);
+            self.formatter.imm32(imm);
         }
     }
     cfg_if::cfg_if! {
@@ -931,9 +1981,14 @@ impl X86Assembler {
         self.formatter.imm32(imm);
     }
     #[cfg(target_arch = "x86_64")]
-    pub fn oqr_rr(&mut self, src: u8, dst: u8) {
+    pub fn orq_rr(&mut self, src: u8, dst: u8) {
         self.formatter.one_byte_op64_2(OP_OR_EvGv, src, dst);
     }
+    #[cfg(target_arch = "x86_64")]
+    #[deprecated(note = "renamed to `orq_rr`")]
+    pub fn oqr_rr(&mut self, src: u8, dst: u8) {
+        self.orq_rr(src, dst);
+    }
 
     #[cfg(target_arch = "x86_64")]
     pub fn orq_ir(&mut self, imm: i32, dst: u8) {
@@ -954,7 +2009,7 @@ impl X86Assembler {
         } else {
             self.formatter
                 .one_byte_op_6(OP_GROUP1_EvIz, GROUP1_OP_SUB, dst);
-            self.formatter.imm8(imm as _);
+            self.formatter.imm32(imm);
         }
     }
 
@@ -975,9 +2030,15 @@ impl X86Assembler {
     }
     #[cfg(target_arch = "x86_64")]
     pub fn subq_ir(&mut self, imm: i32, dst: u8) {
-        self.formatter
-            .one_byte_op64_2(OP_GROUP1_EvIz, GROUP1_OP_ADD, dst);
-        self.formatter.imm32(imm);
+        if can_sign_extend(imm) {
+            self.formatter
+                .one_byte_op64_2(OP_GROUP1_EvIb, GROUP1_OP_SUB, dst);
+            self.formatter.imm8(imm as _);
+        } else {
+            self.formatter
+                .one_byte_op64_2(OP_GROUP1_EvIz, GROUP1_OP_SUB, dst);
+            self.formatter.imm32(imm);
+        }
     }
 
     pub fn xorl_rr(&mut self, src: u8, dst: u8) {
@@ -1006,7 +2067,7 @@ impl X86Assembler {
             }
             pub fn xorq_ir(&mut self,imm: i32,dst: u8) {
                 if can_sign_extend(imm) {
-                    self.formatter.one_byte_op64_2(OP_GROUP1_EbIb, GROUP1_OP_XOR,dst);
+                    self.formatter.one_byte_op64_2(OP_GROUP1_EvIb, GROUP1_OP_XOR,dst);
                     self.formatter.imm8(imm as _);
                 } else {
                     self.formatter.one_byte_op64_2(OP_GROUP1_EvIz, GROUP1_OP_XOR,dst);
@@ -1024,7 +2085,7 @@ impl X86Assembler {
             }
 
             pub fn sarq_clr(&mut self,dst: u8) {
-                self.formatter.one_byte_op64_2(OP_GROUP2_EvCL,GROUP2_OP_ROR,dst);
+                self.formatter.one_byte_op64_2(OP_GROUP2_EvCL,GROUP2_OP_SAR,dst);
             }
 
             pub fn sarq_i8r(&mut self,imm: i8,dst: u8)
@@ -1109,49 +2170,2311 @@ impl X86Assembler {
     }
 
     pub fn imull_rr(&mut self, src: u8, dst: u8) {
-        self.formatter.two_byte_op8_1(OP2_IMUL_GvEv, dst, src);
+        self.formatter.two_byte_op_6(OP2_IMUL_GvEv, dst, src);
     }
     pub fn imull_mr(&mut self, offset: i32, base: u8, dst: u8) {
         self.formatter
-            .one_byte_op_3(OP2_IMUL_GvEv, dst, base, offset);
+            .two_byte_op_3(OP2_IMUL_GvEv, dst, base, offset);
+    }
+    pub fn imull_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op_4(OP2_IMUL_GvEv, dst, base, index, scale, offset);
+    }
+    pub fn imull_i8r(&mut self, src: u8, imm: i8, dst: u8) {
+        self.formatter.one_byte_op_6(OP_IMUL_GvEvIb, dst, src);
+        self.formatter.imm8(imm);
     }
     pub fn imull_i32r(&mut self, src: u8, imm: i32, dst: u8) {
         self.formatter.one_byte_op_6(OP_IMUL_GvEvIz, dst, src);
         self.formatter.imm32(imm);
     }
-
-    pub fn idivl_r(&mut self, dst: u8) {
+    pub fn imull_i8mr(&mut self, offset: i32, base: u8, imm: i8, dst: u8) {
         self.formatter
-            .one_byte_op_6(OP_GROUP3_Ev, GROUP3_OP_IDIV, dst);
+            .one_byte_op_3(OP_IMUL_GvEvIb, dst, base, offset);
+        self.formatter.imm8(imm);
     }
-
-    pub fn cmpl_rr(&mut self, src: u8, dst: u8) {
-        self.formatter.one_byte_op_6(OP_CMP_EvGv, src, dst);
+    pub fn imull_i32mr(&mut self, offset: i32, base: u8, imm: i32, dst: u8) {
+        self.formatter
+            .one_byte_op_3(OP_IMUL_GvEvIz, dst, base, offset);
+        self.formatter.imm32(imm);
+    }
+    /// `dst *= imm`, with the shortest immediate.
+    pub fn imull_ir(&mut self, imm: i32, dst: u8) {
+        if can_sign_extend(imm) {
+            self.imull_i8r(dst, imm as i8, dst);
+        } else {
+            self.imull_i32r(dst, imm, dst);
+        }
     }
 
-    pub fn cmpl_rm(&mut self, src: u8, offset: i32, base: u8) {
-        self.formatter.one_byte_op_3(OP_CMP_EvGv, src, base, offset);
+    #[cfg(target_arch = "x86_64")]
+    pub fn imulq_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.two_byte_op64(OP2_IMUL_GvEv, dst, src);
     }
-    pub fn cmpl_mr(&mut self, offset: i32, base: u8, dst: u8) {
-        self.formatter.one_byte_op_3(OP_CMP_GvEv, dst, base, offset);
+    #[cfg(target_arch = "x86_64")]
+    pub fn imulq_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op64_3(OP2_IMUL_GvEv, dst, base, offset);
     }
-    pub fn cmpl_ir(&mut self, imm: i32, dst: u8, force_i32: bool) {
-        if can_sign_extend(imm) && !force_i32 {
-            self.formatter
-                .one_byte_op_6(OP_GROUP1_EvIb, GROUP1_OP_CMP, dst);
-            self.formatter.imm8(imm as _);
+    #[cfg(target_arch = "x86_64")]
+    pub fn imulq_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op64_4(OP2_IMUL_GvEv, dst, base, index, scale, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn imulq_i8r(&mut self, src: u8, imm: i8, dst: u8) {
+        self.formatter.one_byte_op64_2(OP_IMUL_GvEvIb, dst, src);
+        self.formatter.imm8(imm);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn imulq_i32r(&mut self, src: u8, imm: i32, dst: u8) {
+        self.formatter.one_byte_op64_2(OP_IMUL_GvEvIz, dst, src);
+        self.formatter.imm32(imm);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn imulq_i8mr(&mut self, offset: i32, base: u8, imm: i8, dst: u8) {
+        self.formatter
+            .one_byte_op64_3(OP_IMUL_GvEvIb, dst, base, offset);
+        self.formatter.imm8(imm);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn imulq_i32mr(&mut self, offset: i32, base: u8, imm: i32, dst: u8) {
+        self.formatter
+            .one_byte_op64_3(OP_IMUL_GvEvIz, dst, base, offset);
+        self.formatter.imm32(imm);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn imulq_ir(&mut self, imm: i32, dst: u8) {
+        if can_sign_extend(imm) {
+            self.imulq_i8r(dst, imm as i8, dst);
         } else {
-            self.formatter
-                .one_byte_op_6(OP_GROUP1_EvIz, GROUP1_OP_CMP, dst);
-            self.formatter.imm32(imm as _);
+            self.imulq_i32r(dst, imm, dst);
         }
     }
+
+    /// Signed `edx:eax = eax * src`.
+    pub fn imull_r(&mut self, src: u8) {
+        self.formatter
+            .one_byte_op_6(OP_GROUP3_Ev, GROUP3_OP_IMUL, src);
+    }
     #[cfg(target_arch = "x86_64")]
-    pub fn movq_rr(&mut self, src: u8, dst: u8) {
-        self.formatter.one_byte_op64_2(OP_MOV_EvGv, src, dst);
+    pub fn imulq_r(&mut self, src: u8) {
+        self.formatter
+            .one_byte_op64_2(OP_GROUP3_Ev, GROUP3_OP_IMUL, src);
+    }
+
+    /// Unsigned `edx:eax = eax * src`.
+    pub fn mull_r(&mut self, src: u8) {
+        self.formatter
+            .one_byte_op_6(OP_GROUP3_Ev, GROUP3_OP_MUL, src);
+    }
+    pub fn mull_m(&mut self, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op_3(OP_GROUP3_Ev, GROUP3_OP_MUL, base, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn mulq_r(&mut self, src: u8) {
+        self.formatter
+            .one_byte_op64_2(OP_GROUP3_Ev, GROUP3_OP_MUL, src);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn mulq_m(&mut self, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op64_3(OP_GROUP3_Ev, GROUP3_OP_MUL, base, offset);
+    }
+
+    /// BMI2 `mulx`: unsigned `hi:lo = edx * src` without touching the flags.
+    pub fn mulxl_rr(&mut self, src: u8, lo: u8, hi: u8) {
+        self.formatter.vex_op_rr(2, 3, false, OP2_MULX, hi, lo, src);
+    }
+    pub fn mulxl_mr(&mut self, offset: i32, base: u8, lo: u8, hi: u8) {
+        self.formatter
+            .vex_op_mr(2, 3, false, OP2_MULX, hi, lo, base, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn mulxq_rr(&mut self, src: u8, lo: u8, hi: u8) {
+        self.formatter.vex_op_rr(2, 3, true, OP2_MULX, hi, lo, src);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn mulxq_mr(&mut self, offset: i32, base: u8, lo: u8, hi: u8) {
+        self.formatter
+            .vex_op_mr(2, 3, true, OP2_MULX, hi, lo, base, offset);
+    }
+
+    /// Sign-extends `eax` into `edx:eax`.
+    pub fn cdq(&mut self) {
+        self.formatter.one_byte_op_1(OP_CDQ);
+    }
+    /// Sign-extends `rax` into `rdx:rax`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn cqo(&mut self) {
+        self.formatter.one_byte_op64(OP_CDQ);
+    }
+
+    /// Unsigned `edx:eax / dst`, quotient in `eax`, remainder in `edx`.
+    pub fn divl_r(&mut self, dst: u8) {
+        self.formatter
+            .one_byte_op_6(OP_GROUP3_Ev, GROUP3_OP_DIV, dst);
+    }
+    pub fn divl_m(&mut self, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op_3(OP_GROUP3_Ev, GROUP3_OP_DIV, base, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn divq_r(&mut self, dst: u8) {
+        self.formatter
+            .one_byte_op64_2(OP_GROUP3_Ev, GROUP3_OP_DIV, dst);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn divq_m(&mut self, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op64_3(OP_GROUP3_Ev, GROUP3_OP_DIV, base, offset);
+    }
+
+    pub fn idivl_r(&mut self, dst: u8) {
+        self.formatter
+            .one_byte_op_6(OP_GROUP3_Ev, GROUP3_OP_IDIV, dst);
+    }
+    pub fn idivl_m(&mut self, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op_3(OP_GROUP3_Ev, GROUP3_OP_IDIV, base, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn idivq_r(&mut self, dst: u8) {
+        self.formatter
+            .one_byte_op64_2(OP_GROUP3_Ev, GROUP3_OP_IDIV, dst);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn idivq_m(&mut self, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op64_3(OP_GROUP3_Ev, GROUP3_OP_IDIV, base, offset);
+    }
+
+    /// Divides `dividend` by `divisor` into `quotient` and/or `remainder`,
+    /// moving operands in and out of `eax`/`edx` as `div`/`idiv` require.
+    /// `eax` and `edx` are clobbered; `scratch` is used if the divisor lives
+    /// in one of them or the results have to be swapped.
+    pub fn div_reml(
+        &mut self,
+        signed: bool,
+        dividend: u8,
+        divisor: u8,
+        quotient: Option<u8>,
+        remainder: Option<u8>,
+        scratch: u8,
+    ) {
+        self.div_rem(
+            false, signed, dividend, divisor, quotient, remainder, scratch,
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn div_remq(
+        &mut self,
+        signed: bool,
+        dividend: u8,
+        divisor: u8,
+        quotient: Option<u8>,
+        remainder: Option<u8>,
+        scratch: u8,
+    ) {
+        self.div_rem(
+            true, signed, dividend, divisor, quotient, remainder, scratch,
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn div_rem(
+        &mut self,
+        wide: bool,
+        signed: bool,
+        dividend: u8,
+        divisor: u8,
+        quotient: Option<u8>,
+        remainder: Option<u8>,
+        scratch: u8,
+    ) {
+        let eax = X86Gpr::Eax as u8;
+        let edx = X86Gpr::Edx as u8;
+        let mov = |asm: &mut Self, src: u8, dst: u8| {
+            if src == dst {
+                return;
+            }
+            #[cfg(target_arch = "x86_64")]
+            {
+                if wide {
+                    return asm.movq_rr(src, dst);
+                }
+            }
+            asm.movl_rr(src, dst);
+        };
+        let mut divisor = divisor;
+        if divisor == eax || divisor == edx {
+            assert!(scratch != eax && scratch != edx && scratch != dividend);
+            mov(self, divisor, scratch);
+            divisor = scratch;
+        }
+        mov(self, dividend, eax);
+        match (signed, wide) {
+            #[cfg(target_arch = "x86_64")]
+            (true, true) => self.cqo(),
+            (true, _) => self.cdq(),
+            (false, _) => self.xorl_rr(edx, edx),
+        }
+        match (signed, wide) {
+            #[cfg(target_arch = "x86_64")]
+            (true, true) => self.idivq_r(divisor),
+            #[cfg(target_arch = "x86_64")]
+            (false, true) => self.divq_r(divisor),
+            (true, _) => self.idivl_r(divisor),
+            (false, _) => self.divl_r(divisor),
+        }
+        match (quotient, remainder) {
+            (Some(q), Some(r)) if q == edx && r == eax => {
+                assert!(scratch != eax && scratch != edx);
+                mov(self, eax, scratch);
+                mov(self, edx, eax);
+                mov(self, scratch, edx);
+            }
+            // Read the remainder before the quotient overwrites edx.
+            (Some(q), r) if q == edx => {
+                if let Some(r) = r {
+                    mov(self, edx, r);
+                }
+                mov(self, eax, q);
+            }
+            (q, r) => {
+                if let Some(q) = q {
+                    mov(self, eax, q);
+                }
+                if let Some(r) = r {
+                    mov(self, edx, r);
+                }
+            }
+        }
+    }
+
+    lock_group1! {
+        OP_ADD_EbGb, OP_ADD_EvGv, GROUP1_OP_ADD =>
+            lock_addb_rm, lock_addb_im, lock_addw_rm, lock_addw_im,
+            lock_addl_rm, lock_addl_im, lock_addq_rm, lock_addq_im;
+            lock_addb_rm_index, lock_addb_im_index, lock_addw_rm_index, lock_addw_im_index,
+            lock_addl_rm_index, lock_addl_im_index, lock_addq_rm_index, lock_addq_im_index;
+        OP_SUB_EbGb, OP_SUB_EvGv, GROUP1_OP_SUB =>
+            lock_subb_rm, lock_subb_im, lock_subw_rm, lock_subw_im,
+            lock_subl_rm, lock_subl_im, lock_subq_rm, lock_subq_im;
+            lock_subb_rm_index, lock_subb_im_index, lock_subw_rm_index, lock_subw_im_index,
+            lock_subl_rm_index, lock_subl_im_index, lock_subq_rm_index, lock_subq_im_index;
+        OP_AND_EbGb, OP_AND_EvGv, GROUP1_OP_AND =>
+            lock_andb_rm, lock_andb_im, lock_andw_rm, lock_andw_im,
+            lock_andl_rm, lock_andl_im, lock_andq_rm, lock_andq_im;
+            lock_andb_rm_index, lock_andb_im_index, lock_andw_rm_index, lock_andw_im_index,
+            lock_andl_rm_index, lock_andl_im_index, lock_andq_rm_index, lock_andq_im_index;
+        OP_OR_EbGb, OP_OR_EvGv, GROUP1_OP_OR =>
+            lock_orb_rm, lock_orb_im, lock_orw_rm, lock_orw_im,
+            lock_orl_rm, lock_orl_im, lock_orq_rm, lock_orq_im;
+            lock_orb_rm_index, lock_orb_im_index, lock_orw_rm_index, lock_orw_im_index,
+            lock_orl_rm_index, lock_orl_im_index, lock_orq_rm_index, lock_orq_im_index;
+        OP_XOR_EbGb, OP_XOR_EvGv, GROUP1_OP_XOR =>
+            lock_xorb_rm, lock_xorb_im, lock_xorw_rm, lock_xorw_im,
+            lock_xorl_rm, lock_xorl_im, lock_xorq_rm, lock_xorq_im;
+            lock_xorb_rm_index, lock_xorb_im_index, lock_xorw_rm_index, lock_xorw_im_index,
+            lock_xorl_rm_index, lock_xorl_im_index, lock_xorq_rm_index, lock_xorq_im_index;
+    }
+
+    /// Exchanges `src` with memory. `xchg` with a memory operand is always
+    /// atomic, so no `lock` prefix is needed.
+    pub fn xchgb_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op8_4(OP_XCHG_EbGb, src, base, offset);
+    }
+    pub fn xchgb_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter
+            .one_byte_op8_3(OP_XCHG_EbGb, src, base, index, scale, offset);
+    }
+    pub fn xchgw_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.xchgl_rm(src, offset, base);
+    }
+    pub fn xchgw_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.xchgl_rm_index(src, offset, base, index, scale);
+    }
+    pub fn xchgl_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op_3(OP_XCHG_EvGv, src, base, offset);
+    }
+    pub fn xchgl_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter
+            .one_byte_op_4(OP_XCHG_EvGv, src, base, index, scale, offset);
+    }
+    pub fn xchgl_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.one_byte_op_6(OP_XCHG_EvGv, src, dst);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn xchgq_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op64_3(OP_XCHG_EvGv, src, base, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn xchgq_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter
+            .one_byte_op64_4(OP_XCHG_EvGv, src, base, index, scale, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn xchgq_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.one_byte_op64_2(OP_XCHG_EvGv, src, dst);
+    }
+
+    /// Atomically adds `src` to memory, leaving the old value in `src`.
+    pub fn lock_xaddb_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op8_4(OP2_XADD_EbGb, src, base, offset);
+    }
+    pub fn lock_xaddb_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op8_5(OP2_XADD_EbGb, src, base, index, scale, offset);
+    }
+    pub fn lock_xaddw_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.lock_xaddl_rm(src, offset, base);
+    }
+    pub fn lock_xaddw_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.lock_xaddl_rm_index(src, offset, base, index, scale);
+    }
+    pub fn lock_xaddl_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op_3(OP2_XADD_EvGv, src, base, offset);
+    }
+    pub fn lock_xaddl_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op_4(OP2_XADD_EvGv, src, base, index, scale, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn lock_xaddq_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op64_3(OP2_XADD_EvGv, src, base, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn lock_xaddq_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op64_4(OP2_XADD_EvGv, src, base, index, scale, offset);
+    }
+
+    /// Atomically stores `src` to memory if it equals `al`/`ax`/`eax`/`rax`,
+    /// setting ZF. Either way the accumulator ends up holding the old value.
+    pub fn lock_cmpxchgb_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op8_4(OP2_CMPXCHG_EbGb, src, base, offset);
+    }
+    pub fn lock_cmpxchgb_rm_index(
+        &mut self,
+        src: u8,
+        offset: i32,
+        base: u8,
+        index: u8,
+        scale: i32,
+    ) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op8_5(OP2_CMPXCHG_EbGb, src, base, index, scale, offset);
+    }
+    pub fn lock_cmpxchgw_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.lock_cmpxchgl_rm(src, offset, base);
+    }
+    pub fn lock_cmpxchgw_rm_index(
+        &mut self,
+        src: u8,
+        offset: i32,
+        base: u8,
+        index: u8,
+        scale: i32,
+    ) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.lock_cmpxchgl_rm_index(src, offset, base, index, scale);
+    }
+    pub fn lock_cmpxchgl_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op_3(OP2_CMPXCHG_EvGv, src, base, offset);
+    }
+    pub fn lock_cmpxchgl_rm_index(
+        &mut self,
+        src: u8,
+        offset: i32,
+        base: u8,
+        index: u8,
+        scale: i32,
+    ) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op_4(OP2_CMPXCHG_EvGv, src, base, index, scale, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn lock_cmpxchgq_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op64_3(OP2_CMPXCHG_EvGv, src, base, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn lock_cmpxchgq_rm_index(
+        &mut self,
+        src: u8,
+        offset: i32,
+        base: u8,
+        index: u8,
+        scale: i32,
+    ) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op64_4(OP2_CMPXCHG_EvGv, src, base, index, scale, offset);
+    }
+
+    /// Compares `edx:eax` with 8 bytes of memory and stores `ecx:ebx` if they
+    /// are equal, otherwise loads the memory into `edx:eax`.
+    pub fn lock_cmpxchg8b_m(&mut self, offset: i32, base: u8) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op_3(OP2_GROUP9, GROUP9_OP_CMPXCHG8B, base, offset);
+    }
+    pub fn lock_cmpxchg8b_m_index(&mut self, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op_4(OP2_GROUP9, GROUP9_OP_CMPXCHG8B, base, index, scale, offset);
+    }
+    /// The 16 byte form of `lock_cmpxchg8b_m` on `rdx:rax` and `rcx:rbx`. The
+    /// memory operand must be 16 byte aligned.
+    #[cfg(target_arch = "x86_64")]
+    pub fn lock_cmpxchg16b_m(&mut self, offset: i32, base: u8) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op64_3(OP2_GROUP9, GROUP9_OP_CMPXCHG8B, base, offset);
+    }
+    #[cfg(target_arch = "x86_64")]
+    pub fn lock_cmpxchg16b_m_index(&mut self, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.lock();
+        self.formatter
+            .two_byte_op64_4(OP2_GROUP9, GROUP9_OP_CMPXCHG8B, base, index, scale, offset);
+    }
+
+    pub fn mfence(&mut self) {
+        self.formatter
+            .two_byte_op_6(OP2_GROUP15, GROUP15_OP_MFENCE, 0);
+    }
+    pub fn lfence(&mut self) {
+        self.formatter
+            .two_byte_op_6(OP2_GROUP15, GROUP15_OP_LFENCE, 0);
+    }
+    pub fn sfence(&mut self) {
+        self.formatter
+            .two_byte_op_6(OP2_GROUP15, GROUP15_OP_SFENCE, 0);
+    }
+    /// Spin-wait loop hint.
+    pub fn pause(&mut self) {
+        self.formatter.prefix(PRE_REP);
+        self.formatter.one_byte_op_1(OP_NOP);
+    }
+
+    pub fn cmpl_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.one_byte_op_6(OP_CMP_EvGv, src, dst);
+    }
+
+    pub fn cmpl_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.one_byte_op_3(OP_CMP_EvGv, src, base, offset);
+    }
+    pub fn cmpl_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.one_byte_op_3(OP_CMP_GvEv, dst, base, offset);
+    }
+    pub fn cmpl_ir(&mut self, imm: i32, dst: u8, force_i32: bool) {
+        if can_sign_extend(imm) && !force_i32 {
+            self.formatter
+                .one_byte_op_6(OP_GROUP1_EvIb, GROUP1_OP_CMP, dst);
+            self.formatter.imm8(imm as _);
+        } else {
+            self.formatter
+                .one_byte_op_6(OP_GROUP1_EvIz, GROUP1_OP_CMP, dst);
+            self.formatter.imm32(imm as _);
+        }
+    }
+
+    /// `cmpl_ir` with a forced imm32 that can be linked or repatched.
+    pub fn cmpl_ir_with_patch(&mut self, imm: i32, dst: u8) -> DataLabel32 {
+        self.cmpl_ir(imm, dst, true);
+        DataLabel32::new(self.formatter.label())
+    }
+
+    /// `cmpl $imm32, offset(base)` with a patchable imm32.
+    pub fn cmpl_im_with_patch(&mut self, imm: i32, offset: i32, base: u8) -> DataLabel32 {
+        self.formatter
+            .one_byte_op_3(OP_GROUP1_EvIz, GROUP1_OP_CMP, base, offset);
+        self.formatter.imm32(imm);
+        DataLabel32::new(self.formatter.label())
+    }
+
+    /// Loads with an always-32-bit displacement that can be repatched.
+    pub fn movl_mr_disp32(&mut self, offset: i32, base: u8, dst: u8) -> DataLabel32 {
+        self.formatter
+            .one_byte_op_disp32(OP_MOV_GvEv, dst, base, offset);
+        DataLabel32::new(self.formatter.label())
+    }
+
+    pub fn movl_rm_disp32(&mut self, src: u8, offset: i32, base: u8) -> DataLabel32 {
+        self.formatter
+            .one_byte_op_disp32(OP_MOV_EvGv, src, base, offset);
+        DataLabel32::new(self.formatter.label())
+    }
+
+    /// Loads with an always-8-bit displacement that can be repatched.
+    pub fn movl_mr_disp8(&mut self, offset: i32, base: u8, dst: u8) -> DataLabelCompact {
+        assert!(can_sign_extend(offset));
+        self.formatter
+            .one_byte_op_disp8(OP_MOV_GvEv, dst, base, offset);
+        DataLabelCompact::new(self.formatter.label())
+    }
+
+    /// Always a 10-byte `movabs`, so the pointer can be linked or repatched.
+    #[cfg(target_arch = "x86_64")]
+    pub fn movq_i64r_with_patch(&mut self, imm: i64, dst: u8) -> DataLabelPtr {
+        self.formatter.one_byte_op64_1(OP_MOV_EAXIv, dst);
+        self.formatter.imm64(imm);
+        DataLabelPtr::new(self.formatter.label())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn cmpq_ir_with_patch(&mut self, imm: i32, dst: u8) -> DataLabel32 {
+        self.formatter
+            .one_byte_op64_2(OP_GROUP1_EvIz, GROUP1_OP_CMP, dst);
+        self.formatter.imm32(imm);
+        DataLabel32::new(self.formatter.label())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movq_mr_disp32(&mut self, offset: i32, base: u8, dst: u8) -> DataLabel32 {
+        self.formatter
+            .one_byte_op64_disp32(OP_MOV_GvEv, dst, base, offset);
+        DataLabel32::new(self.formatter.label())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movq_rm_disp32(&mut self, src: u8, offset: i32, base: u8) -> DataLabel32 {
+        self.formatter
+            .one_byte_op64_disp32(OP_MOV_EvGv, src, base, offset);
+        DataLabel32::new(self.formatter.label())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movq_mr_disp8(&mut self, offset: i32, base: u8, dst: u8) -> DataLabelCompact {
+        assert!(can_sign_extend(offset));
+        self.formatter
+            .one_byte_op64_disp8(OP_MOV_GvEv, dst, base, offset);
+        DataLabelCompact::new(self.formatter.label())
+    }
+
+    /// Zero-extending byte load; also clears the upper half of a 64-bit `dst`.
+    pub fn movzbl_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.two_byte_op8_3(OP2_MOVZX_GvEb, dst, src);
+    }
+
+    pub fn movzbl_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op_3(OP2_MOVZX_GvEb, dst, base, offset);
+    }
+
+    pub fn movzbl_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op_4(OP2_MOVZX_GvEb, dst, base, index, scale, offset);
+    }
+
+    pub fn movzwl_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.two_byte_op_6(OP2_MOVZX_GvEw, dst, src);
+    }
+
+    pub fn movzwl_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op_3(OP2_MOVZX_GvEw, dst, base, offset);
+    }
+
+    pub fn movzwl_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op_4(OP2_MOVZX_GvEw, dst, base, index, scale, offset);
+    }
+
+    pub fn movsbl_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.two_byte_op8_3(OP2_MOVSX_GvEb, dst, src);
+    }
+
+    pub fn movsbl_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op_3(OP2_MOVSX_GvEb, dst, base, offset);
+    }
+
+    pub fn movsbl_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op_4(OP2_MOVSX_GvEb, dst, base, index, scale, offset);
+    }
+
+    pub fn movswl_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.two_byte_op_6(OP2_MOVSX_GvEw, dst, src);
+    }
+
+    pub fn movswl_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op_3(OP2_MOVSX_GvEw, dst, base, offset);
+    }
+
+    pub fn movswl_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op_4(OP2_MOVSX_GvEw, dst, base, index, scale, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movzbq_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.two_byte_op64(OP2_MOVZX_GvEb, dst, src);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movzbq_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op64_3(OP2_MOVZX_GvEb, dst, base, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movzbq_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op64_4(OP2_MOVZX_GvEb, dst, base, index, scale, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movzwq_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.two_byte_op64(OP2_MOVZX_GvEw, dst, src);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movzwq_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op64_3(OP2_MOVZX_GvEw, dst, base, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movzwq_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op64_4(OP2_MOVZX_GvEw, dst, base, index, scale, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movsbq_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.two_byte_op64(OP2_MOVSX_GvEb, dst, src);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movsbq_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op64_3(OP2_MOVSX_GvEb, dst, base, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movsbq_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op64_4(OP2_MOVSX_GvEb, dst, base, index, scale, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movswq_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.two_byte_op64(OP2_MOVSX_GvEw, dst, src);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movswq_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .two_byte_op64_3(OP2_MOVSX_GvEw, dst, base, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movswq_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .two_byte_op64_4(OP2_MOVSX_GvEw, dst, base, index, scale, offset);
+    }
+
+    /// `movsxd`: sign-extends a 32-bit value into a 64-bit register.
+    #[cfg(target_arch = "x86_64")]
+    pub fn movslq_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.one_byte_op64_2(OP_MOVSXD_GvEv, dst, src);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movslq_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .one_byte_op64_3(OP_MOVSXD_GvEv, dst, base, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn movslq_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .one_byte_op64_4(OP_MOVSXD_GvEv, dst, base, index, scale, offset);
+    }
+
+    pub fn leal_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.one_byte_op_3(OP_LEA, dst, base, offset);
+    }
+
+    pub fn leal_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .one_byte_op_4(OP_LEA, dst, base, index, scale, offset);
+    }
+
+    /// `lea offset(, index, 1 << scale), dst`, which has no base register.
+    pub fn leal_mr_no_base(&mut self, offset: i32, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .one_byte_op_no_base(OP_LEA, dst, index, scale, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn leaq_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.one_byte_op64_3(OP_LEA, dst, base, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn leaq_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .one_byte_op64_4(OP_LEA, dst, base, index, scale, offset);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn leaq_mr_no_base(&mut self, offset: i32, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .one_byte_op64_no_base(OP_LEA, dst, index, scale, offset);
+    }
+
+    /// Loads the address of `target`, see `rip_relative`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn leaq_rip(&mut self, target: PcRelTarget, dst: u8) {
+        self.rip_relative(target, |asm, offset, base| asm.leaq_mr(offset, base, dst));
+    }
+
+    /// `dst = a + b` in one instruction that leaves the flags alone.
+    pub fn add3l(&mut self, a: u8, b: u8, dst: u8) {
+        let (base, index) = Self::lea_operands(a, b);
+        self.leal_mr_index(0, base, index, 0, dst);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn add3q(&mut self, a: u8, b: u8, dst: u8) {
+        let (base, index) = Self::lea_operands(a, b);
+        self.leaq_mr_index(0, base, index, 0, dst);
+    }
+
+    /// `dst = src * factor` for a factor of 3, 5 or 9 as a single
+    /// `lea (src, src, factor - 1)`.
+    pub fn scale_by_constl(&mut self, factor: i32, src: u8, dst: u8) {
+        self.leal_mr_index(0, src, src, Self::lea_scale(factor, src), dst);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn scale_by_constq(&mut self, factor: i32, src: u8, dst: u8) {
+        self.leaq_mr_index(0, src, src, Self::lea_scale(factor, src), dst);
+    }
+
+    /// Orders the operands of an addition so that the stack pointer, which
+    /// cannot be an index, is the base.
+    fn lea_operands(a: u8, b: u8) -> (u8, u8) {
+        if b == X86Gpr::Esp as u8 {
+            assert!(a != b, "cannot add the stack pointer to itself with lea");
+            (b, a)
+        } else {
+            (a, b)
+        }
+    }
+
+    fn lea_scale(factor: i32, src: u8) -> i32 {
+        assert!(
+            src != X86Gpr::Esp as u8,
+            "the stack pointer cannot be scaled"
+        );
+        match factor {
+            3 => 1,
+            5 => 2,
+            9 => 3,
+            _ => panic!("lea cannot multiply by {}", factor),
+        }
+    }
+
+    pub fn movb_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.one_byte_op8_2(OP_MOV_EbGb, src, dst);
+    }
+    pub fn movb_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op8_4(OP_MOV_EbGb, src, base, offset);
+    }
+    pub fn movb_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter
+            .one_byte_op8_3(OP_MOV_EbGb, src, base, index, scale, offset);
+    }
+    pub fn movb_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter
+            .one_byte_op8_4(OP_MOV_GbEb, dst, base, offset);
+    }
+    pub fn movb_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .one_byte_op8_3(OP_MOV_GbEb, dst, base, index, scale, offset);
+    }
+    pub fn movb_i8r(&mut self, imm: i8, dst: u8) {
+        self.formatter.one_byte_op8_5(OP_MOV_ALIb, dst);
+        self.formatter.imm8(imm);
+    }
+    pub fn movb_i8m(&mut self, imm: i8, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op_3(OP_GROUP11_EvIb, GROUP11_MOV, base, offset);
+        self.formatter.imm8(imm);
+    }
+    pub fn movb_i8m_index(&mut self, imm: i8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter
+            .one_byte_op_4(OP_GROUP11_EvIb, GROUP11_MOV, base, index, scale, offset);
+        self.formatter.imm8(imm);
+    }
+
+    pub fn movw_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.formatter.one_byte_op_6(OP_MOV_EvGv, src, dst);
+    }
+    pub fn movw_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.formatter.one_byte_op_3(OP_MOV_EvGv, src, base, offset);
+    }
+    pub fn movw_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.formatter
+            .one_byte_op_4(OP_MOV_EvGv, src, base, index, scale, offset);
+    }
+    pub fn movw_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.formatter.one_byte_op_3(OP_MOV_GvEv, dst, base, offset);
+    }
+    pub fn movw_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.formatter
+            .one_byte_op_4(OP_MOV_GvEv, dst, base, index, scale, offset);
+    }
+    pub fn movw_i16r(&mut self, imm: i16, dst: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.formatter.one_byte_op_2(OP_MOV_EAXIv, dst);
+        self.formatter.imm16(imm);
+    }
+    pub fn movw_i16m(&mut self, imm: i16, offset: i32, base: u8) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.formatter
+            .one_byte_op_3(OP_GROUP11_EvIz, GROUP11_MOV, base, offset);
+        self.formatter.imm16(imm);
+    }
+    pub fn movw_i16m_index(&mut self, imm: i16, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.prefix(PRE_OPERAND_SIZE);
+        self.formatter
+            .one_byte_op_4(OP_GROUP11_EvIz, GROUP11_MOV, base, index, scale, offset);
+        self.formatter.imm16(imm);
+    }
+
+    pub fn movl_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.one_byte_op_6(OP_MOV_EvGv, src, dst);
+    }
+    pub fn movl_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.one_byte_op_3(OP_MOV_EvGv, src, base, offset);
+    }
+    pub fn movl_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter
+            .one_byte_op_4(OP_MOV_EvGv, src, base, index, scale, offset);
+    }
+    pub fn movl_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.one_byte_op_3(OP_MOV_GvEv, dst, base, offset);
+    }
+    pub fn movl_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter
+            .one_byte_op_4(OP_MOV_GvEv, dst, base, index, scale, offset);
+    }
+    pub fn movl_i32r(&mut self, imm: i32, dst: u8) {
+        self.formatter.one_byte_op_2(OP_MOV_EAXIv, dst);
+        self.formatter.imm32(imm);
+    }
+    pub fn movl_i32m(&mut self, imm: i32, offset: i32, base: u8) {
+        self.formatter
+            .one_byte_op_3(OP_GROUP11_EvIz, GROUP11_MOV, base, offset);
+        self.formatter.imm32(imm);
+    }
+    pub fn movl_i32m_index(&mut self, imm: i32, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter
+            .one_byte_op_4(OP_GROUP11_EvIz, GROUP11_MOV, base, index, scale, offset);
+        self.formatter.imm32(imm);
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch="x86_64")] {
+            pub fn movq_rr(&mut self, src: u8, dst: u8) {
+                self.formatter.one_byte_op64_2(OP_MOV_EvGv, src, dst);
+            }
+            pub fn movq_rm(&mut self,src: u8,offset: i32,base: u8) {
+                self.formatter.one_byte_op64_3(OP_MOV_EvGv,src,base,offset);
+            }
+            pub fn movq_rm_index(&mut self,src: u8,offset: i32,base: u8,index: u8,scale: i32) {
+                self.formatter.one_byte_op64_4(OP_MOV_EvGv,src,base,index,scale,offset);
+            }
+            pub fn movq_mr(&mut self,offset: i32,base: u8,dst: u8) {
+                self.formatter.one_byte_op64_3(OP_MOV_GvEv,dst,base,offset);
+            }
+            pub fn movq_mr_index(&mut self,offset: i32,base: u8,index: u8,scale: i32,dst: u8) {
+                self.formatter.one_byte_op64_4(OP_MOV_GvEv,dst,base,index,scale,offset);
+            }
+            /// Picks the shortest encoding: a zero-extending `movl` when the value fits
+            /// in 32 unsigned bits, a sign-extended imm32 when it fits in i32, `movabs` otherwise.
+            pub fn movq_i64r(&mut self,imm: i64,dst: u8) {
+                if imm as u32 as i64 == imm {
+                    self.movl_i32r(imm as i32,dst);
+                } else if imm as i32 as i64 == imm {
+                    self.formatter.one_byte_op64_2(OP_GROUP11_EvIz,GROUP11_MOV,dst);
+                    self.formatter.imm32(imm as _);
+                } else {
+                    self.formatter.one_byte_op64_1(OP_MOV_EAXIv,dst);
+                    self.formatter.imm64(imm);
+                }
+            }
+            pub fn movq_i32m(&mut self,imm: i32,offset: i32,base: u8) {
+                self.formatter.one_byte_op64_3(OP_GROUP11_EvIz,GROUP11_MOV,base,offset);
+                self.formatter.imm32(imm);
+            }
+            pub fn movq_i32m_index(&mut self,imm: i32,offset: i32,base: u8,index: u8,scale: i32) {
+                self.formatter.one_byte_op64_4(OP_GROUP11_EvIz,GROUP11_MOV,base,index,scale,offset);
+                self.formatter.imm32(imm);
+            }
+        }
+    }
+
+    pub fn addsd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter.two_byte_op_6(OP2_ADDSD_VsdWsd, dst, src);
+    }
+    pub fn addsd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_3(OP2_ADDSD_VsdWsd, dst, base, offset);
+    }
+    pub fn subsd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter.two_byte_op_6(OP2_SUBSD_VsdWsd, dst, src);
+    }
+    pub fn subsd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_3(OP2_SUBSD_VsdWsd, dst, base, offset);
+    }
+    pub fn mulsd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter.two_byte_op_6(OP2_MULSD_VsdWsd, dst, src);
+    }
+    pub fn mulsd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_3(OP2_MULSD_VsdWsd, dst, base, offset);
+    }
+    pub fn divsd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter.two_byte_op_6(OP2_DIVSD_VsdWsd, dst, src);
+    }
+    pub fn divsd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_3(OP2_DIVSD_VsdWsd, dst, base, offset);
+    }
+    pub fn sqrtsd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter.two_byte_op_6(OP2_SQRTSD_VsdWsd, dst, src);
+    }
+    pub fn sqrtsd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_3(OP2_SQRTSD_VsdWsd, dst, base, offset);
+    }
+    pub fn ucomisd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter.two_byte_op_6(OP2_UCOMISD_VsdWsd, dst, src);
+    }
+    pub fn ucomisd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter
+            .two_byte_op_3(OP2_UCOMISD_VsdWsd, dst, base, offset);
+    }
+
+    pub fn movsd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter.two_byte_op_6(OP2_MOVSD_VsdWsd, dst, src);
+    }
+    pub fn movsd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_3(OP2_MOVSD_VsdWsd, dst, base, offset);
+    }
+    pub fn movsd_mr_index(&mut self, offset: i32, base: u8, index: u8, scale: i32, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_4(OP2_MOVSD_VsdWsd, dst, base, index, scale, offset);
+    }
+    pub fn movsd_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_3(OP2_MOVSD_WsdVsd, src, base, offset);
+    }
+    pub fn movsd_rm_index(&mut self, src: u8, offset: i32, base: u8, index: u8, scale: i32) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_4(OP2_MOVSD_WsdVsd, src, base, index, scale, offset);
+    }
+    pub fn movss_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F3);
+        self.formatter
+            .two_byte_op_3(OP2_MOVSS_VsdWsd, dst, base, offset);
+    }
+    pub fn movss_rm(&mut self, src: u8, offset: i32, base: u8) {
+        self.formatter.prefix(PRE_SSE_F3);
+        self.formatter
+            .two_byte_op_3(OP2_MOVSS_WsdVsd, src, base, offset);
+    }
+
+    /// `src` is a general purpose register, `dst` an XMM register.
+    pub fn cvtsi2sd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter.two_byte_op_6(OP2_CVTSI2SD_VsdEd, dst, src);
+    }
+    pub fn cvtsi2sd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter
+            .two_byte_op_3(OP2_CVTSI2SD_VsdEd, dst, base, offset);
+    }
+    /// `src` is an XMM register, `dst` a general purpose register.
+    pub fn cvttsd2si_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter.two_byte_op_6(OP2_CVTTSD2SI_GdWsd, dst, src);
+    }
+    pub fn cvtsd2ss_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F2);
+        self.formatter.two_byte_op_6(OP2_CVTSD2SS_VsdWsd, dst, src);
+    }
+    pub fn cvtss2sd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_F3);
+        self.formatter.two_byte_op_6(OP2_CVTSS2SD_VsdWsd, dst, src);
+    }
+
+    pub fn xorpd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter.two_byte_op_6(OP2_XORPD_VpdWpd, dst, src);
+    }
+    /// The memory operand of the packed `xorpd`/`andpd`/`andnpd` forms must be
+    /// 16-byte aligned.
+    pub fn xorpd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter
+            .two_byte_op_3(OP2_XORPD_VpdWpd, dst, base, offset);
+    }
+    pub fn andpd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter.two_byte_op_6(OP2_ANDPD_VpdWpd, dst, src);
+    }
+    pub fn andpd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter
+            .two_byte_op_3(OP2_ANDPD_VpdWpd, dst, base, offset);
+    }
+    pub fn andnpd_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter.two_byte_op_6(OP2_ANDNPD_VpdWpd, dst, src);
+    }
+    pub fn andnpd_mr(&mut self, offset: i32, base: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter
+            .two_byte_op_3(OP2_ANDNPD_VpdWpd, dst, base, offset);
+    }
+    pub fn por_rr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter.two_byte_op_6(OP2_POR_VdqWdq, dst, src);
+    }
+    pub fn psllq_i8r(&mut self, imm: i8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter
+            .two_byte_op_6(OP2_PSLLQ_UdqIb, GROUP14_OP_PSLLQ, dst);
+        self.formatter.imm8(imm);
+    }
+    pub fn psrlq_i8r(&mut self, imm: i8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter
+            .two_byte_op_6(OP2_PSRLQ_UdqIb, GROUP14_OP_PSRLQ, dst);
+        self.formatter.imm8(imm);
+    }
+    /// `pextrw $imm, src, dst`: `src` is an XMM register, `dst` a general purpose register.
+    pub fn pextrw_irr(&mut self, imm: i8, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter.two_byte_op_6(OP2_PEXTRW_GdUdIb, dst, src);
+        self.formatter.imm8(imm);
+    }
+
+    /// Moves the low 32 bits of XMM `src` into GPR `dst`.
+    pub fn movd_xr(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter.two_byte_op_6(OP2_MOVD_EdVd, src, dst);
+    }
+    /// Moves GPR `src` into the low 32 bits of XMM `dst`, zeroing the rest.
+    pub fn movd_rx(&mut self, src: u8, dst: u8) {
+        self.formatter.prefix(PRE_SSE_66);
+        self.formatter.two_byte_op_6(OP2_MOVD_VdEd, dst, src);
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch="x86_64")] {
+            pub fn cvtsi2sdq_rr(&mut self,src: u8,dst: u8) {
+                self.formatter.prefix(PRE_SSE_F2);
+                self.formatter.two_byte_op64(OP2_CVTSI2SD_VsdEd,dst,src);
+            }
+            pub fn cvtsi2sdq_mr(&mut self,offset: i32,base: u8,dst: u8) {
+                self.formatter.prefix(PRE_SSE_F2);
+                self.formatter.two_byte_op64_3(OP2_CVTSI2SD_VsdEd,dst,base,offset);
+            }
+            pub fn cvttsd2siq_rr(&mut self,src: u8,dst: u8) {
+                self.formatter.prefix(PRE_SSE_F2);
+                self.formatter.two_byte_op64(OP2_CVTTSD2SI_GdWsd,dst,src);
+            }
+            /// Moves XMM `src` into GPR `dst`.
+            pub fn movq_xr(&mut self,src: u8,dst: u8) {
+                self.formatter.prefix(PRE_SSE_66);
+                self.formatter.two_byte_op64(OP2_MOVD_EdVd,src,dst);
+            }
+            /// Moves GPR `src` into XMM `dst`.
+            pub fn movq_rx(&mut self,src: u8,dst: u8) {
+                self.formatter.prefix(PRE_SSE_66);
+                self.formatter.two_byte_op64(OP2_MOVD_VdEd,dst,src);
+            }
+        }
     }
 }
 
 pub const fn diff_between_labels(a: AssemblerLabel, b: AssemblerLabel) -> u32 {
     b.offset - a.offset
 }
+
+#[test]
+fn test_mov_register_and_immediate_forms() {
+    let mut asm = X86Assembler::new();
+    asm.movl_rr(X86Gpr::Esi as _, X86Gpr::Eax as _);
+    asm.movq_rr(X86Gpr::R8 as _, X86Gpr::Ecx as _);
+    asm.movb_rr(X86Gpr::Esi as _, X86Gpr::Eax as _);
+    asm.movw_rr(X86Gpr::Eax as _, X86Gpr::R9 as _);
+    asm.movb_i8r(-1, X86Gpr::Edi as _);
+    asm.movw_i16r(0x1234, X86Gpr::Ecx as _);
+    asm.movl_i32r(0x1234_5678, X86Gpr::R10 as _);
+    assert_eq!(
+        asm.code(),
+        &[
+            0x89, 0xf0, // movl %esi, %eax
+            0x4c, 0x89, 0xc1, // movq %r8, %rcx
+            0x40, 0x88, 0xf0, // movb %sil, %al
+            0x66, 0x41, 0x89, 0xc1, // movw %ax, %r9w
+            0x40, 0xb7, 0xff, // movb $-1, %dil
+            0x66, 0xb9, 0x34, 0x12, // movw $0x1234, %cx
+            0x41, 0xba, 0x78, 0x56, 0x34, 0x12, // movl $0x12345678, %r10d
+        ][..]
+    );
+}
+
+#[test]
+fn test_movq_i64r_picks_shortest_encoding() {
+    let mut asm = X86Assembler::new();
+    asm.movq_i64r(1, X86Gpr::Eax as _);
+    assert_eq!(asm.code(), &[0xb8, 0x01, 0x00, 0x00, 0x00][..]);
+
+    let mut asm = X86Assembler::new();
+    asm.movq_i64r(-1, X86Gpr::Eax as _);
+    assert_eq!(asm.code(), &[0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff][..]);
+
+    let mut asm = X86Assembler::new();
+    asm.movq_i64r(0x1122_3344_5566_7788, X86Gpr::R11 as _);
+    assert_eq!(
+        asm.code(),
+        &[0x49, 0xbb, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11][..]
+    );
+}
+
+#[test]
+fn test_mov_memory_forms() {
+    let mut asm = X86Assembler::new();
+    asm.movl_mr(0, X86Gpr::Ebp as _, X86Gpr::Eax as _);
+    asm.movq_rm(X86Gpr::Eax as _, 8, X86Gpr::R12 as _);
+    asm.movq_mr(0x100, X86Gpr::Esp as _, X86Gpr::R15 as _);
+    asm.movl_mr_index(-4, X86Gpr::Edi as _, X86Gpr::Ecx as _, 2, X86Gpr::Edx as _);
+    asm.movb_rm(X86Gpr::Esi as _, 0, X86Gpr::Esp as _);
+    asm.movw_mr(2, X86Gpr::R13 as _, X86Gpr::Eax as _);
+    asm.movl_i32m(7, 4, X86Gpr::Ebx as _);
+    asm.movq_i32m_index(-1, 0, X86Gpr::Eax as _, X86Gpr::R9 as _, 3);
+    asm.movb_i8m(1, 0, X86Gpr::Eax as _);
+    asm.movw_i16m(0x100, 0, X86Gpr::Eax as _);
+    assert_eq!(
+        asm.code(),
+        &[
+            0x8b, 0x45, 0x00, // movl 0(%rbp), %eax
+            0x49, 0x89, 0x44, 0x24, 0x08, // movq %rax, 8(%r12)
+            0x4c, 0x8b, 0xbc, 0x24, 0x00, 0x01, 0x00, 0x00, // movq 0x100(%rsp), %r15
+            0x8b, 0x54, 0x8f, 0xfc, // movl -4(%rdi,%rcx,4), %edx
+            0x40, 0x88, 0x34, 0x24, // movb %sil, (%rsp)
+            0x66, 0x41, 0x8b, 0x45, 0x02, // movw 2(%r13), %ax
+            0xc7, 0x43, 0x04, 0x07, 0x00, 0x00, 0x00, // movl $7, 4(%rbx)
+            0x4a, 0xc7, 0x04, 0xc8, 0xff, 0xff, 0xff, 0xff, // movq $-1, (%rax,%r9,8)
+            0xc6, 0x00, 0x01, // movb $1, (%rax)
+            0x66, 0xc7, 0x00, 0x00, 0x01, // movw $0x100, (%rax)
+        ][..]
+    );
+}
+
+/// Encodings the baseline got wrong: `memory_modrm_1` dropped the disp8
+/// byte, `memory_modrm_disp32` rejected offsets outside i8 and
+/// `one_byte_op8_3` emitted a REX prefix for a legacy base or index.
+#[test]
+fn test_baseline_memory_operand_fixes() {
+    use X86Gpr::*;
+    let mut asm = X86Assembler::new();
+    asm.movl_mr(8, Edi as _, Eax as _);
+    asm.movl_mr_disp32(1000, Edi as _, Eax as _);
+    asm.movb_rm_index(Eax as _, 0, Esi as _, Edi as _, 0);
+    asm.movb_rm_index(Eax as _, 0, R8 as _, R9 as _, 0);
+    assert_eq!(
+        asm.code(),
+        &[
+            0x8b, 0x47, 0x08, // movl 8(%rdi), %eax
+            0x8b, 0x87, 0xe8, 0x03, 0x00, 0x00, // movl 1000(%rdi), %eax
+            0x88, 0x04, 0x3e, // movb %al, (%rsi,%rdi,1)
+            0x43, 0x88, 0x04, 0x08, // movb %al, (%r8,%r9,1)
+        ][..]
+    );
+}
+
+/// The baseline `align` looped while the size was already aligned, and
+/// `set_rel32` computed backward displacements with an unsigned subtraction.
+#[test]
+fn test_baseline_align_and_link_fixes() {
+    let mut asm = X86Assembler::new();
+    asm.ret();
+    assert_eq!(asm.align(8).offset, 8);
+    assert_eq!(asm.align(8).offset, 8);
+    assert_eq!(
+        asm.code(),
+        &[0xc3, 0xf4, 0xf4, 0xf4, 0xf4, 0xf4, 0xf4, 0xf4][..]
+    );
+
+    let mut asm = X86Assembler::new();
+    let top = asm.label();
+    let jump = asm.jmp();
+    asm.link_jump(jump, top);
+    assert_eq!(asm.code(), &[0xe9, 0xfb, 0xff, 0xff, 0xff][..]);
+}
+
+/// Baseline arithmetic emitters fixed by the typed front-end: `subq_ir`
+/// emitted an ADD, `sarq_clr` a ROR and `xorq_ir` the byte `80 /6` opcode;
+/// `subl_ir` and `addq_ir` wrote an imm8 after an `EvIz` opcode and
+/// `andl_im` dropped its imm32.
+#[test]
+fn test_baseline_arithmetic_fixes() {
+    use X86Gpr::*;
+    let mut asm = X86Assembler::new();
+    asm.subq_ir(1, Eax as _);
+    asm.subq_ir(1000, R9 as _);
+    asm.sarq_clr(Edx as _);
+    asm.xorq_ir(-1, Esi as _);
+    asm.subl_ir(1000, Edi as _);
+    asm.addq_ir(1000, Ecx as _);
+    asm.andl_im(1000, 4, Edi as _);
+    asm.orq_rr(Edx as _, R8 as _);
+    #[allow(deprecated)]
+    asm.oqr_rr(Edx as _, R8 as _);
+    assert_eq!(
+        asm.code(),
+        &[
+            0x48, 0x83, 0xe8, 0x01, // sub $1, %rax
+            0x49, 0x81, 0xe9, 0xe8, 0x03, 0x00, 0x00, // sub $1000, %r9
+            0x48, 0xd3, 0xfa, // sar %cl, %rdx
+            0x48, 0x83, 0xf6, 0xff, // xor $-1, %rsi
+            0x81, 0xef, 0xe8, 0x03, 0x00, 0x00, // sub $1000, %edi
+            0x48, 0x81, 0xc1, 0xe8, 0x03, 0x00, 0x00, // add $1000, %rcx
+            0x81, 0x67, 0x04, 0xe8, 0x03, 0x00, 0x00, // andl $1000, 4(%rdi)
+            0x49, 0x09, 0xd0, // or %rdx, %r8
+            0x49, 0x09, 0xd0, // or %rdx, %r8
+        ][..]
+    );
+}
+
+#[test]
+fn test_jump_encodings() {
+    let mut asm = X86Assembler::new();
+    let top = asm.label();
+    let back = asm.jmp();
+    let fwd = asm.jcc(X86Condition::NE);
+    asm.jmp_r(X86Gpr::R11 as _);
+    asm.jmp_m(8, X86Gpr::Eax as _);
+    let end = asm.label();
+    asm.link_jump(back, top);
+    asm.link_jump(fwd, end);
+    assert_eq!(
+        asm.code(),
+        &[
+            0xe9, 0xfb, 0xff, 0xff, 0xff, // jmp top
+            0x0f, 0x85, 0x06, 0x00, 0x00, 0x00, // jne end
+            0x41, 0xff, 0xe3, // jmp *%r11
+            0xff, 0x60, 0x08, // jmp *8(%rax)
+        ][..]
+    );
+}
+
+#[test]
+fn test_jit_loop() {
+    let mut asm = X86Assembler::new();
+    asm.movl_i32r(0, X86Gpr::Eax as _);
+    asm.cmpl_ir(0, X86Gpr::Edi as _, false);
+    let skip = asm.jcc(X86Condition::E);
+    let top = asm.label();
+    asm.addl_rr(X86Gpr::Edi as _, X86Gpr::Eax as _);
+    asm.subl_ir(1, X86Gpr::Edi as _);
+    let again = asm.jcc(X86Condition::NE);
+    asm.link_jump(again, top);
+    let done = asm.label();
+    asm.link_jump(skip, done);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(i32) -> i32 = unsafe { code.as_fn() };
+    assert_eq!(f(0), 0);
+    assert_eq!(f(10), 55);
+}
+
+#[test]
+fn test_branch_relaxation() {
+    let mut asm = X86Assembler::new();
+    asm.set_branch_relaxation(true);
+    asm.movl_i32r(0, X86Gpr::Eax as _);
+    asm.cmpl_ir(0, X86Gpr::Edi as _, false);
+    let skip = asm.jcc(X86Condition::E);
+    let enter = asm.jmp();
+    let top = asm.align(16);
+    asm.link_jump(enter, top);
+    asm.addl_rr(X86Gpr::Edi as _, X86Gpr::Eax as _);
+    asm.subl_ir(1, X86Gpr::Edi as _);
+    let again = asm.jcc(X86Condition::NE);
+    asm.link_jump(again, top);
+    let far = asm.jmp();
+    for _ in 0..200 {
+        asm.int3();
+    }
+    let done = asm.label();
+    asm.link_jump(skip, done);
+    asm.link_jump(far, done);
+    asm.ret();
+    let size = asm.code().len();
+
+    let map = asm.relax_branches();
+    let top = map.map_label(top);
+    assert_eq!(top.offset % 16, 0);
+    assert_eq!(asm.code()[map.map_label(enter).offset as usize - 2], 0xeb);
+    assert_eq!(
+        &asm.code()[top.offset as usize + 5..][..2],
+        &[0x75, 0xf9][..]
+    );
+    assert_eq!(asm.code()[map.map_label(skip).offset as usize - 5], 0x84);
+    assert_eq!(asm.code()[map.map_label(far).offset as usize - 5], 0xe9);
+    assert_eq!(map.map_label(done).offset as usize, asm.code().len() - 1);
+    assert!(asm.code().len() < size);
+
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(i32) -> i32 = unsafe { code.as_fn() };
+    assert_eq!(f(0), 0);
+    assert_eq!(f(10), 55);
+}
+
+#[test]
+fn test_branch_relaxation_at_empty_alignment() {
+    // The alignment adds no padding and starts where the jump does.
+    let mut asm = X86Assembler::new();
+    asm.set_branch_relaxation(true);
+    let top = asm.align(16);
+    let j = asm.jmp();
+    asm.ret();
+    let done = asm.label();
+    asm.link_jump(j, done);
+    asm.ret();
+
+    let map = asm.relax_branches();
+    assert_eq!(map.map_label(top).offset, 0);
+    assert_eq!(map.map_label(done).offset, 3);
+    assert_eq!(asm.code(), &[0xeb, 0x01, 0xc3, 0xc3][..]);
+}
+
+#[cfg(test)]
+extern "C" fn add_one(x: i64) -> i64 {
+    x + 1
+}
+
+#[test]
+fn test_calls() {
+    let mut asm = X86Assembler::new();
+    asm.push_r(X86Gpr::Ebp as _);
+    asm.call_external(add_one as *const u8);
+    asm.movq_rr(X86Gpr::Eax as _, X86Gpr::Edi as _);
+    asm.call_far(add_one as *const u8);
+    asm.movq_rr(X86Gpr::Eax as _, X86Gpr::Edi as _);
+    let local = asm.call();
+    asm.pop_r(X86Gpr::Ebp as _);
+    asm.ret();
+    let helper = asm.label();
+    asm.link_call(local, helper);
+    asm.movq_rr(X86Gpr::Edi as _, X86Gpr::Eax as _);
+    asm.addq_ir(10, X86Gpr::Eax as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(i64) -> i64 = unsafe { code.as_fn() };
+    assert_eq!(f(1), 13);
+}
+
+#[test]
+fn test_call_veneers_only_when_out_of_range() {
+    use crate::mem::{page_size, ExecutableAllocator};
+    use std::sync::Arc;
+
+    let pool = Arc::new(ExecutableAllocator::new(page_size()).unwrap());
+    let mut helper = X86Assembler::new();
+    helper.leaq_mr(1, X86Gpr::Edi as _, X86Gpr::Eax as _);
+    helper.ret();
+    let helper = helper.formatter.executable_in(&pool);
+
+    // Within the pool every target is in rel32 range.
+    let mut asm = X86Assembler::new();
+    asm.push_r(X86Gpr::Ebp as _);
+    asm.call_external(helper.as_ptr());
+    asm.pop_r(X86Gpr::Ebp as _);
+    asm.ret();
+    let size = asm.code().len();
+    let code = asm.formatter.executable_in(&pool);
+    assert_eq!(code.len(), size);
+    let f: extern "C" fn(i64) -> i64 = unsafe { code.as_fn() };
+    assert_eq!(f(1), 2);
+
+    // A target far away from the pool still gets its trampoline.
+    let mut asm = X86Assembler::new();
+    asm.call_external(0x1000 as *const u8);
+    asm.ret();
+    let size = asm.code().len();
+    let code = asm.formatter.executable_in(&pool);
+    assert_eq!(code.len(), size + 14);
+}
+
+#[test]
+fn test_call_encodings() {
+    let mut asm = X86Assembler::new();
+    asm.call_r(X86Gpr::R11 as _);
+    asm.call_m(16, X86Gpr::Esp as _);
+    assert_eq!(asm.code(), &[0x41, 0xff, 0xd3, 0xff, 0x54, 0x24, 0x10][..]);
+}
+
+#[test]
+fn test_data_label_encodings() {
+    let mut asm = X86Assembler::new();
+    let a = asm.movl_mr_disp32(0, X86Gpr::Edi as _, X86Gpr::Eax as _);
+    let b = asm.movq_mr_disp32(0, X86Gpr::R12 as _, X86Gpr::Eax as _);
+    let c = asm.movq_rm_disp32(X86Gpr::Esi as _, 16, X86Gpr::Esp as _);
+    let d = asm.movq_mr_disp8(0, X86Gpr::Edi as _, X86Gpr::Eax as _);
+    let e = asm.cmpl_im_with_patch(0x1000, 8, X86Gpr::Esi as _);
+    let f = asm.cmpq_ir_with_patch(0x1000, X86Gpr::Edx as _);
+    let g = asm.movq_i64r_with_patch(0, X86Gpr::R9 as _);
+    assert_eq!(
+        asm.code(),
+        &[
+            0x8b, 0x87, 0, 0, 0, 0, // movl 0x0(%rdi), %eax
+            0x49, 0x8b, 0x84, 0x24, 0, 0, 0, 0, // movq 0x0(%r12), %rax
+            0x48, 0x89, 0xb4, 0x24, 0x10, 0, 0, 0, // movq %rsi, 0x10(%rsp)
+            0x48, 0x8b, 0x47, 0x00, // movq 0x0(%rdi), %rax
+            0x81, 0x7e, 0x08, 0x00, 0x10, 0, 0, // cmpl $0x1000, 0x8(%rsi)
+            0x48, 0x81, 0xfa, 0x00, 0x10, 0, 0, // cmpq $0x1000, %rdx
+            0x49, 0xb9, 0, 0, 0, 0, 0, 0, 0, 0, // movabs $0x0, %r9
+        ][..]
+    );
+    let ends = [
+        a.label, b.label, c.label, d.label, e.label, f.label, g.label,
+    ];
+    let ends: Vec<u32> = ends.iter().map(|l| l.offset).collect();
+    assert_eq!(ends, [6, 14, 22, 26, 33, 40, 50]);
+}
+
+#[test]
+fn test_link_data_labels() {
+    let mut asm = X86Assembler::new();
+    let values = [10i32, 20, 30, 40];
+    // f(p, x) = x == K ? p[A] + p[B] : &f
+    let k = asm.cmpl_ir_with_patch(0, X86Gpr::Esi as _);
+    let equal = asm.jcc(X86Condition::E);
+    let ptr = asm.movq_i64r_with_patch(0, X86Gpr::Eax as _);
+    asm.ret();
+    let take = asm.label();
+    asm.link_jump(equal, take);
+    let a = asm.movl_mr_disp32(0, X86Gpr::Edi as _, X86Gpr::Eax as _);
+    let b = asm.movl_mr_disp8(0, X86Gpr::Edi as _, X86Gpr::Ecx as _);
+    asm.addl_rr(X86Gpr::Ecx as _, X86Gpr::Eax as _);
+    asm.ret();
+
+    asm.link_data32(k, 7);
+    asm.link_data_ptr_to_label(ptr, AssemblerLabel::new(0));
+    asm.link_data32(a, 4);
+    asm.link_data_compact(b, 12);
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(*const i32, i32) -> i64 = unsafe { code.as_fn() };
+    assert_eq!(f(values.as_ptr(), 7), 60);
+    assert_eq!(f(values.as_ptr(), 0), code.as_ptr() as i64);
+}
+
+#[test]
+fn test_constant_pool_operands() {
+    let mut asm = X86Assembler::new();
+    let sign = asm.constant_u128(0x8000_0000_0000_0000);
+    let two = asm.constant_f64(2.0);
+    // f(x) = -(x * 2.0) + 2.0
+    asm.with_constant(two, |asm, offset, base| {
+        asm.mulsd_mr(offset, base, X86Fpr::XMM0 as _)
+    });
+    asm.with_constant(sign, |asm, offset, base| {
+        asm.xorpd_mr(offset, base, X86Fpr::XMM0 as _)
+    });
+    asm.with_constant(two, |asm, offset, base| {
+        asm.addsd_mr(offset, base, X86Fpr::XMM0 as _)
+    });
+    // Immediates after the displacement: cmpl $1, two+4(%rip).
+    asm.with_constant(two, |asm, offset, base| {
+        asm.cmpl_im_with_patch(1, offset + 4, base);
+    });
+    asm.ret();
+    assert_eq!(&asm.code()[..4], &[0xf2, 0x0f, 0x59, 0x05]);
+    assert_eq!(asm.constant_f64(2.0), two);
+
+    let code = asm.formatter.executable_readable();
+    let data = code.as_slice();
+    let sign_at = asm.formatter.buffer.constant_offset(sign).unwrap() as usize;
+    let two_at = asm.formatter.buffer.constant_offset(two).unwrap() as usize;
+    assert_eq!(sign_at % 16, 0);
+    assert_eq!(&data[two_at..two_at + 8], &2.0f64.to_ne_bytes());
+    // The displacement of the cmpl is relative to the end of its imm32.
+    let cmp_end = 3 * 8 + 10;
+    let disp = i32::from_ne_bytes([data[26], data[27], data[28], data[29]]);
+    assert_eq!(cmp_end + disp, two_at as i32 + 4);
+
+    let f: extern "C" fn(f64) -> f64 = unsafe { code.as_fn() };
+    assert_eq!(f(1.5), -1.0);
+    assert_eq!(f(-3.0), 8.0);
+}
+
+#[test]
+fn test_rip_relative_operands() {
+    use crate::mem::{page_size, ExecutableAllocator};
+    use std::sync::Arc;
+
+    // A data chunk and code referencing it by absolute address share a pool,
+    // so they are within rel32 range of each other.
+    let pool = Arc::new(ExecutableAllocator::new(page_size()).unwrap());
+    let mut data = AssemblerBuffer::new();
+    data.put_long(0x1122_3344_5566_7788);
+    let data = data.executable_memory_in(&pool, 16).unwrap();
+
+    let mut asm = X86Assembler::new();
+    asm.rip_relative(
+        PcRelTarget::Label(AssemblerLabel::new(64)),
+        |asm, offset, base| asm.movl_mr(offset + 4, base, X86Gpr::Ecx as _),
+    );
+    asm.rip_relative(
+        PcRelTarget::Absolute(data.as_ptr() as usize),
+        |asm, offset, base| asm.movq_mr(offset, base, X86Gpr::Eax as _),
+    );
+    asm.rip_relative(
+        PcRelTarget::Label(AssemblerLabel::new(64)),
+        |asm, offset, base| {
+            asm.cmpl_im_with_patch(7, offset + 4, base);
+        },
+    );
+    let equal = asm.jcc(X86Condition::E);
+    asm.xorl_rr(X86Gpr::Eax as _, X86Gpr::Eax as _);
+    let done = asm.label();
+    asm.link_jump(equal, done);
+    asm.addq_rr(X86Gpr::Ecx as _, X86Gpr::Eax as _);
+    asm.ret();
+    asm.align(64);
+    assert_eq!(asm.label().offset, 64);
+    asm.formatter.buffer.put_int(0);
+    asm.formatter.buffer.put_int(7);
+
+    let code = asm.formatter.executable_in(&pool);
+    let f: extern "C" fn() -> i64 = unsafe { code.as_fn() };
+    assert_eq!(f(), 0x1122_3344_5566_7788 + 7);
+
+    // Too far away to be reached from any mapping.
+    let mut asm = X86Assembler::new();
+    asm.rip_relative(PcRelTarget::Absolute(0x1000), |asm, offset, base| {
+        asm.movq_mr(offset, base, X86Gpr::Eax as _)
+    });
+    asm.ret();
+    let err = asm.formatter.try_executable_readable().err().unwrap();
+    assert_eq!(err.errno(), libc::ERANGE);
+
+    let mut asm = X86Assembler::new();
+    asm.formatter
+        .one_byte_op_5(OP_MOV_GvEv, X86Gpr::Eax as _, 0x1000);
+    assert_eq!(asm.code(), &[0x8b, 0x04, 0x25, 0x00, 0x10, 0x00, 0x00][..]);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_rip_relative_operand_count() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let target = PcRelTarget::Label(AssemblerLabel::new(0));
+    let mut asm = X86Assembler::new();
+    let two = catch_unwind(AssertUnwindSafe(|| {
+        asm.rip_relative(target, |asm, offset, base| {
+            asm.movl_mr(offset, base, X86Gpr::Eax as _);
+            asm.movl_rm(X86Gpr::Eax as _, offset, base);
+        })
+    }));
+    assert!(two.is_err());
+    let none = catch_unwind(AssertUnwindSafe(|| {
+        asm.rip_relative(target, |asm, _, _| asm.ret())
+    }));
+    assert!(none.is_err());
+    let outside = catch_unwind(AssertUnwindSafe(|| asm.movl_mr(0, RIP, X86Gpr::Eax as _)));
+    assert!(outside.is_err());
+
+    // None of the panics above left the operand count open.
+    let start = asm.label().offset as usize;
+    asm.rip_relative(target, |asm, offset, base| {
+        asm.movl_mr(offset, base, X86Gpr::R9 as _)
+    });
+    assert_eq!(&asm.code()[start..], &[0x44, 0x8b, 0x0d, 0, 0, 0, 0][..]);
+}
+
+#[test]
+fn test_conditions() {
+    use X86Condition::*;
+    let all = [O, NO, B, AE, E, NE, BE, A, S, NS, P, NP, L, GE, LE, G];
+    for &cond in all.iter() {
+        assert_eq!(cond.invert().invert(), cond);
+        assert_ne!(cond.invert(), cond);
+        // The encodings of a condition and its inverse differ in the lowest bit.
+        assert_eq!(cond.invert() as u8, cond as u8 ^ 1);
+        assert_eq!(cond.commute().commute(), cond);
+    }
+    assert_eq!(L.invert(), GE);
+    assert_eq!(BE.invert(), A);
+    assert_eq!(L.commute(), G);
+    assert_eq!(AE.commute(), BE);
+    assert_eq!(NE.commute(), NE);
+}
+
+#[test]
+fn test_setcc_cmovcc_encodings() {
+    use X86Condition::*;
+    use X86Gpr::*;
+    let mut asm = X86Assembler::new();
+    asm.setcc_r(B, Eax as _);
+    asm.setcc_r(NE, Esi as _);
+    asm.setcc_r(E, R9 as _);
+    asm.setcc_m(G, 8, Ebx as _);
+    asm.setcc_m_index(L, 0, R12 as _, Ecx as _, 2);
+    asm.cmovl_rr(L, Esi as _, Eax as _);
+    asm.cmovl_mr(B, 16, R13 as _, R8 as _);
+    asm.cmovw_rr(GE, R9 as _, Edx as _);
+    asm.cmovw_mr(NE, -4, Ebp as _, Eax as _);
+    asm.cmovq_rr(A, Edi as _, R11 as _);
+    asm.cmovq_mr(LE, 8, Esp as _, Eax as _);
+    asm.cmovq_mr_index(S, 0, Eax as _, R10 as _, 3, Ecx as _);
+    asm.cmovl_mr_index(O, 0, Ebx as _, Ecx as _, 1, Ecx as _);
+    asm.cmovw_mr_index(NP, 2, Edi as _, Esi as _, 0, Esi as _);
+    assert_eq!(
+        asm.code(),
+        &[
+            0x0f, 0x92, 0xc0, // setb %al
+            0x40, 0x0f, 0x95, 0xc6, // setne %sil
+            0x41, 0x0f, 0x94, 0xc1, // sete %r9b
+            0x0f, 0x9f, 0x43, 0x08, // setg 8(%rbx)
+            0x41, 0x0f, 0x9c, 0x04, 0x8c, // setl (%r12,%rcx,4)
+            0x0f, 0x4c, 0xc6, // cmovl %esi, %eax
+            0x45, 0x0f, 0x42, 0x45, 0x10, // cmovb 16(%r13), %r8d
+            0x66, 0x41, 0x0f, 0x4d, 0xd1, // cmovge %r9w, %dx
+            0x66, 0x0f, 0x45, 0x45, 0xfc, // cmovne -4(%rbp), %ax
+            0x4c, 0x0f, 0x47, 0xdf, // cmova %rdi, %r11
+            0x48, 0x0f, 0x4e, 0x44, 0x24, 0x08, // cmovle 8(%rsp), %rax
+            0x4a, 0x0f, 0x48, 0x0c, 0xd0, // cmovs (%rax,%r10,8), %rcx
+            0x0f, 0x40, 0x0c, 0x4b, // cmovo (%rbx,%rcx,2), %ecx
+            0x66, 0x0f, 0x4b, 0x74, 0x37, 0x02, // cmovnp 2(%rdi,%rsi,1), %si
+        ][..]
+    );
+
+    // max(a, b) and a < b materialised without branches.
+    let mut asm = X86Assembler::new();
+    asm.movq_rr(Edi as _, Eax as _);
+    asm.formatter
+        .one_byte_op64_2(OP_CMP_EvGv, Esi as _, Edi as _);
+    asm.cmovq_rr(L, Esi as _, Eax as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let max: extern "C" fn(i64, i64) -> i64 = unsafe { code.as_fn() };
+    assert_eq!(max(-3, 7), 7);
+    assert_eq!(max(9, 7), 9);
+
+    let mut asm = X86Assembler::new();
+    asm.xorl_rr(Eax as _, Eax as _);
+    asm.cmpl_rr(Esi as _, Edi as _);
+    asm.setcc_r(L, Eax as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let less: extern "C" fn(i32, i32) -> i32 = unsafe { code.as_fn() };
+    assert_eq!(less(1, 2), 1);
+    assert_eq!(less(2, 1), 0);
+}
+
+#[test]
+fn test_lea_encodings() {
+    use X86Gpr::*;
+    let mut asm = X86Assembler::new();
+    asm.leal_mr(8, Ebx as _, Eax as _);
+    asm.leal_mr(-300, R13 as _, R9 as _);
+    asm.leal_mr_index(0, Eax as _, Ecx as _, 2, Edx as _);
+    asm.leal_mr_no_base(0, Esi as _, 3, Eax as _);
+    asm.leal_mr_no_base(16, R10 as _, 1, R11 as _);
+    asm.leaq_mr(8, Esp as _, Eax as _);
+    asm.leaq_mr_index(0, R13 as _, R12 as _, 0, R8 as _);
+    asm.leaq_mr_no_base(0x1000, Edi as _, 2, Ecx as _);
+    asm.leaq_rip(PcRelTarget::Label(AssemblerLabel::new(0x35)), Eax as _);
+    asm.add3l(Edi as _, Esi as _, Eax as _);
+    asm.scale_by_constq(3, Edi as _, Eax as _);
+    asm.scale_by_constq(9, R12 as _, R12 as _);
+    asm.scale_by_constl(5, Ebp as _, Ebp as _);
+    asm.formatter.buffer.place_constant_pool(OP_HLT);
+    assert_eq!(
+        asm.code(),
+        &[
+            0x8d, 0x43, 0x08, // lea 8(%rbx), %eax
+            0x45, 0x8d, 0x8d, 0xd4, 0xfe, 0xff, 0xff, // lea -300(%r13), %r9d
+            0x8d, 0x14, 0x88, // lea (%rax,%rcx,4), %edx
+            0x8d, 0x04, 0xf5, 0, 0, 0, 0, // lea 0(,%rsi,8), %eax
+            0x46, 0x8d, 0x1c, 0x55, 0x10, 0, 0, 0, // lea 16(,%r10,2), %r11d
+            0x48, 0x8d, 0x44, 0x24, 0x08, // lea 8(%rsp), %rax
+            0x4f, 0x8d, 0x44, 0x25, 0x00, // lea 0(%r13,%r12,1), %r8
+            0x48, 0x8d, 0x0c, 0xbd, 0x00, 0x10, 0, 0, // lea 0x1000(,%rdi,4), %rcx
+            0x48, 0x8d, 0x05, 0, 0, 0, 0, // lea 0(%rip), %rax
+            0x8d, 0x04, 0x37, // lea (%rdi,%rsi,1), %eax
+            0x48, 0x8d, 0x04, 0x7f, // lea (%rdi,%rdi,2), %rax
+            0x4f, 0x8d, 0x24, 0xe4, // lea (%r12,%r12,8), %r12
+            0x8d, 0x6c, 0xad, 0x00, // lea 0(%rbp,%rbp,4), %ebp
+        ][..]
+    );
+
+    // a + b * 9 + 7 without touching the flags.
+    let mut asm = X86Assembler::new();
+    asm.scale_by_constq(9, Esi as _, Esi as _);
+    asm.add3q(Esp as _, Edi as _, Eax as _);
+    asm.subq_rr(Esp as _, Eax as _);
+    asm.leaq_mr_index(7, Eax as _, Esi as _, 0, Eax as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(i64, i64) -> i64 = unsafe { code.as_fn() };
+    assert_eq!(f(10, 3), 10 + 27 + 7);
+}
+
+#[test]
+fn test_widening_load_encodings() {
+    use X86Gpr::*;
+    let mut asm = X86Assembler::new();
+    asm.movzbl_rr(Eax as _, Esi as _);
+    asm.movzbl_rr(Esi as _, Eax as _);
+    asm.movzbl_rr(R9 as _, R10 as _);
+    asm.movzbl_rr(Ebx as _, R8 as _);
+    asm.movzbl_mr(8, Edi as _, Eax as _);
+    asm.movzbl_mr_index(0, Eax as _, R11 as _, 1, Ecx as _);
+    asm.movzwl_rr(Edi as _, Eax as _);
+    asm.movzwl_mr(-2, R12 as _, R15 as _);
+    asm.movzwl_mr_index(0, Ebx as _, Ecx as _, 1, Edx as _);
+    asm.movsbl_rr(Edi as _, Eax as _);
+    asm.movsbl_mr(1, Esi as _, Ecx as _);
+    asm.movsbl_mr_index(0, Edi as _, Esi as _, 0, R8 as _);
+    asm.movswl_rr(Eax as _, Ebx as _);
+    asm.movswl_mr(4, Ebp as _, Eax as _);
+    asm.movswl_mr_index(0, Edi as _, Ecx as _, 1, Eax as _);
+    asm.movzbq_rr(Esp as _, Eax as _);
+    asm.movzbq_mr(3, Edi as _, R9 as _);
+    asm.movzwq_rr(R8 as _, Eax as _);
+    asm.movzwq_mr_index(0, Eax as _, Ebx as _, 2, Edx as _);
+    asm.movsbq_rr(Ebp as _, Ecx as _);
+    asm.movsbq_mr(-1, R13 as _, Eax as _);
+    asm.movsbq_mr_index(0, Eax as _, Ecx as _, 0, Edx as _);
+    asm.movswq_rr(Esi as _, R10 as _);
+    asm.movswq_mr(6, Esp as _, Eax as _);
+    asm.movswq_mr_index(0, Esi as _, Edi as _, 3, Eax as _);
+    asm.movslq_rr(Edi as _, Eax as _);
+    asm.movslq_rr(R14 as _, R15 as _);
+    asm.movslq_mr(12, Ebx as _, Ecx as _);
+    asm.movslq_mr_index(0, Edi as _, Esi as _, 2, Eax as _);
+    assert_eq!(
+        asm.code(),
+        &[
+            0x0f, 0xb6, 0xf0, // movzbl %al, %esi
+            0x40, 0x0f, 0xb6, 0xc6, // movzbl %sil, %eax
+            0x45, 0x0f, 0xb6, 0xd1, // movzbl %r9b, %r10d
+            0x44, 0x0f, 0xb6, 0xc3, // movzbl %bl, %r8d
+            0x0f, 0xb6, 0x47, 0x08, // movzbl 8(%rdi), %eax
+            0x42, 0x0f, 0xb6, 0x0c, 0x58, // movzbl (%rax,%r11,2), %ecx
+            0x0f, 0xb7, 0xc7, // movzwl %di, %eax
+            0x45, 0x0f, 0xb7, 0x7c, 0x24, 0xfe, // movzwl -2(%r12), %r15d
+            0x0f, 0xb7, 0x14, 0x4b, // movzwl (%rbx,%rcx,2), %edx
+            0x40, 0x0f, 0xbe, 0xc7, // movsbl %dil, %eax
+            0x0f, 0xbe, 0x4e, 0x01, // movsbl 1(%rsi), %ecx
+            0x44, 0x0f, 0xbe, 0x04, 0x37, // movsbl (%rdi,%rsi,1), %r8d
+            0x0f, 0xbf, 0xd8, // movswl %ax, %ebx
+            0x0f, 0xbf, 0x45, 0x04, // movswl 4(%rbp), %eax
+            0x0f, 0xbf, 0x04, 0x4f, // movswl (%rdi,%rcx,2), %eax
+            0x48, 0x0f, 0xb6, 0xc4, // movzbq %spl, %rax
+            0x4c, 0x0f, 0xb6, 0x4f, 0x03, // movzbq 3(%rdi), %r9
+            0x49, 0x0f, 0xb7, 0xc0, // movzwq %r8w, %rax
+            0x48, 0x0f, 0xb7, 0x14, 0x98, // movzwq (%rax,%rbx,4), %rdx
+            0x48, 0x0f, 0xbe, 0xcd, // movsbq %bpl, %rcx
+            0x49, 0x0f, 0xbe, 0x45, 0xff, // movsbq -1(%r13), %rax
+            0x48, 0x0f, 0xbe, 0x14, 0x08, // movsbq (%rax,%rcx,1), %rdx
+            0x4c, 0x0f, 0xbf, 0xd6, // movswq %si, %r10
+            0x48, 0x0f, 0xbf, 0x44, 0x24, 0x06, // movswq 6(%rsp), %rax
+            0x48, 0x0f, 0xbf, 0x04, 0xfe, // movswq (%rsi,%rdi,8), %rax
+            0x48, 0x63, 0xc7, // movslq %edi, %rax
+            0x4d, 0x63, 0xfe, // movslq %r14d, %r15
+            0x48, 0x63, 0x4b, 0x0c, // movslq 12(%rbx), %rcx
+            0x48, 0x63, 0x04, 0xb7, // movslq (%rdi,%rsi,4), %rax
+        ][..]
+    );
+
+    // Sum the fields of `struct { u8 a; i8 b; u16 c; i16 d; i32 e; }`.
+    let mut asm = X86Assembler::new();
+    asm.movzbl_mr(0, Edi as _, Eax as _);
+    asm.movsbq_mr(1, Edi as _, Ecx as _);
+    asm.addq_rr(Ecx as _, Eax as _);
+    asm.movzwl_mr(2, Edi as _, Ecx as _);
+    asm.addq_rr(Ecx as _, Eax as _);
+    asm.movswq_mr(4, Edi as _, Ecx as _);
+    asm.addq_rr(Ecx as _, Eax as _);
+    asm.movslq_mr(8, Edi as _, Ecx as _);
+    asm.addq_rr(Ecx as _, Eax as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let sum: extern "C" fn(*const u8) -> i64 = unsafe { code.as_fn() };
+    let mut fields = [0u8; 12];
+    fields[0] = 200;
+    fields[1] = -3i8 as u8;
+    fields[2..4].copy_from_slice(&60000u16.to_ne_bytes());
+    fields[4..6].copy_from_slice(&(-1000i16).to_ne_bytes());
+    fields[8..12].copy_from_slice(&(-70000i32).to_ne_bytes());
+    assert_eq!(sum(fields.as_ptr()), 200 - 3 + 60000 - 1000 - 70000);
+}
+
+#[test]
+fn test_multiply_divide_encodings() {
+    use X86Gpr::*;
+    let mut asm = X86Assembler::new();
+    asm.imull_rr(Esi as _, Eax as _);
+    asm.imull_rr(R9 as _, Ecx as _);
+    asm.imull_mr(8, Edi as _, Eax as _);
+    asm.imull_mr_index(0, Eax as _, R11 as _, 2, R8 as _);
+    asm.imull_i8r(Esi as _, 7, Eax as _);
+    asm.imull_i32r(R10 as _, 1000, Ecx as _);
+    asm.imull_i8mr(8, Ebx as _, -2, Edx as _);
+    asm.imull_i32mr(0, R12 as _, 300, Eax as _);
+    asm.imulq_rr(Esi as _, Eax as _);
+    asm.imulq_mr(-8, Ebp as _, R15 as _);
+    asm.imulq_mr_index(0, Edi as _, Esi as _, 3, Edx as _);
+    asm.imulq_i8r(Edi as _, 5, Eax as _);
+    asm.imulq_i32r(R8 as _, 100000, R9 as _);
+    asm.imulq_i8mr(16, Esp as _, 3, Ecx as _);
+    asm.imulq_i32mr(0, Eax as _, 70000, Eax as _);
+    asm.mull_r(Esi as _);
+    asm.mulq_r(R9 as _);
+    asm.mull_m(4, Edi as _);
+    asm.mulq_m(0, Esp as _);
+    asm.divl_r(Ecx as _);
+    asm.divq_r(R11 as _);
+    asm.divl_m(8, Ebx as _);
+    asm.divq_m(-8, R13 as _);
+    asm.idivl_r(Esi as _);
+    asm.idivq_r(Edi as _);
+    asm.idivl_m(0, Eax as _);
+    asm.idivq_m(16, R12 as _);
+    asm.imull_r(Ecx as _);
+    asm.imulq_r(R8 as _);
+    asm.cdq();
+    asm.cqo();
+    asm.mulxl_rr(Esi as _, Ecx as _, Eax as _);
+    asm.mulxq_rr(R9 as _, R10 as _, R11 as _);
+    asm.mulxl_mr(8, Edi as _, Eax as _, Ebx as _);
+    asm.mulxq_mr(0, R12 as _, Eax as _, R8 as _);
+    assert_eq!(
+        asm.code(),
+        &[
+            0x0f, 0xaf, 0xc6, // imul %esi, %eax
+            0x41, 0x0f, 0xaf, 0xc9, // imul %r9d, %ecx
+            0x0f, 0xaf, 0x47, 0x08, // imul 8(%rdi), %eax
+            0x46, 0x0f, 0xaf, 0x04, 0x98, // imul (%rax,%r11,4), %r8d
+            0x6b, 0xc6, 0x07, // imul $7, %esi, %eax
+            0x41, 0x69, 0xca, 0xe8, 0x03, 0, 0, // imul $1000, %r10d, %ecx
+            0x6b, 0x53, 0x08, 0xfe, // imul $-2, 8(%rbx), %edx
+            0x41, 0x69, 0x04, 0x24, 0x2c, 0x01, 0, 0, // imul $300, (%r12), %eax
+            0x48, 0x0f, 0xaf, 0xc6, // imul %rsi, %rax
+            0x4c, 0x0f, 0xaf, 0x7d, 0xf8, // imul -8(%rbp), %r15
+            0x48, 0x0f, 0xaf, 0x14, 0xf7, // imul (%rdi,%rsi,8), %rdx
+            0x48, 0x6b, 0xc7, 0x05, // imul $5, %rdi, %rax
+            0x4d, 0x69, 0xc8, 0xa0, 0x86, 0x01, 0x00, // imul $100000, %r8, %r9
+            0x48, 0x6b, 0x4c, 0x24, 0x10, 0x03, // imul $3, 16(%rsp), %rcx
+            0x48, 0x69, 0x00, 0x70, 0x11, 0x01, 0x00, // imul $70000, (%rax), %rax
+            0xf7, 0xe6, // mul %esi
+            0x49, 0xf7, 0xe1, // mul %r9
+            0xf7, 0x67, 0x04, // mull 4(%rdi)
+            0x48, 0xf7, 0x24, 0x24, // mulq (%rsp)
+            0xf7, 0xf1, // div %ecx
+            0x49, 0xf7, 0xf3, // div %r11
+            0xf7, 0x73, 0x08, // divl 8(%rbx)
+            0x49, 0xf7, 0x75, 0xf8, // divq -8(%r13)
+            0xf7, 0xfe, // idiv %esi
+            0x48, 0xf7, 0xff, // idiv %rdi
+            0xf7, 0x38, // idivl (%rax)
+            0x49, 0xf7, 0x7c, 0x24, 0x10, // idivq 16(%r12)
+            0xf7, 0xe9, // imul %ecx
+            0x49, 0xf7, 0xe8, // imul %r8
+            0x99, // cltd
+            0x48, 0x99, // cqto
+            0xc4, 0xe2, 0x73, 0xf6, 0xc6, // mulx %esi, %ecx, %eax
+            0xc4, 0x42, 0xab, 0xf6, 0xd9, // mulx %r9, %r10, %r11
+            0xc4, 0xe2, 0x7b, 0xf6, 0x5f, 0x08, // mulx 8(%rdi), %eax, %ebx
+            0xc4, 0x42, 0xfb, 0xf6, 0x04, 0x24, // mulx (%r12), %rax, %r8
+        ][..]
+    );
+}
+
+#[test]
+fn test_multiply_divide() {
+    use X86Gpr::*;
+    // (a * b * 3 * -5 + 1000) with the 2- and 3-operand forms.
+    let mut asm = X86Assembler::new();
+    asm.imulq_rr(Esi as _, Edi as _);
+    asm.imulq_i8r(Edi as _, 3, Eax as _);
+    asm.imulq_ir(-5, Eax as _);
+    asm.addq_ir(1000, Eax as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(i64, i64) -> i64 = unsafe { code.as_fn() };
+    assert_eq!(f(4, 7), 4 * 7 * 3 * -5 + 1000);
+
+    // The high half of an unsigned 64x64 bit product.
+    let mut asm = X86Assembler::new();
+    asm.movq_rr(Edi as _, Eax as _);
+    asm.mulq_r(Esi as _);
+    asm.movq_rr(Edx as _, Eax as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let mulhi: extern "C" fn(u64, u64) -> u64 = unsafe { code.as_fn() };
+    assert_eq!(mulhi(u64::MAX, 3), 2);
+
+    // quotient * 1000 + remainder, with the divisor arriving in rdx and the
+    // results wanted in swapped registers.
+    let div_rem = |signed: bool, wide: bool| {
+        let mut asm = X86Assembler::new();
+        let quotient = Edx as u8;
+        let remainder = Eax as u8;
+        if wide {
+            asm.div_remq(
+                signed,
+                Edi as _,
+                Esi as _,
+                Some(quotient),
+                Some(remainder),
+                Ecx as _,
+            );
+        } else {
+            asm.div_reml(
+                signed,
+                Edi as _,
+                Esi as _,
+                Some(quotient),
+                Some(remainder),
+                Ecx as _,
+            );
+        }
+        if !wide {
+            asm.movslq_rr(quotient, quotient);
+            asm.movslq_rr(remainder, remainder);
+        }
+        asm.imulq_ir(1000, quotient);
+        asm.addq_rr(quotient, remainder);
+        asm.ret();
+        asm.formatter.executable_readable()
+    };
+    for &(signed, wide) in [(true, true), (false, true), (true, false), (false, false)].iter() {
+        let code = div_rem(signed, wide);
+        let f: extern "C" fn(i64, i64) -> i64 = unsafe { code.as_fn() };
+        assert_eq!(f(47, 5), 9 * 1000 + 2);
+        if signed {
+            assert_eq!(f(-47, 5), -9 * 1000 - 2);
+        }
+    }
+    let mut asm = X86Assembler::new();
+    asm.div_remq(false, Edi as _, Edx as _, Some(Eax as _), None, Ecx as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(u64, u64, u64) -> u64 = unsafe { code.as_fn() };
+    assert_eq!(f(u64::MAX, 0, 2), u64::MAX / 2);
+
+    if is_x86_feature_detected!("bmi2") {
+        let mut asm = X86Assembler::new();
+        asm.movq_rr(Edi as _, Edx as _);
+        asm.mulxq_rr(Esi as _, Ecx as _, Eax as _);
+        asm.ret();
+        let code = asm.formatter.executable_readable();
+        let mulhi: extern "C" fn(u64, u64) -> u64 = unsafe { code.as_fn() };
+        assert_eq!(mulhi(u64::MAX, 3), 2);
+    }
+}
+
+#[test]
+fn test_atomic_encodings() {
+    use X86Gpr::*;
+    let mut asm = X86Assembler::new();
+    asm.lock_addl_rm(Esi as _, 8, Edi as _);
+    asm.lock_addl_im(1, 0, Edi as _);
+    asm.lock_addl_im(1000, 0, R12 as _);
+    asm.lock_addq_rm(R9 as _, -8, Ebp as _);
+    asm.lock_addq_im(-1, 0, Esp as _);
+    asm.lock_subl_rm(Eax as _, 0, Ebx as _);
+    asm.lock_subq_im(200, 16, R13 as _);
+    asm.lock_andl_rm(Ecx as _, 0, Edi as _);
+    asm.lock_andq_im(15, 0, Eax as _);
+    asm.lock_orl_im(16, 4, Edi as _);
+    asm.lock_orq_rm(Edx as _, 0, Esi as _);
+    asm.lock_xorl_rm(R8 as _, 0, Edi as _);
+    asm.lock_xorq_im(-256, 0, Edi as _);
+    asm.xchgb_rm(Esi as _, 0, Edi as _);
+    asm.xchgw_rm(Eax as _, 2, Edi as _);
+    asm.xchgl_rm(Ecx as _, 0, R9 as _);
+    asm.xchgl_rr(Eax as _, Esi as _);
+    asm.xchgq_rm(R10 as _, 0, Edi as _);
+    asm.xchgq_rr(R10 as _, Eax as _);
+    asm.lock_xaddb_rm(Eax as _, 0, Edi as _);
+    asm.lock_xaddw_rm(Ecx as _, 0, Edi as _);
+    asm.lock_xaddl_rm(Eax as _, 0, Edi as _);
+    asm.lock_xaddq_rm(R11 as _, 8, Edi as _);
+    asm.lock_cmpxchgb_rm(Esi as _, 0, Edi as _);
+    asm.lock_cmpxchgw_rm(R9 as _, 8, Esi as _);
+    asm.lock_cmpxchgl_rm(Edx as _, 0, Edi as _);
+    asm.lock_cmpxchgq_rm(Edx as _, 0, Edi as _);
+    asm.lock_cmpxchg8b_m(8, Edi as _);
+    asm.lock_cmpxchg16b_m(0, R8 as _);
+    asm.mfence();
+    asm.lfence();
+    asm.sfence();
+    asm.pause();
+    assert_eq!(
+        asm.code(),
+        &[
+            0xf0, 0x01, 0x77, 0x08, // lock add %esi, 8(%rdi)
+            0xf0, 0x83, 0x07, 0x01, // lock addl $1, (%rdi)
+            0xf0, 0x41, 0x81, 0x04, 0x24, 0xe8, 0x03, 0, 0, // lock addl $1000, (%r12)
+            0xf0, 0x4c, 0x01, 0x4d, 0xf8, // lock add %r9, -8(%rbp)
+            0xf0, 0x48, 0x83, 0x04, 0x24, 0xff, // lock addq $-1, (%rsp)
+            0xf0, 0x29, 0x03, // lock sub %eax, (%rbx)
+            0xf0, 0x49, 0x81, 0x6d, 0x10, 0xc8, 0, 0, 0, // lock subq $200, 16(%r13)
+            0xf0, 0x21, 0x0f, // lock and %ecx, (%rdi)
+            0xf0, 0x48, 0x83, 0x20, 0x0f, // lock andq $15, (%rax)
+            0xf0, 0x83, 0x4f, 0x04, 0x10, // lock orl $16, 4(%rdi)
+            0xf0, 0x48, 0x09, 0x16, // lock or %rdx, (%rsi)
+            0xf0, 0x44, 0x31, 0x07, // lock xor %r8d, (%rdi)
+            0xf0, 0x48, 0x81, 0x37, 0x00, 0xff, 0xff, 0xff, // lock xorq $-256, (%rdi)
+            0x40, 0x86, 0x37, // xchg %sil, (%rdi)
+            0x66, 0x87, 0x47, 0x02, // xchg %ax, 2(%rdi)
+            0x41, 0x87, 0x09, // xchg %ecx, (%r9)
+            0x87, 0xc6, // xchg %eax, %esi
+            0x4c, 0x87, 0x17, // xchg %r10, (%rdi)
+            0x4c, 0x87, 0xd0, // xchg %r10, %rax
+            0xf0, 0x0f, 0xc0, 0x07, // lock xadd %al, (%rdi)
+            0x66, 0xf0, 0x0f, 0xc1, 0x0f, // lock xadd %cx, (%rdi)
+            0xf0, 0x0f, 0xc1, 0x07, // lock xadd %eax, (%rdi)
+            0xf0, 0x4c, 0x0f, 0xc1, 0x5f, 0x08, // lock xadd %r11, 8(%rdi)
+            0xf0, 0x40, 0x0f, 0xb0, 0x37, // lock cmpxchg %sil, (%rdi)
+            0x66, 0xf0, 0x44, 0x0f, 0xb1, 0x4e, 0x08, // lock cmpxchg %r9w, 8(%rsi)
+            0xf0, 0x0f, 0xb1, 0x17, // lock cmpxchg %edx, (%rdi)
+            0xf0, 0x48, 0x0f, 0xb1, 0x17, // lock cmpxchg %rdx, (%rdi)
+            0xf0, 0x0f, 0xc7, 0x4f, 0x08, // lock cmpxchg8b 8(%rdi)
+            0xf0, 0x49, 0x0f, 0xc7, 0x08, // lock cmpxchg16b (%r8)
+            0x0f, 0xae, 0xf0, // mfence
+            0x0f, 0xae, 0xe8, // lfence
+            0x0f, 0xae, 0xf8, // sfence
+            0xf3, 0x90, // pause
+        ][..]
+    );
+}
+
+#[test]
+fn test_narrow_and_indexed_atomic_encodings() {
+    use X86Gpr::*;
+    let mut asm = X86Assembler::new();
+    asm.lock_addb_rm(Esi as _, 0, Edi as _);
+    asm.lock_addb_im(-1, 4, R8 as _);
+    asm.lock_andb_im(0x7f, 0, Edi as _);
+    asm.lock_xorb_rm_index(Eax as _, 8, Edi as _, Esi as _, 2);
+    asm.lock_orb_im_index(1, 0, R12 as _, R9 as _, 3);
+    asm.lock_subw_rm(Ecx as _, 2, Edi as _);
+    asm.lock_addw_im(1, 0, Edi as _);
+    asm.lock_andw_im(0x1234, 0, Edi as _);
+    asm.lock_xorw_im_index(-300, 0, Edi as _, Ecx as _, 1);
+    asm.lock_addl_rm_index(Esi as _, 0, Edi as _, Eax as _, 0);
+    asm.lock_subl_im_index(1000, -4, Ebp as _, Ebx as _, 2);
+    asm.lock_orq_rm_index(R10 as _, 0, Esp as _, Ecx as _, 3);
+    asm.lock_andq_im_index(-2, 16, Edi as _, R11 as _, 0);
+    asm.xchgb_rm_index(Edi as _, 0, Eax as _, Ebx as _, 0);
+    asm.xchgw_rm_index(Eax as _, 0, Edi as _, Esi as _, 1);
+    asm.xchgl_rm_index(Ecx as _, 0, Edi as _, R8 as _, 2);
+    asm.xchgq_rm_index(R9 as _, 8, Edi as _, Esi as _, 3);
+    asm.lock_xaddb_rm_index(Esi as _, 0, Edi as _, Eax as _, 0);
+    asm.lock_xaddw_rm_index(Eax as _, 0, Edi as _, Eax as _, 1);
+    asm.lock_xaddl_rm_index(Edx as _, 0, R13 as _, Eax as _, 2);
+    asm.lock_xaddq_rm_index(Eax as _, 0, Edi as _, Ecx as _, 3);
+    asm.lock_cmpxchgb_rm_index(Ecx as _, 0, Edi as _, Esi as _, 0);
+    asm.lock_cmpxchgw_rm_index(Edx as _, 0, Edi as _, Esi as _, 1);
+    asm.lock_cmpxchgl_rm_index(Ecx as _, 0, Edi as _, Esi as _, 2);
+    asm.lock_cmpxchgq_rm_index(R8 as _, 0, Edi as _, Esi as _, 3);
+    asm.lock_cmpxchg8b_m_index(0, Edi as _, Esi as _, 3);
+    asm.lock_cmpxchg16b_m_index(0, Edi as _, Esi as _, 0);
+    assert_eq!(
+        asm.code(),
+        &[
+            0xf0, 0x40, 0x00, 0x37, // lock add %sil, (%rdi)
+            0xf0, 0x41, 0x80, 0x40, 0x04, 0xff, // lock addb $-1, 4(%r8)
+            0xf0, 0x80, 0x27, 0x7f, // lock andb $0x7f, (%rdi)
+            0xf0, 0x30, 0x44, 0xb7, 0x08, // lock xor %al, 8(%rdi,%rsi,4)
+            0xf0, 0x43, 0x80, 0x0c, 0xcc, 0x01, // lock orb $1, (%r12,%r9,8)
+            0x66, 0xf0, 0x29, 0x4f, 0x02, // lock sub %cx, 2(%rdi)
+            0x66, 0xf0, 0x83, 0x07, 0x01, // lock addw $1, (%rdi)
+            0x66, 0xf0, 0x81, 0x27, 0x34, 0x12, // lock andw $0x1234, (%rdi)
+            0x66, 0xf0, 0x81, 0x34, 0x4f, 0xd4, 0xfe, // lock xorw $-300, (%rdi,%rcx,2)
+            0xf0, 0x01, 0x34, 0x07, // lock add %esi, (%rdi,%rax,1)
+            0xf0, 0x81, 0x6c, 0x9d, 0xfc, 0xe8, 0x03, 0,
+            0, // lock subl $1000, -4(%rbp,%rbx,4)
+            0xf0, 0x4c, 0x09, 0x14, 0xcc, // lock or %r10, (%rsp,%rcx,8)
+            0xf0, 0x4a, 0x83, 0x64, 0x1f, 0x10, 0xfe, // lock andq $-2, 16(%rdi,%r11,1)
+            0x40, 0x86, 0x3c, 0x18, // xchg %dil, (%rax,%rbx,1)
+            0x66, 0x87, 0x04, 0x77, // xchg %ax, (%rdi,%rsi,2)
+            0x42, 0x87, 0x0c, 0x87, // xchg %ecx, (%rdi,%r8,4)
+            0x4c, 0x87, 0x4c, 0xf7, 0x08, // xchg %r9, 8(%rdi,%rsi,8)
+            0xf0, 0x40, 0x0f, 0xc0, 0x34, 0x07, // lock xadd %sil, (%rdi,%rax,1)
+            0x66, 0xf0, 0x0f, 0xc1, 0x04, 0x47, // lock xadd %ax, (%rdi,%rax,2)
+            0xf0, 0x41, 0x0f, 0xc1, 0x54, 0x85, 0x00, // lock xadd %edx, 0(%r13,%rax,4)
+            0xf0, 0x48, 0x0f, 0xc1, 0x04, 0xcf, // lock xadd %rax, (%rdi,%rcx,8)
+            0xf0, 0x0f, 0xb0, 0x0c, 0x37, // lock cmpxchg %cl, (%rdi,%rsi,1)
+            0x66, 0xf0, 0x0f, 0xb1, 0x14, 0x77, // lock cmpxchg %dx, (%rdi,%rsi,2)
+            0xf0, 0x0f, 0xb1, 0x0c, 0xb7, // lock cmpxchg %ecx, (%rdi,%rsi,4)
+            0xf0, 0x4c, 0x0f, 0xb1, 0x04, 0xf7, // lock cmpxchg %r8, (%rdi,%rsi,8)
+            0xf0, 0x0f, 0xc7, 0x0c, 0xf7, // lock cmpxchg8b (%rdi,%rsi,8)
+            0xf0, 0x48, 0x0f, 0xc7, 0x0c, 0x37, // lock cmpxchg16b (%rdi,%rsi,1)
+        ][..]
+    );
+}
+
+#[test]
+fn test_atomics() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use X86Gpr::*;
+
+    // Compare-and-swap returning the old value.
+    let mut asm = X86Assembler::new();
+    asm.movq_rr(Esi as _, Eax as _);
+    asm.lock_cmpxchgq_rm(Edx as _, 0, Edi as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let cas: extern "C" fn(*mut u64, u64, u64) -> u64 = unsafe { code.as_fn() };
+    let mut word = 5u64;
+    assert_eq!(cas(&mut word, 5, 7), 5);
+    assert_eq!(word, 7);
+    assert_eq!(cas(&mut word, 5, 9), 7);
+    assert_eq!(word, 7);
+
+    // Fetch-and-add, hammered from several threads.
+    let mut asm = X86Assembler::new();
+    asm.movq_rr(Esi as _, Eax as _);
+    asm.lock_xaddq_rm(Eax as _, 0, Edi as _);
+    asm.pause();
+    asm.lock_addq_im(1, 8, Edi as _);
+    asm.mfence();
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let fetch_add: extern "C" fn(*const AtomicU64, u64) -> u64 = unsafe { code.as_fn() };
+    let counters = [AtomicU64::new(0), AtomicU64::new(0)];
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..10000 {
+                    fetch_add(&counters[0], 3);
+                }
+            });
+        }
+    });
+    assert_eq!(counters[0].load(Ordering::SeqCst), 4 * 10000 * 3);
+    assert_eq!(counters[1].load(Ordering::SeqCst), 4 * 10000);
+    assert_eq!(fetch_add(&counters[0], 1), 4 * 10000 * 3);
+}
+
+#[test]
+fn test_sse2_encodings() {
+    let mut asm = X86Assembler::new();
+    asm.addsd_rr(X86Fpr::XMM1 as _, X86Fpr::XMM0 as _);
+    asm.mulsd_rr(X86Fpr::XMM9 as _, X86Fpr::XMM2 as _);
+    asm.movsd_mr(8, X86Gpr::Esp as _, X86Fpr::XMM15 as _);
+    asm.movsd_rm(X86Fpr::XMM3 as _, -8, X86Gpr::Ebp as _);
+    asm.ucomisd_rr(X86Fpr::XMM1 as _, X86Fpr::XMM0 as _);
+    asm.cvtsi2sdq_rr(X86Gpr::R8 as _, X86Fpr::XMM0 as _);
+    asm.cvttsd2si_rr(X86Fpr::XMM10 as _, X86Gpr::Eax as _);
+    asm.movq_xr(X86Fpr::XMM0 as _, X86Gpr::Eax as _);
+    asm.movq_rx(X86Gpr::Eax as _, X86Fpr::XMM0 as _);
+    asm.psrlq_i8r(63, X86Fpr::XMM12 as _);
+    assert_eq!(
+        asm.code(),
+        &[
+            0xf2, 0x0f, 0x58, 0xc1, // addsd %xmm1, %xmm0
+            0xf2, 0x41, 0x0f, 0x59, 0xd1, // mulsd %xmm9, %xmm2
+            0xf2, 0x44, 0x0f, 0x10, 0x7c, 0x24, 0x08, // movsd 8(%rsp), %xmm15
+            0xf2, 0x0f, 0x11, 0x5d, 0xf8, // movsd %xmm3, -8(%rbp)
+            0x66, 0x0f, 0x2e, 0xc1, // ucomisd %xmm1, %xmm0
+            0xf2, 0x49, 0x0f, 0x2a, 0xc0, // cvtsi2sdq %r8, %xmm0
+            0xf2, 0x41, 0x0f, 0x2c, 0xc2, // cvttsd2si %xmm10, %eax
+            0x66, 0x48, 0x0f, 0x7e, 0xc0, // movq %xmm0, %rax
+            0x66, 0x48, 0x0f, 0x6e, 0xc0, // movq %rax, %xmm0
+            0x66, 0x41, 0x0f, 0x73, 0xd4, 0x3f, // psrlq $63, %xmm12
+        ][..]
+    );
+}
+
+#[test]
+fn test_jit_double_arithmetic() {
+    // (a + b) * a / sqrt(b) + (double)n
+    let mut asm = X86Assembler::new();
+    asm.movsd_rr(X86Fpr::XMM0 as _, X86Fpr::XMM2 as _);
+    asm.addsd_rr(X86Fpr::XMM1 as _, X86Fpr::XMM2 as _);
+    asm.mulsd_rr(X86Fpr::XMM0 as _, X86Fpr::XMM2 as _);
+    asm.sqrtsd_rr(X86Fpr::XMM1 as _, X86Fpr::XMM3 as _);
+    asm.divsd_rr(X86Fpr::XMM3 as _, X86Fpr::XMM2 as _);
+    asm.cvtsi2sdq_rr(X86Gpr::Edi as _, X86Fpr::XMM4 as _);
+    asm.addsd_rr(X86Fpr::XMM4 as _, X86Fpr::XMM2 as _);
+    asm.movsd_rr(X86Fpr::XMM2 as _, X86Fpr::XMM0 as _);
+    asm.ret();
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(f64, f64, i64) -> f64 = unsafe { code.as_fn() };
+    assert_eq!(f(2.0, 4.0, 7), 13.0);
+}
@@ -0,0 +1,650 @@
+//! Typed front-end over the byte-level `X86Assembler` methods.
+//!
+//! Register classes and immediate widths are checked by the type system: every
+//! mnemonic is a trait implemented only for the operand combinations the
+//! instruction accepts, so passing an XMM register where a GPR is expected does
+//! not compile. Conversions from raw register numbers and indexed memory
+//! operands are checked at runtime and return `OperandError`.
+//!
+//! Operands follow the byte-level AT&T order: `asm.movq(src, dst)`.
+use crate::assembler::PcRelTarget;
+use crate::x86assembler::*;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OperandError {
+    /// The number does not name a register of the requested class.
+    InvalidRegister(u8),
+    /// `%esp`/`%rsp` cannot be used as an index register.
+    StackPointerIndex,
+}
+
+impl fmt::Display for OperandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperandError::InvalidRegister(r) => write!(f, "invalid register number {}", r),
+            OperandError::StackPointerIndex => write!(f, "stack pointer used as index register"),
+        }
+    }
+}
+
+impl std::error::Error for OperandError {}
+
+#[cfg(target_arch = "x86_64")]
+const REGISTER_COUNT: u8 = 16;
+#[cfg(target_arch = "x86")]
+const REGISTER_COUNT: u8 = 8;
+
+impl TryFrom<u8> for X86Gpr {
+    type Error = OperandError;
+    fn try_from(r: u8) -> Result<Self, OperandError> {
+        if r >= REGISTER_COUNT {
+            return Err(OperandError::InvalidRegister(r));
+        }
+        Ok(unsafe { std::mem::transmute::<u8, X86Gpr>(r) })
+    }
+}
+
+impl TryFrom<u8> for X86Fpr {
+    type Error = OperandError;
+    fn try_from(r: u8) -> Result<Self, OperandError> {
+        if r >= REGISTER_COUNT {
+            return Err(OperandError::InvalidRegister(r));
+        }
+        Ok(unsafe { std::mem::transmute::<u8, X86Fpr>(r) })
+    }
+}
+
+/// An immediate; its width is the width of `T`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Imm<T>(pub T);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Scale {
+    Times1,
+    Times2,
+    Times4,
+    Times8,
+}
+
+/// `[base + index * scale + disp]`, or `[rip + disp32]` addressing a target
+/// plus `disp`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Mem {
+    base: X86Gpr,
+    index: Option<X86Gpr>,
+    scale: Scale,
+    disp: i32,
+    rip: Option<PcRelTarget>,
+}
+
+impl Mem {
+    /// `[base + disp]`.
+    pub const fn new(base: X86Gpr, disp: i32) -> Self {
+        Self {
+            base,
+            index: None,
+            scale: Scale::Times1,
+            disp,
+            rip: None,
+        }
+    }
+
+    /// `[rip + disp32]` addressing `target + disp`, see
+    /// `X86Assembler::rip_relative`.
+    #[cfg(target_arch = "x86_64")]
+    pub const fn rip(target: PcRelTarget, disp: i32) -> Self {
+        Self {
+            base: X86Gpr::Eax,
+            index: None,
+            scale: Scale::Times1,
+            disp,
+            rip: Some(target),
+        }
+    }
+
+    pub fn indexed(
+        base: X86Gpr,
+        index: X86Gpr,
+        scale: Scale,
+        disp: i32,
+    ) -> Result<Self, OperandError> {
+        if index == X86Gpr::Esp {
+            return Err(OperandError::StackPointerIndex);
+        }
+        Ok(Self {
+            base,
+            index: Some(index),
+            scale,
+            disp,
+            rip: None,
+        })
+    }
+
+    pub fn base(&self) -> X86Gpr {
+        self.base
+    }
+
+    pub fn index(&self) -> Option<X86Gpr> {
+        self.index
+    }
+
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    pub fn disp(&self) -> i32 {
+        self.disp
+    }
+
+    pub fn rip_target(&self) -> Option<PcRelTarget> {
+        self.rip
+    }
+
+    /// The base register to encode, `RIP` for a RIP-relative operand.
+    fn base_reg(&self) -> u8 {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.rip.is_some() {
+                return RIP;
+            }
+        }
+        self.base as u8
+    }
+}
+
+/// Lets the mnemonic macros route instructions with a RIP-relative operand
+/// through `X86Assembler::rip_relative`.
+trait Operand: Copy {
+    fn rip_target(&self) -> Option<PcRelTarget> {
+        None
+    }
+
+    /// The operand with the offset `rip_relative` hands out added to a
+    /// RIP-relative memory operand; its base encodes as `RIP`.
+    fn rebased(self, _offset: i32) -> Self {
+        self
+    }
+}
+
+impl Operand for X86Gpr {}
+impl Operand for X86Fpr {}
+impl<T: Copy> Operand for Imm<T> {}
+
+impl Operand for Mem {
+    fn rip_target(&self) -> Option<PcRelTarget> {
+        self.rip
+    }
+
+    fn rebased(self, offset: i32) -> Self {
+        match self.rip {
+            Some(_) => Self {
+                disp: self.disp + offset,
+                ..self
+            },
+            None => self,
+        }
+    }
+}
+
+fn mem_op(asm: &mut X86Assembler, op: u8, reg: u8, m: Mem) {
+    match m.index {
+        None => asm.formatter.one_byte_op_3(op, reg, m.base_reg(), m.disp),
+        Some(index) => {
+            asm.formatter
+                .one_byte_op_4(op, reg, m.base_reg(), index as _, m.scale as _, m.disp)
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn mem_op64(asm: &mut X86Assembler, op: u8, reg: u8, m: Mem) {
+    match m.index {
+        None => asm.formatter.one_byte_op64_3(op, reg, m.base_reg(), m.disp),
+        Some(index) => {
+            asm.formatter
+                .one_byte_op64_4(op, reg, m.base_reg(), index as _, m.scale as _, m.disp)
+        }
+    }
+}
+
+fn two_byte_mem_op(asm: &mut X86Assembler, op: u8, reg: u8, m: Mem) {
+    match m.index {
+        None => asm.formatter.two_byte_op_3(op, reg, m.base_reg(), m.disp),
+        Some(index) => {
+            asm.formatter
+                .two_byte_op_4(op, reg, m.base_reg(), index as _, m.scale as _, m.disp)
+        }
+    }
+}
+
+/// Declares a two-operand mnemonic trait and its implementations on `X86Assembler`.
+macro_rules! typed {
+    ($(#[$attr: meta])* $tr: ident :: $name: ident {
+        $($(#[$iattr: meta])* ($s: ident: $sty: ty, $d: ident: $dty: ty) => |$asm: ident| $body: expr;)*
+    }) => {
+        $(#[$attr])*
+        pub trait $tr<S, D> {
+            fn $name(&mut self, src: S, dst: D);
+        }
+        $(
+            $(#[$iattr])*
+            impl $tr<$sty, $dty> for X86Assembler {
+                fn $name(&mut self, $s: $sty, $d: $dty) {
+                    #[cfg(target_arch = "x86_64")]
+                    {
+                        if let Some(target) = $s.rip_target().or_else(|| $d.rip_target()) {
+                            return self.rip_relative(target, |$asm, offset, _| {
+                                let $s = $s.rebased(offset);
+                                let $d = $d.rebased(offset);
+                                $body
+                            });
+                        }
+                    }
+                    let $asm = self;
+                    $body
+                }
+            }
+        )*
+    };
+}
+
+/// Declares a one-operand mnemonic trait and its implementations on `X86Assembler`.
+macro_rules! typed1 {
+    ($(#[$attr: meta])* $tr: ident :: $name: ident {
+        $($(#[$iattr: meta])* ($o: ident: $oty: ty) => |$asm: ident| $body: expr;)*
+    }) => {
+        $(#[$attr])*
+        pub trait $tr<O> {
+            fn $name(&mut self, op: O);
+        }
+        $(
+            $(#[$iattr])*
+            impl $tr<$oty> for X86Assembler {
+                fn $name(&mut self, $o: $oty) {
+                    #[cfg(target_arch = "x86_64")]
+                    {
+                        if let Some(target) = $o.rip_target() {
+                            return self.rip_relative(target, |$asm, offset, _| {
+                                let $o = $o.rebased(offset);
+                                $body
+                            });
+                        }
+                    }
+                    let $asm = self;
+                    $body
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! mem_dispatch {
+    ($asm: ident, $m: ident, $base: ident ($($pre: expr),*), $index: ident ($($ipre: expr),*)) => {
+        match $m.index {
+            None => $asm.$base($($pre,)* $m.disp, $m.base_reg()),
+            Some(index) => $asm.$index($($ipre,)* $m.disp, $m.base_reg(), index as _, $m.scale as _),
+        }
+    };
+}
+
+typed! { Movb::movb {
+    (src: X86Gpr, dst: X86Gpr) => |asm| asm.movb_rr(src as _, dst as _);
+    (src: X86Gpr, dst: Mem) => |asm| mem_dispatch!(asm, dst, movb_rm(src as _), movb_rm_index(src as _));
+    (src: Mem, dst: X86Gpr) => |asm| match src.index {
+        None => asm.movb_mr(src.disp, src.base_reg(), dst as _),
+        Some(index) => asm.movb_mr_index(src.disp, src.base_reg(), index as _, src.scale as _, dst as _),
+    };
+    (src: Imm<i8>, dst: X86Gpr) => |asm| asm.movb_i8r(src.0, dst as _);
+    (src: Imm<i8>, dst: Mem) => |asm| mem_dispatch!(asm, dst, movb_i8m(src.0), movb_i8m_index(src.0));
+}}
+
+typed! { Movw::movw {
+    (src: X86Gpr, dst: X86Gpr) => |asm| asm.movw_rr(src as _, dst as _);
+    (src: X86Gpr, dst: Mem) => |asm| mem_dispatch!(asm, dst, movw_rm(src as _), movw_rm_index(src as _));
+    (src: Mem, dst: X86Gpr) => |asm| match src.index {
+        None => asm.movw_mr(src.disp, src.base_reg(), dst as _),
+        Some(index) => asm.movw_mr_index(src.disp, src.base_reg(), index as _, src.scale as _, dst as _),
+    };
+    (src: Imm<i16>, dst: X86Gpr) => |asm| asm.movw_i16r(src.0, dst as _);
+    (src: Imm<i16>, dst: Mem) => |asm| mem_dispatch!(asm, dst, movw_i16m(src.0), movw_i16m_index(src.0));
+}}
+
+typed! { Movl::movl {
+    (src: X86Gpr, dst: X86Gpr) => |asm| asm.movl_rr(src as _, dst as _);
+    (src: X86Gpr, dst: Mem) => |asm| mem_dispatch!(asm, dst, movl_rm(src as _), movl_rm_index(src as _));
+    (src: Mem, dst: X86Gpr) => |asm| match src.index {
+        None => asm.movl_mr(src.disp, src.base_reg(), dst as _),
+        Some(index) => asm.movl_mr_index(src.disp, src.base_reg(), index as _, src.scale as _, dst as _),
+    };
+    (src: Imm<i32>, dst: X86Gpr) => |asm| asm.movl_i32r(src.0, dst as _);
+    (src: Imm<i32>, dst: Mem) => |asm| mem_dispatch!(asm, dst, movl_i32m(src.0), movl_i32m_index(src.0));
+}}
+
+#[cfg(target_arch = "x86_64")]
+typed! { Movq::movq {
+    (src: X86Gpr, dst: X86Gpr) => |asm| asm.movq_rr(src as _, dst as _);
+    (src: X86Gpr, dst: Mem) => |asm| mem_dispatch!(asm, dst, movq_rm(src as _), movq_rm_index(src as _));
+    (src: Mem, dst: X86Gpr) => |asm| match src.index {
+        None => asm.movq_mr(src.disp, src.base_reg(), dst as _),
+        Some(index) => asm.movq_mr_index(src.disp, src.base_reg(), index as _, src.scale as _, dst as _),
+    };
+    (src: Imm<i64>, dst: X86Gpr) => |asm| asm.movq_i64r(src.0, dst as _);
+    (src: Imm<i32>, dst: Mem) => |asm| mem_dispatch!(asm, dst, movq_i32m(src.0), movq_i32m_index(src.0));
+    (src: X86Fpr, dst: X86Gpr) => |asm| asm.movq_xr(src as _, dst as _);
+    (src: X86Gpr, dst: X86Fpr) => |asm| asm.movq_rx(src as _, dst as _);
+}}
+
+/// Declares a widening load trait from its byte-level `_rr`, `_mr` and
+/// `_mr_index` emitters.
+macro_rules! widen {
+    ($tr: ident :: $name: ident, $rr: ident, $mr: ident, $mr_index: ident) => {
+        typed! { $tr::$name {
+            (src: X86Gpr, dst: X86Gpr) => |asm| asm.$rr(src as _, dst as _);
+            (src: Mem, dst: X86Gpr) => |asm| match src.index {
+                None => asm.$mr(src.disp, src.base_reg(), dst as _),
+                Some(index) => asm.$mr_index(src.disp, src.base_reg(), index as _, src.scale as _, dst as _),
+            };
+        }}
+    };
+}
+
+widen!(Movzbl::movzbl, movzbl_rr, movzbl_mr, movzbl_mr_index);
+widen!(Movzwl::movzwl, movzwl_rr, movzwl_mr, movzwl_mr_index);
+widen!(Movsbl::movsbl, movsbl_rr, movsbl_mr, movsbl_mr_index);
+widen!(Movswl::movswl, movswl_rr, movswl_mr, movswl_mr_index);
+#[cfg(target_arch = "x86_64")]
+widen!(Movsbq::movsbq, movsbq_rr, movsbq_mr, movsbq_mr_index);
+#[cfg(target_arch = "x86_64")]
+widen!(Movswq::movswq, movswq_rr, movswq_mr, movswq_mr_index);
+#[cfg(target_arch = "x86_64")]
+widen!(Movslq::movslq, movslq_rr, movslq_mr, movslq_mr_index);
+
+typed! { Leal::leal {
+    (src: Mem, dst: X86Gpr) => |asm| mem_op(asm, OP_LEA, dst as _, src);
+}}
+
+#[cfg(target_arch = "x86_64")]
+typed! { Leaq::leaq {
+    (src: Mem, dst: X86Gpr) => |asm| mem_op64(asm, OP_LEA, dst as _, src);
+}}
+
+/// Declares the 32- and 64-bit traits of a group 1 ALU instruction.
+macro_rules! alu {
+    ($tr32: ident :: $name32: ident, $tr64: ident :: $name64: ident, $evgv: ident, $gvev: ident, $group: ident) => {
+        typed! { $tr32::$name32 {
+            (src: X86Gpr, dst: X86Gpr) => |asm| asm.formatter.one_byte_op_6($evgv, src as _, dst as _);
+            (src: Mem, dst: X86Gpr) => |asm| mem_op(asm, $gvev, dst as _, src);
+            (src: X86Gpr, dst: Mem) => |asm| mem_op(asm, $evgv, src as _, dst);
+            (src: Imm<i32>, dst: X86Gpr) => |asm| if can_sign_extend(src.0) {
+                asm.formatter.one_byte_op_6(OP_GROUP1_EvIb, $group, dst as _);
+                asm.formatter.imm8(src.0 as _);
+            } else {
+                asm.formatter.one_byte_op_6(OP_GROUP1_EvIz, $group, dst as _);
+                asm.formatter.imm32(src.0);
+            };
+            (src: Imm<i32>, dst: Mem) => |asm| if can_sign_extend(src.0) {
+                mem_op(asm, OP_GROUP1_EvIb, $group, dst);
+                asm.formatter.imm8(src.0 as _);
+            } else {
+                mem_op(asm, OP_GROUP1_EvIz, $group, dst);
+                asm.formatter.imm32(src.0);
+            };
+        }}
+
+        #[cfg(target_arch = "x86_64")]
+        typed! { $tr64::$name64 {
+            (src: X86Gpr, dst: X86Gpr) => |asm| asm.formatter.one_byte_op64_2($evgv, src as _, dst as _);
+            (src: Mem, dst: X86Gpr) => |asm| mem_op64(asm, $gvev, dst as _, src);
+            (src: X86Gpr, dst: Mem) => |asm| mem_op64(asm, $evgv, src as _, dst);
+            (src: Imm<i32>, dst: X86Gpr) => |asm| if can_sign_extend(src.0) {
+                asm.formatter.one_byte_op64_2(OP_GROUP1_EvIb, $group, dst as _);
+                asm.formatter.imm8(src.0 as _);
+            } else {
+                asm.formatter.one_byte_op64_2(OP_GROUP1_EvIz, $group, dst as _);
+                asm.formatter.imm32(src.0);
+            };
+            (src: Imm<i32>, dst: Mem) => |asm| if can_sign_extend(src.0) {
+                mem_op64(asm, OP_GROUP1_EvIb, $group, dst);
+                asm.formatter.imm8(src.0 as _);
+            } else {
+                mem_op64(asm, OP_GROUP1_EvIz, $group, dst);
+                asm.formatter.imm32(src.0);
+            };
+        }}
+    };
+}
+
+alu!(
+    Addl::addl,
+    Addq::addq,
+    OP_ADD_EvGv,
+    OP_ADD_GvEv,
+    GROUP1_OP_ADD
+);
+alu!(
+    Subl::subl,
+    Subq::subq,
+    OP_SUB_EvGv,
+    OP_SUB_GvEv,
+    GROUP1_OP_SUB
+);
+alu!(
+    Andl::andl,
+    Andq::andq,
+    OP_AND_EvGv,
+    OP_AND_GvEv,
+    GROUP1_OP_AND
+);
+alu!(Orl::orl, Orq::orq, OP_OR_EvGv, OP_OR_GvEv, GROUP1_OP_OR);
+alu!(
+    Xorl::xorl,
+    Xorq::xorq,
+    OP_XOR_EvGv,
+    OP_XOR_GvEv,
+    GROUP1_OP_XOR
+);
+alu!(
+    Cmpl::cmpl,
+    Cmpq::cmpq,
+    OP_CMP_EvGv,
+    OP_CMP_GvEv,
+    GROUP1_OP_CMP
+);
+
+typed1! { Negl::negl {
+    (dst: X86Gpr) => |asm| asm.negl_r(dst as _);
+    (dst: Mem) => |asm| mem_op(asm, OP_GROUP3_Ev, GROUP3_OP_NEG, dst);
+}}
+
+typed1! { Notl::notl {
+    (dst: X86Gpr) => |asm| asm.notl_r(dst as _);
+    (dst: Mem) => |asm| mem_op(asm, OP_GROUP3_Ev, GROUP3_OP_NOT, dst);
+}}
+
+#[cfg(target_arch = "x86_64")]
+typed1! { Negq::negq {
+    (dst: X86Gpr) => |asm| asm.negq_r(dst as _);
+    (dst: Mem) => |asm| mem_op64(asm, OP_GROUP3_Ev, GROUP3_OP_NEG, dst);
+}}
+
+#[cfg(target_arch = "x86_64")]
+typed1! { Notq::notq {
+    (dst: X86Gpr) => |asm| asm.formatter.one_byte_op64_2(OP_GROUP3_Ev, GROUP3_OP_NOT, dst as _);
+    (dst: Mem) => |asm| mem_op64(asm, OP_GROUP3_Ev, GROUP3_OP_NOT, dst);
+}}
+
+typed1! { Push::push {
+    (src: X86Gpr) => |asm| asm.push_r(src as _);
+    (src: Mem) => |asm| mem_op(asm, OP_GROUP5_Ev, GROUP5_OP_PUSH, src);
+    (src: Imm<i32>) => |asm| asm.push_i32(src.0);
+}}
+
+typed1! { Pop::pop {
+    (dst: X86Gpr) => |asm| asm.pop_r(dst as _);
+    (dst: Mem) => |asm| mem_op(asm, OP_GROUP1A_Ev, GROUP1A_OP_POP, dst);
+}}
+
+typed1! {
+    /// Indirect jump; use `X86Assembler::jmp` for a linkable rel32 jump.
+    JmpIndirect::jmp_to {
+    (dst: X86Gpr) => |asm| asm.jmp_r(dst as _);
+    (dst: Mem) => |asm| mem_op(asm, OP_GROUP5_Ev, GROUP5_OP_JMPN, dst);
+}}
+
+typed1! {
+    /// Indirect call; use `X86Assembler::call` for a linkable rel32 call.
+    CallIndirect::call_to {
+    (dst: X86Gpr) => |asm| asm.call_r(dst as _);
+    (dst: Mem) => |asm| mem_op(asm, OP_GROUP5_Ev, GROUP5_OP_CALLN, dst);
+}}
+
+/// Declares a scalar SSE2 trait with register and memory source forms.
+macro_rules! sse {
+    ($tr: ident :: $name: ident, $prefix: ident, $op: ident) => {
+        typed! { $tr::$name {
+            (src: X86Fpr, dst: X86Fpr) => |asm| {
+                asm.formatter.prefix($prefix);
+                asm.formatter.two_byte_op_6($op, dst as _, src as _);
+            };
+            (src: Mem, dst: X86Fpr) => |asm| {
+                asm.formatter.prefix($prefix);
+                two_byte_mem_op(asm, $op, dst as _, src);
+            };
+        }}
+    };
+}
+
+sse!(Addsd::addsd, PRE_SSE_F2, OP2_ADDSD_VsdWsd);
+sse!(Subsd::subsd, PRE_SSE_F2, OP2_SUBSD_VsdWsd);
+sse!(Mulsd::mulsd, PRE_SSE_F2, OP2_MULSD_VsdWsd);
+sse!(Divsd::divsd, PRE_SSE_F2, OP2_DIVSD_VsdWsd);
+sse!(Sqrtsd::sqrtsd, PRE_SSE_F2, OP2_SQRTSD_VsdWsd);
+sse!(Ucomisd::ucomisd, PRE_SSE_66, OP2_UCOMISD_VsdWsd);
+sse!(Xorpd::xorpd, PRE_SSE_66, OP2_XORPD_VpdWpd);
+sse!(Andnpd::andnpd, PRE_SSE_66, OP2_ANDNPD_VpdWpd);
+
+typed! { Movsd::movsd {
+    (src: X86Fpr, dst: X86Fpr) => |asm| asm.movsd_rr(src as _, dst as _);
+    (src: Mem, dst: X86Fpr) => |asm| match src.index {
+        None => asm.movsd_mr(src.disp, src.base_reg(), dst as _),
+        Some(index) => asm.movsd_mr_index(src.disp, src.base_reg(), index as _, src.scale as _, dst as _),
+    };
+    (src: X86Fpr, dst: Mem) => |asm| mem_dispatch!(asm, dst, movsd_rm(src as _), movsd_rm_index(src as _));
+}}
+
+typed! { Cvtsi2sd::cvtsi2sd {
+    (src: X86Gpr, dst: X86Fpr) => |asm| asm.cvtsi2sd_rr(src as _, dst as _);
+    (src: Mem, dst: X86Fpr) => |asm| {
+        asm.formatter.prefix(PRE_SSE_F2);
+        two_byte_mem_op(asm, OP2_CVTSI2SD_VsdEd, dst as _, src);
+    };
+}}
+
+#[cfg(target_arch = "x86_64")]
+typed! { Cvtsi2sdq::cvtsi2sdq {
+    (src: X86Gpr, dst: X86Fpr) => |asm| asm.cvtsi2sdq_rr(src as _, dst as _);
+}}
+
+typed! { Cvttsd2si::cvttsd2si {
+    (src: X86Fpr, dst: X86Gpr) => |asm| asm.cvttsd2si_rr(src as _, dst as _);
+}}
+
+#[cfg(target_arch = "x86_64")]
+typed! { Cvttsd2siq::cvttsd2siq {
+    (src: X86Fpr, dst: X86Gpr) => |asm| asm.cvttsd2siq_rr(src as _, dst as _);
+}}
+
+typed! { Movd::movd {
+    (src: X86Fpr, dst: X86Gpr) => |asm| asm.movd_xr(src as _, dst as _);
+    (src: X86Gpr, dst: X86Fpr) => |asm| asm.movd_rx(src as _, dst as _);
+}}
+
+#[test]
+fn test_typed_matches_byte_level() {
+    let mut typed = X86Assembler::new();
+    typed.movq(X86Gpr::Esi, X86Gpr::Eax);
+    typed.movl(Mem::new(X86Gpr::R12, 8), X86Gpr::Ecx);
+    typed.movq(
+        X86Gpr::Edx,
+        Mem::indexed(X86Gpr::Edi, X86Gpr::R9, Scale::Times8, -16).unwrap(),
+    );
+    typed.movq(Imm(-1), X86Gpr::R11);
+    typed.addq(Imm(1000), X86Gpr::Eax);
+    typed.subl(Imm(1), X86Gpr::Edi);
+    typed.addsd(X86Fpr::XMM9, X86Fpr::XMM0);
+    typed.movsd(Mem::new(X86Gpr::Esp, 0), X86Fpr::XMM1);
+    typed.leal(
+        Mem::indexed(X86Gpr::Ebx, X86Gpr::Ecx, Scale::Times2, 4).unwrap(),
+        X86Gpr::Edx,
+    );
+    typed.movzbl(X86Gpr::Esi, X86Gpr::Eax);
+    typed.movslq(
+        Mem::indexed(X86Gpr::Edi, X86Gpr::Esi, Scale::Times4, 0).unwrap(),
+        X86Gpr::Eax,
+    );
+
+    let mut raw = X86Assembler::new();
+    raw.movq_rr(X86Gpr::Esi as _, X86Gpr::Eax as _);
+    raw.movl_mr(8, X86Gpr::R12 as _, X86Gpr::Ecx as _);
+    raw.movq_rm_index(X86Gpr::Edx as _, -16, X86Gpr::Edi as _, X86Gpr::R9 as _, 3);
+    raw.movq_i64r(-1, X86Gpr::R11 as _);
+    raw.addq_ir(1000, X86Gpr::Eax as _);
+    raw.subl_ir(1, X86Gpr::Edi as _);
+    raw.addsd_rr(X86Fpr::XMM9 as _, X86Fpr::XMM0 as _);
+    raw.movsd_mr(0, X86Gpr::Esp as _, X86Fpr::XMM1 as _);
+    raw.leal_mr_index(4, X86Gpr::Ebx as _, X86Gpr::Ecx as _, 1, X86Gpr::Edx as _);
+    raw.movzbl_rr(X86Gpr::Esi as _, X86Gpr::Eax as _);
+    raw.movslq_mr_index(0, X86Gpr::Edi as _, X86Gpr::Esi as _, 2, X86Gpr::Eax as _);
+
+    assert_eq!(typed.code(), raw.code());
+}
+
+#[test]
+fn test_typed_rip_relative() {
+    use crate::assembler::AssemblerLabel;
+
+    let table = PcRelTarget::Label(AssemblerLabel::new(64));
+    let mut typed = X86Assembler::new();
+    typed.movq(Mem::rip(table, 8), X86Gpr::R10);
+    typed.cmpl(Imm(1000), Mem::rip(table, 0));
+    typed.push(Mem::rip(table, 16));
+    typed.movsd(X86Fpr::XMM3, Mem::rip(table, 24));
+
+    let mut raw = X86Assembler::new();
+    raw.rip_relative(table, |asm, offset, base| {
+        asm.movq_mr(offset + 8, base, X86Gpr::R10 as _)
+    });
+    raw.rip_relative(table, |asm, offset, base| {
+        asm.formatter
+            .one_byte_op_3(OP_GROUP1_EvIz, GROUP1_OP_CMP, base, offset);
+        asm.formatter.imm32(1000);
+    });
+    raw.rip_relative(table, |asm, offset, base| {
+        asm.formatter
+            .one_byte_op_3(OP_GROUP5_Ev, GROUP5_OP_PUSH, base, offset + 16)
+    });
+    raw.rip_relative(table, |asm, offset, base| {
+        asm.movsd_rm(X86Fpr::XMM3 as _, offset + 24, base)
+    });
+
+    typed.leaq(Mem::rip(table, 0), X86Gpr::Eax);
+    raw.leaq_rip(table, X86Gpr::Eax as _);
+
+    assert_eq!(typed.code(), raw.code());
+    // movq 8+table(%rip), %r10 with the displacement still unresolved.
+    assert_eq!(&typed.code()[..7], &[0x4c, 0x8b, 0x15, 8, 0, 0, 0][..]);
+}
+
+#[test]
+fn test_operand_validation() {
+    assert_eq!(X86Gpr::try_from(9), Ok(X86Gpr::R9));
+    assert_eq!(X86Fpr::try_from(3), Ok(X86Fpr::XMM3));
+    assert_eq!(X86Gpr::try_from(16), Err(OperandError::InvalidRegister(16)));
+    assert_eq!(
+        Mem::indexed(X86Gpr::Eax, X86Gpr::Esp, Scale::Times1, 0),
+        Err(OperandError::StackPointerIndex)
+    );
+}
@@ -0,0 +1,638 @@
+//! Runtime patching of finalized code, e.g. for inline caches.
+//!
+//! Sites are named by the `AssemblerLabel`s the assembler returned while
+//! emitting. Following the assembler's convention a label for a rel32, imm32
+//! or imm64 field points at the end of that field, while labels passed to the
+//! `replace_with_*` and `revert_jump_to_*` methods point at the start of the
+//! instruction. Every method checks that the bytes at the site encode the
+//! instruction it expects before rewriting anything, and publishes the change
+//! with `flush_code` so that other threads running the code pick it up.
+//!
+//! Patching running code is only safe when the bytes can be stored without
+//! changing page protection: the code must be dual-mapped or RWX. Patches to
+//! W^X code without a writable alias fail with `PatchError::ProtectionFlip`,
+//! unless the patcher was created with the unsafe `new_quiescent`. A field
+//! rewritten under running threads must also lie within one aligned 8-byte
+//! word, so that it is replaced with a single store; otherwise the patch
+//! fails with `PatchError::Misaligned`.
+use crate::assembler::{AssemblerLabel, ExecutableCode};
+use crate::mem::{self, flush_code, try_write_code, JitProtection};
+use crate::x86assembler::*;
+use std::fmt;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PatchError {
+    /// The site does not lie inside the code.
+    OutOfBounds(AssemblerLabel),
+    /// The bytes at the site do not encode the expected instruction.
+    UnexpectedInstruction {
+        at: AssemblerLabel,
+        expected: &'static str,
+    },
+    /// The new target is not reachable with a rel32 displacement.
+    OutOfRange,
+    /// The site cannot be overwritten with a single store.
+    Misaligned(AssemblerLabel),
+    /// The code is W^X without a writable alias, so patching it would flip
+    /// its pages to RW under threads that may be running it.
+    ProtectionFlip(AssemblerLabel),
+    Memory(mem::Error),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::OutOfBounds(at) => write!(f, "patch site {} out of bounds", at.offset),
+            PatchError::UnexpectedInstruction { at, expected } => {
+                write!(f, "expected {} at offset {}", expected, at.offset)
+            }
+            PatchError::OutOfRange => write!(f, "target out of rel32 range"),
+            PatchError::Misaligned(at) => write!(f, "patch site {} is misaligned", at.offset),
+            PatchError::ProtectionFlip(at) => write!(
+                f,
+                "patch site {} is neither dual-mapped nor writable and executable",
+                at.offset
+            ),
+            PatchError::Memory(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl From<mem::Error> for PatchError {
+    fn from(err: mem::Error) -> Self {
+        PatchError::Memory(err)
+    }
+}
+
+/// Rewrites instructions inside a block of finalized code.
+pub struct CodePatcher {
+    base: *mut u8,
+    len: usize,
+    protection: JitProtection,
+    /// Set when no thread runs the code, so W^X pages may be flipped.
+    quiescent: bool,
+}
+
+impl CodePatcher {
+    pub fn new(code: &ExecutableCode) -> Self {
+        Self {
+            base: code.as_mut_ptr(),
+            len: code.len(),
+            protection: code.protection(),
+            quiescent: false,
+        }
+    }
+
+    /// Like `new`, but also patches W^X code without a writable alias by
+    /// flipping its pages, e.g. before the code is published.
+    ///
+    /// # Safety
+    /// No thread may execute code on the pages of `code` while the patcher is
+    /// in use.
+    pub unsafe fn new_quiescent(code: &ExecutableCode) -> Self {
+        Self {
+            quiescent: true,
+            ..Self::new(code)
+        }
+    }
+
+    /// # Safety
+    /// `base..base + len` must be finalized code, protected as `protection`
+    /// describes, that stays mapped while the patcher is in use.
+    pub unsafe fn from_raw(base: *mut u8, len: usize, protection: JitProtection) -> Self {
+        Self {
+            base,
+            len,
+            protection,
+            quiescent: false,
+        }
+    }
+
+    pub fn address(&self, at: AssemblerLabel) -> *mut u8 {
+        assert!(at.is_set() && at.offset as usize <= self.len);
+        unsafe { self.base.add(at.offset as usize) }
+    }
+
+    /// Returns the `len` bytes ending at `end`, or starting at `end - len`.
+    fn bytes_before(&self, end: AssemblerLabel, len: usize) -> Result<&[u8], PatchError> {
+        let end_offset = end.offset as usize;
+        if !end.is_set() || end_offset > self.len || end_offset < len {
+            return Err(PatchError::OutOfBounds(end));
+        }
+        Ok(unsafe { std::slice::from_raw_parts(self.base.add(end_offset - len), len) })
+    }
+
+    fn bytes_at(&self, start: AssemblerLabel, len: usize) -> Result<&[u8], PatchError> {
+        self.bytes_before(start.label_at_offset(len as u32), len)
+            .map_err(|_| PatchError::OutOfBounds(start))
+    }
+
+    /// Writes `bytes` at `offset` for the patch of `site`, which errors refer
+    /// to. Fails instead of flipping the protection of W^X pages that may be
+    /// running, or of storing bytes that span two 8-byte words, which running
+    /// threads could see half-written.
+    fn write(&self, site: AssemblerLabel, offset: usize, bytes: &[u8]) -> Result<(), PatchError> {
+        let dst = unsafe { self.base.add(offset) };
+        if self.protection == JitProtection::WriteXorExecute
+            && !self.quiescent
+            && mem::writable_alias_range(dst, bytes.len()).is_none()
+        {
+            return Err(PatchError::ProtectionFlip(site));
+        }
+        let start = dst as usize;
+        if !self.quiescent && start / 8 != (start + bytes.len() - 1) / 8 {
+            return Err(PatchError::Misaligned(site));
+        }
+        unsafe { try_write_code(dst, bytes, self.protection)? };
+        flush_code();
+        Ok(())
+    }
+
+    fn write_before(&self, end: AssemblerLabel, bytes: &[u8]) -> Result<(), PatchError> {
+        self.write(end, end.offset as usize - bytes.len(), bytes)
+    }
+
+    fn rel32(&self, from: AssemblerLabel, to: *const u8) -> Result<i32, PatchError> {
+        let disp = (to as isize).wrapping_sub(self.address(from) as isize);
+        if disp != disp as i32 as isize {
+            return Err(PatchError::OutOfRange);
+        }
+        Ok(disp as i32)
+    }
+
+    fn is_jump(&self, from: AssemblerLabel) -> Result<bool, PatchError> {
+        let op = self.bytes_before(from, 5)?[0];
+        if op == OP_JMP_rel32 {
+            return Ok(true);
+        }
+        match self.bytes_before(from, 6) {
+            Ok(bytes) => Ok(bytes[0] == OP_2BYTE_ESCAPE && (op & 0xF0) == OP2_JCC_rel32),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Points the `jmp` or `jcc` whose rel32 ends at `from` to `to`.
+    pub fn relink_jump(&self, from: AssemblerLabel, to: *const u8) -> Result<(), PatchError> {
+        if !self.is_jump(from)? {
+            return Err(PatchError::UnexpectedInstruction {
+                at: from,
+                expected: "jmp/jcc rel32",
+            });
+        }
+        let disp = self.rel32(from, to)?;
+        self.write_before(from, &disp.to_ne_bytes())
+    }
+
+    /// Points the `call` whose rel32 ends at `from` to `to`.
+    pub fn relink_call(&self, from: AssemblerLabel, to: *const u8) -> Result<(), PatchError> {
+        if self.bytes_before(from, 5)?[0] != OP_CALL_rel32 {
+            return Err(PatchError::UnexpectedInstruction {
+                at: from,
+                expected: "call rel32",
+            });
+        }
+        let disp = self.rel32(from, to)?;
+        self.write_before(from, &disp.to_ne_bytes())
+    }
+
+    /// Absolute target of the jump, jcc or call whose rel32 ends at `from`.
+    pub fn branch_target(&self, from: AssemblerLabel) -> Result<*const u8, PatchError> {
+        let disp = self.read_i32(from)?;
+        Ok((self.address(from) as isize).wrapping_add(disp as isize) as *const u8)
+    }
+
+    pub fn read_i32(&self, at: AssemblerLabel) -> Result<i32, PatchError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes_before(at, 4)?);
+        Ok(i32::from_ne_bytes(bytes))
+    }
+
+    /// Rewrites the imm32 or disp32 ending at `at`. Only bounds are checked,
+    /// since such fields appear in too many instructions to recognise.
+    pub fn repatch_i32(&self, at: AssemblerLabel, value: i32) -> Result<(), PatchError> {
+        self.bytes_before(at, 4)?;
+        self.write_before(at, &value.to_ne_bytes())
+    }
+
+    pub fn read_ptr(&self, at: AssemblerLabel) -> Result<*mut u8, PatchError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes_before(at, 8)?);
+        Ok(u64::from_ne_bytes(bytes) as usize as *mut u8)
+    }
+
+    /// Rewrites the imm64 of the `movabs` ending at `at`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn repatch_ptr(&self, at: AssemblerLabel, value: *const u8) -> Result<(), PatchError> {
+        let bytes = self.bytes_before(at, 10)?;
+        if (bytes[0] & !1) != (PRE_REX | (1 << 3)) || (bytes[1] & !7) != OP_MOV_EAXIv {
+            return Err(PatchError::UnexpectedInstruction {
+                at,
+                expected: "movabs imm64",
+            });
+        }
+        self.write_before(at, &(value as u64).to_ne_bytes())
+    }
+
+    /// Invalidates the watchpoint at `at` by overwriting its first five bytes
+    /// with `jmp to`, typically an OSR exit. The site returned by
+    /// `X86Assembler::label_for_watchpoit` lies within one 8-byte word, so
+    /// the jump is written with a single store and threads running the code
+    /// execute either the old bytes or the jump, never a mix.
+    ///
+    /// That only holds when the store does not flip page protection: W^X code
+    /// must be dual-mapped, e.g. placed in `ExecutableAllocator::dual_mapped`,
+    /// or the patcher must be `new_quiescent`. Otherwise this fails with
+    /// `PatchError::ProtectionFlip`.
+    pub fn replace_with_jump(&self, at: AssemblerLabel, to: *const u8) -> Result<(), PatchError> {
+        self.bytes_at(at, 5)?;
+        let start = self.address(at) as usize;
+        if start / 8 != (start + 4) / 8 {
+            return Err(PatchError::Misaligned(at));
+        }
+        let end = at.label_at_offset(5);
+        let mut bytes = [0; 5];
+        bytes[0] = OP_JMP_rel32;
+        bytes[1..].copy_from_slice(&self.rel32(end, to)?.to_ne_bytes());
+        self.write(at, at.offset as usize, &bytes)
+    }
+
+    /// Offset of the opcode of the instruction starting at `at`, skipping a
+    /// REX prefix.
+    fn opcode_offset(&self, at: AssemblerLabel) -> Result<usize, PatchError> {
+        let mut offset = at.offset as usize;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if (self.bytes_at(at, 1)?[0] & !15) == PRE_REX {
+                offset += 1;
+            }
+        }
+        self.bytes_at(AssemblerLabel::new(offset as u32), 1)?;
+        Ok(offset)
+    }
+
+    /// Turns the `lea` starting at `at` into a `mov` load from the same
+    /// address. A `mov` is left alone.
+    pub fn replace_with_load(&self, at: AssemblerLabel) -> Result<(), PatchError> {
+        let offset = self.opcode_offset(at)?;
+        match unsafe { *self.base.add(offset) } {
+            OP_MOV_GvEv => Ok(()),
+            OP_LEA => self.write(at, offset, &[OP_MOV_GvEv]),
+            _ => Err(PatchError::UnexpectedInstruction {
+                at,
+                expected: "mov or lea",
+            }),
+        }
+    }
+
+    /// Turns the `mov` load starting at `at` into a `lea` of its address. A
+    /// `lea` is left alone.
+    pub fn replace_with_address_computation(&self, at: AssemblerLabel) -> Result<(), PatchError> {
+        let offset = self.opcode_offset(at)?;
+        match unsafe { *self.base.add(offset) } {
+            OP_MOV_GvEv => self.write(at, offset, &[OP_LEA]),
+            OP_LEA => Ok(()),
+            _ => Err(PatchError::UnexpectedInstruction {
+                at,
+                expected: "mov or lea",
+            }),
+        }
+    }
+
+    /// Restores the first five bytes of `original`, an instruction that was
+    /// overwritten with a `jmp rel32` starting at `at`. The rest of it must
+    /// be intact.
+    fn revert_jump(&self, at: AssemblerLabel, original: &[u8]) -> Result<(), PatchError> {
+        assert!(original.len() >= 5);
+        let current = self.bytes_at(at, original.len())?;
+        if current[0] != OP_JMP_rel32 || current[5..] != original[5..] {
+            return Err(PatchError::UnexpectedInstruction {
+                at,
+                expected: "jmp rel32 over the instruction",
+            });
+        }
+        self.write(at, at.offset as usize, &original[..5])
+    }
+
+    /// Reverts a jump over `cmpl $imm, (dst)` as `cmpl_im_with_patch` emits it.
+    pub fn revert_jump_to_cmpl_im_force32(
+        &self,
+        at: AssemblerLabel,
+        imm: i32,
+        dst: X86Gpr,
+    ) -> Result<(), PatchError> {
+        let mut asm = X86Assembler::new();
+        asm.cmpl_im_with_patch(imm, 0, dst as u8);
+        self.revert_jump(at, asm.code())
+    }
+
+    /// Reverts a jump over `cmpl $imm, dst` as `cmpl_ir_with_patch` emits it.
+    pub fn revert_jump_to_cmpl_ir_force32(
+        &self,
+        at: AssemblerLabel,
+        imm: i32,
+        dst: X86Gpr,
+    ) -> Result<(), PatchError> {
+        let mut asm = X86Assembler::new();
+        asm.cmpl_ir_with_patch(imm, dst as u8);
+        self.revert_jump(at, asm.code())
+    }
+
+    /// Reverts a jump over `movabs $imm, dst` as `movq_i64r_with_patch`
+    /// emits it.
+    #[cfg(target_arch = "x86_64")]
+    pub fn revert_jump_to_movq_i64r(
+        &self,
+        at: AssemblerLabel,
+        imm: i64,
+        dst: X86Gpr,
+    ) -> Result<(), PatchError> {
+        let mut asm = X86Assembler::new();
+        asm.movq_i64r_with_patch(imm, dst as u8);
+        self.revert_jump(at, asm.code())
+    }
+}
+
+#[test]
+fn test_relink_and_repatch() {
+    let mut asm = X86Assembler::new();
+    let jump = asm.jmp();
+    let first = asm.label();
+    asm.movl_i32r(1, X86Gpr::Eax as _);
+    let imm = asm.label();
+    asm.ret();
+    let second = asm.label();
+    asm.movq_i64r(0x1234_5678_9abc, X86Gpr::Eax as _);
+    let ptr = asm.label();
+    asm.ret();
+    asm.link_jump(jump, first);
+
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn() -> i64 = unsafe { code.as_fn() };
+    // Standalone W^X code could only be patched by flipping its pages.
+    if code.protection() == JitProtection::WriteXorExecute {
+        assert_eq!(
+            CodePatcher::new(&code).repatch_i32(imm, 5),
+            Err(PatchError::ProtectionFlip(imm))
+        );
+    }
+    let patcher = unsafe { CodePatcher::new_quiescent(&code) };
+    assert_eq!(f(), 1);
+    patcher.repatch_i32(imm, 5).unwrap();
+    assert_eq!(f(), 5);
+    patcher.relink_jump(jump, patcher.address(second)).unwrap();
+    assert_eq!(
+        patcher.branch_target(jump).unwrap(),
+        patcher.address(second) as *const u8
+    );
+    assert_eq!(f(), 0x1234_5678_9abc);
+    patcher.repatch_ptr(ptr, 42 as *const u8).unwrap();
+    assert_eq!(patcher.read_ptr(ptr).unwrap(), 42 as *mut u8);
+    assert_eq!(f(), 42);
+
+    // Sites that do not hold the expected instruction are rejected.
+    assert_eq!(
+        patcher.relink_call(jump, patcher.address(first)),
+        Err(PatchError::UnexpectedInstruction {
+            at: jump,
+            expected: "call rel32"
+        })
+    );
+    assert!(patcher.repatch_ptr(imm, std::ptr::null()).is_err());
+    assert!(patcher.replace_with_load(first).is_err());
+    assert_eq!(
+        patcher.repatch_i32(AssemblerLabel::new(code.len() as u32 + 1), 0),
+        Err(PatchError::OutOfBounds(AssemblerLabel::new(
+            code.len() as u32 + 1
+        )))
+    );
+}
+
+#[test]
+fn test_replace_with_load_and_revert_jump() {
+    let mut asm = X86Assembler::new();
+    let load = asm.label();
+    asm.movq_mr(0, X86Gpr::Edi as _, X86Gpr::Eax as _);
+    asm.ret();
+    let compare = asm.label();
+    asm.cmpl_ir(7, X86Gpr::Edi as _, true);
+    asm.ret();
+
+    let code = asm.formatter.executable_readable();
+    let f: extern "C" fn(*const i64) -> i64 = unsafe { code.as_fn() };
+    let patcher = unsafe { CodePatcher::new_quiescent(&code) };
+    let value = 9i64;
+    assert_eq!(f(&value), 9);
+    patcher.replace_with_address_computation(load).unwrap();
+    assert_eq!(f(&value), &value as *const i64 as i64);
+    patcher.replace_with_load(load).unwrap();
+    assert_eq!(f(&value), 9);
+
+    let site = compare.offset as usize;
+    let original = code.as_slice()[site..site + 6].to_vec();
+    code.write(site, &[OP_JMP_rel32, 0, 0, 0, 0]).unwrap();
+    patcher
+        .revert_jump_to_cmpl_ir_force32(compare, 7, X86Gpr::Edi)
+        .unwrap();
+    assert_eq!(&code.as_slice()[site..site + 6], &original[..]);
+    assert!(patcher
+        .revert_jump_to_cmpl_ir_force32(compare, 7, X86Gpr::Edi)
+        .is_err());
+}
+
+#[test]
+fn test_revert_jump_encodings() {
+    use X86Gpr::*;
+    let mut asm = X86Assembler::new();
+    let mut sites = vec![];
+    for &dst in [Edi, R9, R15].iter() {
+        sites.push((asm.label(), dst, false));
+        asm.cmpl_ir_with_patch(0x1234, dst as _);
+    }
+    for &base in [Esi, Esp, Ebp, R10, R12, R13].iter() {
+        sites.push((asm.label(), base, true));
+        asm.cmpl_im_with_patch(0x1234, 0, base as _);
+    }
+    let movabs = asm.label();
+    asm.movq_i64r_with_patch(0x1122_3344_5566_7788, R11 as _);
+    asm.ret();
+
+    let code = asm.formatter.executable_readable();
+    let patcher = unsafe { CodePatcher::new_quiescent(&code) };
+    let original = code.as_slice().to_vec();
+    for &(at, reg, memory) in sites.iter() {
+        let site = at.offset as usize;
+        code.write(site, &[OP_JMP_rel32, 0, 0, 0, 0]).unwrap();
+        if memory {
+            patcher.revert_jump_to_cmpl_im_force32(at, 0x1234, reg)
+        } else {
+            patcher.revert_jump_to_cmpl_ir_force32(at, 0x1234, reg)
+        }
+        .unwrap();
+        assert_eq!(code.as_slice(), &original[..], "{:?}", reg);
+    }
+    code.write(movabs.offset as usize, &[OP_JMP_rel32, 0, 0, 0, 0])
+        .unwrap();
+    patcher
+        .revert_jump_to_movq_i64r(movabs, 0x1122_3344_5566_7788, R11)
+        .unwrap();
+    assert_eq!(code.as_slice(), &original[..]);
+
+    // The bytes after the jump must still be those of the instruction.
+    let (at, reg, _) = sites[1];
+    code.write(at.offset as usize, &[OP_JMP_rel32, 0, 0, 0, 0])
+        .unwrap();
+    assert!(patcher
+        .revert_jump_to_cmpl_ir_force32(at, 0x0055_1234, reg)
+        .is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_patch_through_dual_mapping() {
+    use crate::assembler::mapping_permissions;
+    use crate::mem::ExecutableAllocator;
+    use std::sync::Arc;
+
+    let pool = Arc::new(ExecutableAllocator::dual_mapped(1).unwrap());
+    let mut asm = X86Assembler::new();
+    asm.movl_i32r(1, X86Gpr::Eax as _);
+    let imm = asm.label();
+    asm.ret();
+    let code = asm.formatter.executable_in(&pool);
+    let f: extern "C" fn() -> i32 = unsafe { code.as_fn() };
+    assert_eq!(f(), 1);
+
+    let writable = pool.dual_mapping().unwrap().writable_base();
+    assert_eq!(mapping_permissions(code.as_ptr()), "r-x");
+    assert_eq!(mapping_permissions(writable), "rw-");
+    CodePatcher::new(&code).repatch_i32(imm, 3).unwrap();
+    assert_eq!(f(), 3);
+    assert_eq!(mapping_permissions(code.as_ptr()), "r-x");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_patch_fields_spanning_words() {
+    use crate::mem::ExecutableAllocator;
+    use std::sync::Arc;
+
+    let pool = Arc::new(ExecutableAllocator::dual_mapped(1).unwrap());
+    let mut asm = X86Assembler::new();
+    let nops = |asm: &mut X86Assembler, n| {
+        for _ in 0..n {
+            asm.formatter.one_byte_op_1(OP_NOP);
+        }
+    };
+    nops(&mut asm, 4);
+    let split_jump = asm.jmp(); // rel32 at 5..9
+    nops(&mut asm, 2);
+    let jump = asm.jmp(); // rel32 at 12..16
+    nops(&mut asm, 6);
+    let ptr = asm.movq_i64r_with_patch(0, X86Gpr::Eax as _).label; // imm64 at 24..32
+    let split_ptr = asm.movq_i64r_with_patch(0, X86Gpr::Eax as _).label; // imm64 at 34..42
+    asm.ret();
+    let code = asm.formatter.executable_in(&pool);
+    assert_eq!(code.as_ptr() as usize % 8, 0);
+
+    let patcher = CodePatcher::new(&code);
+    let target = patcher.address(jump);
+    assert_eq!(
+        patcher.relink_jump(split_jump, target),
+        Err(PatchError::Misaligned(split_jump))
+    );
+    assert_eq!(
+        patcher.repatch_i32(split_jump, 0),
+        Err(PatchError::Misaligned(split_jump))
+    );
+    assert_eq!(
+        patcher.repatch_ptr(split_ptr, std::ptr::null()),
+        Err(PatchError::Misaligned(split_ptr))
+    );
+    patcher.relink_jump(jump, target).unwrap();
+    patcher.repatch_ptr(ptr, 42 as *const u8).unwrap();
+    assert_eq!(patcher.read_ptr(ptr).unwrap(), 42 as *mut u8);
+
+    // Nothing runs code that is not published yet.
+    let patcher = unsafe { CodePatcher::new_quiescent(&code) };
+    patcher.relink_jump(split_jump, target).unwrap();
+    assert_eq!(patcher.branch_target(split_jump).unwrap(), target);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_watchpoint_redirects_running_code() {
+    use crate::mem::ExecutableAllocator;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let mut asm = X86Assembler::new();
+    asm.movl_i32r(0, X86Gpr::Eax as _);
+    let site = asm.label_for_watchpoit();
+    asm.addl_ir(1, X86Gpr::Eax as _);
+    asm.ret();
+    let exit = asm.label();
+    asm.movl_i32r(-1, X86Gpr::Eax as _);
+    asm.ret();
+    assert_eq!(site.offset % WATCHPOINT_ALIGNMENT as u32, 0);
+    assert!(exit.offset >= site.offset + 5);
+    assert_eq!(asm.watchpoints(), &[site][..]);
+
+    let pool = Arc::new(ExecutableAllocator::dual_mapped(1).unwrap());
+    let code = Arc::new(asm.formatter.executable_in(&pool));
+    let f: extern "C" fn() -> i32 = unsafe { code.as_fn() };
+    assert_eq!(f(), 1);
+
+    let invalidated = Arc::new(AtomicBool::new(false));
+    let runner = {
+        let invalidated = invalidated.clone();
+        let code = code.clone();
+        std::thread::spawn(move || {
+            let f: extern "C" fn() -> i32 = unsafe { code.as_fn() };
+            loop {
+                let done = invalidated.load(Ordering::Acquire);
+                match f() {
+                    1 => assert!(!done),
+                    -1 => return,
+                    r => panic!("torn watchpoint: {}", r),
+                }
+            }
+        })
+    };
+    let patcher = CodePatcher::new(&code);
+    patcher
+        .replace_with_jump(site, patcher.address(exit))
+        .unwrap();
+    invalidated.store(true, Ordering::Release);
+    runner.join().unwrap();
+    assert_eq!(f(), -1);
+}
+
+#[test]
+fn test_watchpoint_at_end_is_padded() {
+    let mut asm = X86Assembler::new();
+    asm.ret();
+    let site = asm.label_for_watchpoit();
+    assert_eq!(asm.label_for_watchpoit(), site);
+    let code = asm.formatter.executable_readable();
+    assert_eq!(code.len(), site.offset as usize + 5);
+
+    // Standalone W^X code has no writable alias to store the jump through.
+    if code.protection() == JitProtection::WriteXorExecute {
+        assert_eq!(
+            CodePatcher::new(&code).replace_with_jump(site, code.as_ptr()),
+            Err(PatchError::ProtectionFlip(site))
+        );
+    }
+    let patcher = unsafe { CodePatcher::new_quiescent(&code) };
+    patcher.replace_with_jump(site, code.as_ptr()).unwrap();
+    assert_eq!(
+        patcher.branch_target(site.label_at_offset(5)),
+        Ok(code.as_ptr())
+    );
+    assert_eq!(
+        patcher.replace_with_jump(AssemblerLabel::new(site.offset - 4), code.as_ptr()),
+        Err(PatchError::Misaligned(AssemblerLabel::new(site.offset - 4)))
+    );
+}